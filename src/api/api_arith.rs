@@ -1,5 +1,9 @@
 use crate::state::lua_value::LuaValue;
-pub const OPS: &'static [(fn(i64, i64) -> i64, fn(f64, f64) -> f64)] = &[
+use crate::api::consts::*;
+
+type ArithOp = (fn(i64, i64) -> i64, fn(f64, f64) -> f64);
+
+pub const OPS: &[ArithOp] = &[
     (iadd, fadd),
     (isub, fsub),
     (imul, fmul),
@@ -16,38 +20,62 @@ pub const OPS: &'static [(fn(i64, i64) -> i64, fn(f64, f64) -> f64)] = &[
     (bnot, fnone),
 ];
 
-pub fn _arith(a: &LuaValue, b: &LuaValue, op: u8) -> LuaValue {
+pub const METAMETHODS: &[&str] = &[
+    "__add",
+    "__sub",
+    "__mul",
+    "__mod",
+    "__pow",
+    "__div",
+    "__idiv",
+    "__band",
+    "__bor",
+    "__bxor",
+    "__shl",
+    "__shr",
+    "__unm",
+    "__bnot",
+];
+
+// None when the operands do not convert, for the metamethods to take over;
+// Err for an integer division by zero
+pub fn _arith(a: &LuaValue, b: &LuaValue, op: u8) -> Result<Option<LuaValue>, &'static str> {
     let (iop, fop) = OPS[op as usize];
-    if fop == fnone {
+    if (LUA_OPBAND..=LUA_OPSHR).contains(&op) || op == LUA_OPBNOT {
         // bit wise
         let (a_res, a_ok) = a.to_integerx();
         let (b_res, b_ok) = b.to_integerx();
         if a_ok && b_ok {
-            return LuaValue::Int64(iop(a_res, b_res));
+            return Ok(Some(LuaValue::Int64(iop(a_res, b_res))));
         }
     } else {
         // arith
-        if iop != inone {
+        if op != LUA_OPPOW && op != LUA_OPDIV {
             // add,sub,mul,mod,idiv,unm
-            if let LuaValue::Int64(x) = a {
-                if let LuaValue::Int64(y) = b {
-                    return LuaValue::Int64(iop(*x, *y));
+            if let (LuaValue::Int64(x), LuaValue::Int64(y)) = (a, b) {
+                if *y == 0 && op == LUA_OPMOD {
+                    return Err("attempt to perform 'n%0'");
+                }
+                if *y == 0 && op == LUA_OPIDIV {
+                    return Err("attempt to perform 'n//0'");
                 }
+                return Ok(Some(LuaValue::Int64(iop(*x, *y))));
             }
         }
 
         let (a_res, a_ok) = a.to_numberx();
         let (b_res, b_ok) = b.to_numberx();
         if a_ok && b_ok {
-            return LuaValue::Float64(fop(a_res, b_res));
+            return Ok(Some(LuaValue::Float64(fop(a_res, b_res))));
         }
     }
-    panic!("arithmetic error!");
+    Ok(None)
 }
 
 
+// integer operations wrap around, as in C with unsigned arithmetic
 pub fn iadd(a: i64, b: i64) -> i64{
-    a + b
+    a.wrapping_add(b)
 }
 
 pub fn fadd(a: f64, b: f64) -> f64{
@@ -55,7 +83,7 @@ pub fn fadd(a: f64, b: f64) -> f64{
 }
 
 pub fn isub(a: i64, b: i64) -> i64 {
-    a - b
+    a.wrapping_sub(b)
 }
 
 pub fn fsub(a: f64, b: f64) -> f64 {
@@ -64,19 +92,35 @@ pub fn fsub(a: f64, b: f64) -> f64 {
 
 
 pub fn imul(a: i64, b: i64) -> i64 {
-    a * b
+    a.wrapping_mul(b)
 }
 
 pub fn fmul(a: f64, b: f64) -> f64 {
     a * b
 }
 
+// b != 0; the result has the sign of b (see luaV_mod)
 pub fn imod(a: i64, b: i64) -> i64 {
-    a - ii_div(a, b) * b
+    if b == -1 {
+        // avoid the overflow of i64::MIN % -1
+        return 0;
+    }
+    let m = a % b;
+    if m != 0 && (m ^ b) < 0 {
+        m + b
+    } else {
+        m
+    }
 }
 
+// see luai_nummod
 pub fn fmod(a: f64, b: f64) -> f64 {
-    a - fi_div(a, b) * b
+    let m = a % b;
+    if m * b < 0.0 {
+        m + b
+    } else {
+        m
+    }
 }
 
 fn pow(a: f64, b: f64) -> f64 {
@@ -87,11 +131,18 @@ pub fn div(a: f64, b: f64) -> f64 {
     a / b
 }
 
+// b != 0; rounds towards minus infinity (see luaV_div)
 pub fn ii_div(a: i64, b: i64) -> i64 {
-    if a > 0 && b > 0 || a < 0 && b < 0 || a % b == 0 {
-        return a / b;
+    if b == -1 {
+        // i64::MIN // -1 wraps to i64::MIN
+        return a.wrapping_neg();
+    }
+    let q = a / b;
+    if a % b != 0 && (a ^ b) < 0 {
+        q - 1
+    } else {
+        q
     }
-    a / b - 1
 }
 
 pub fn fi_div(a: f64, b: f64) -> f64 {
@@ -110,22 +161,26 @@ pub fn bxor(a: i64, b: i64) -> i64 {
     a ^ b
 }
 
+// shifts of 64 bits or more in either direction give 0
 pub fn shl(a: i64, n: i64) -> i64 {
-    if n >= 0 {
-        return a << n;
+    if n <= -64 || n >= 64 {
+        0
+    } else if n >= 0 {
+        ((a as u64) << n) as i64
+    } else {
+        ((a as u64) >> -n) as i64
     }
-    shr(a, -n)
 }
 
 pub fn shr(a: i64, n: i64) -> i64 {
-    if n >= 0 {
-        return ((a as u64) >> n) as i64;
+    if n <= -64 {
+        return 0;
     }
     shl(a, -n)
 }
 
 pub fn iunm(a: i64, _: i64) -> i64 {
-    -a
+    a.wrapping_neg()
 }
 
 pub fn funm(a: f64, _: f64) -> f64 {
@@ -146,3 +201,109 @@ fn fnone(_: f64, _: f64) -> f64 {
 
 
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::lua_value::LuaValue::{Float64, Int64};
+
+    fn arith(a: LuaValue, op: u8, b: LuaValue) -> LuaValue {
+        _arith(&a, &b, op).unwrap().unwrap()
+    }
+
+    // same value and same subtype, so 3 and 3.0 differ
+    fn assert_same(got: LuaValue, expected: LuaValue) {
+        match (got, expected) {
+            (Int64(x), Int64(y)) => assert_eq!(x, y),
+            (Float64(x), Float64(y)) => {
+                let same = x == y && x.is_sign_negative() == y.is_sign_negative();
+                assert!(same || x.is_nan() && y.is_nan(), "{} != {}", x, y);
+            },
+            _ => panic!("subtype differs"),
+        }
+    }
+
+    #[test]
+    fn integers_wrap_around() {
+        assert_same(arith(Int64(i64::MAX), LUA_OPADD, Int64(1)), Int64(i64::MIN));
+        assert_same(arith(Int64(i64::MIN), LUA_OPSUB, Int64(1)), Int64(i64::MAX));
+        assert_same(arith(Int64(i64::MAX), LUA_OPMUL, Int64(2)), Int64(-2));
+        assert_same(arith(Int64(i64::MIN), LUA_OPMUL, Int64(-1)), Int64(i64::MIN));
+        assert_same(arith(Int64(i64::MIN), LUA_OPUNM, Int64(i64::MIN)), Int64(i64::MIN));
+    }
+
+    #[test]
+    fn integer_division_by_zero() {
+        assert!(matches!(_arith(&Int64(1), &Int64(0), LUA_OPIDIV), Err("attempt to perform 'n//0'")));
+        assert!(matches!(_arith(&Int64(1), &Int64(0), LUA_OPMOD), Err("attempt to perform 'n%0'")));
+        // floats divide by zero
+        assert_same(arith(Int64(1), LUA_OPIDIV, Float64(0.0)), Float64(f64::INFINITY));
+        assert_same(arith(Int64(-1), LUA_OPIDIV, Float64(0.0)), Float64(f64::NEG_INFINITY));
+        assert_same(arith(Float64(1.0), LUA_OPMOD, Int64(0)), Float64(f64::NAN));
+        assert_same(arith(Int64(1), LUA_OPDIV, Int64(0)), Float64(f64::INFINITY));
+    }
+
+    #[test]
+    fn min_integer_by_minus_one() {
+        assert_same(arith(Int64(i64::MIN), LUA_OPIDIV, Int64(-1)), Int64(i64::MIN));
+        assert_same(arith(Int64(i64::MIN), LUA_OPMOD, Int64(-1)), Int64(0));
+        assert_same(arith(Int64(i64::MIN), LUA_OPMOD, Int64(i64::MAX)), Int64(i64::MAX - 1));
+    }
+
+    #[test]
+    fn floor_division_and_modulo_signs() {
+        let cases = [(7, 2, 3, 1), (-7, 2, -4, 1), (7, -2, -4, -1), (-7, -2, 3, -1), (-7, 3, -3, 2), (7, -3, -3, -2)];
+        for (a, b, q, r) in cases {
+            assert_same(arith(Int64(a), LUA_OPIDIV, Int64(b)), Int64(q));
+            assert_same(arith(Int64(a), LUA_OPMOD, Int64(b)), Int64(r));
+        }
+    }
+
+    #[test]
+    fn float_modulo_takes_the_sign_of_the_divisor() {
+        assert_same(arith(Float64(7.5), LUA_OPMOD, Int64(2)), Float64(1.5));
+        assert_same(arith(Float64(-7.5), LUA_OPMOD, Int64(2)), Float64(0.5));
+        assert_same(arith(Float64(7.5), LUA_OPMOD, Int64(-2)), Float64(-0.5));
+        assert_same(arith(Float64(-7.5), LUA_OPMOD, Int64(-2)), Float64(-1.5));
+        assert_same(arith(Float64(-6.0), LUA_OPMOD, Int64(2)), Float64(-0.0));
+        assert_same(arith(Int64(5), LUA_OPMOD, Float64(f64::INFINITY)), Float64(5.0));
+        assert_same(arith(Int64(-5), LUA_OPMOD, Float64(f64::INFINITY)), Float64(f64::INFINITY));
+        assert_same(arith(Int64(5), LUA_OPMOD, Float64(f64::NEG_INFINITY)), Float64(f64::NEG_INFINITY));
+        assert_same(arith(Float64(f64::INFINITY), LUA_OPMOD, Int64(2)), Float64(f64::NAN));
+    }
+
+    #[test]
+    fn shifts() {
+        assert_same(arith(Int64(1), LUA_OPSHL, Int64(63)), Int64(i64::MIN));
+        assert_same(arith(Int64(1), LUA_OPSHL, Int64(64)), Int64(0));
+        assert_same(arith(Int64(-1), LUA_OPSHR, Int64(1)), Int64(i64::MAX));
+        assert_same(arith(Int64(-1), LUA_OPSHR, Int64(63)), Int64(1));
+        assert_same(arith(Int64(-1), LUA_OPSHR, Int64(64)), Int64(0));
+        // negative amounts shift the other way
+        assert_same(arith(Int64(1), LUA_OPSHL, Int64(-1)), Int64(0));
+        assert_same(arith(Int64(2), LUA_OPSHR, Int64(-1)), Int64(4));
+        assert_same(arith(Int64(1), LUA_OPSHR, Int64(-64)), Int64(0));
+        assert_same(arith(Int64(3), LUA_OPSHL, Int64(i64::MIN)), Int64(0));
+        assert_same(arith(Int64(3), LUA_OPSHR, Int64(i64::MIN)), Int64(0));
+    }
+
+    #[test]
+    fn bitwise_operands_must_be_integral() {
+        assert_same(arith(Float64(3.0), LUA_OPBOR, Int64(0)), Int64(3));
+        assert_same(arith(Int64(5), LUA_OPBAND, Int64(3)), Int64(1));
+        assert_same(arith(Int64(5), LUA_OPBXOR, Int64(3)), Int64(6));
+        assert_same(arith(Int64(0), LUA_OPBNOT, Int64(0)), Int64(-1));
+        assert!(matches!(_arith(&Float64(1.5), &Int64(0), LUA_OPBOR), Ok(None)));
+        assert!(matches!(_arith(&Float64(2f64.powi(63)), &Int64(0), LUA_OPBAND), Ok(None)));
+        assert!(matches!(_arith(&Int64(0), &Float64(f64::NAN), LUA_OPSHL), Ok(None)));
+        assert!(matches!(_arith(&LuaValue::Nil, &Int64(0), LUA_OPADD), Ok(None)));
+    }
+
+    #[test]
+    fn float_operators_always_give_floats() {
+        assert_same(arith(Int64(7), LUA_OPDIV, Int64(2)), Float64(3.5));
+        assert_same(arith(Int64(2), LUA_OPPOW, Int64(10)), Float64(1024.0));
+        assert_same(arith(Float64(7.5), LUA_OPIDIV, Int64(2)), Float64(3.0));
+        assert_same(arith(Float64(-7.5), LUA_OPIDIV, Int64(2)), Float64(-4.0));
+    }
+}
@@ -0,0 +1,518 @@
+use crate::state::lua_state::LuaState;
+use crate::state::lua_value::LuaValue;
+use crate::state::closure::RustFn;
+use crate::state::lua_error::{LuaError, LuaResult};
+use crate::api::api_stack::LuaAPI;
+use crate::api::consts::*;
+use crate::state::gc::Gc;
+use std::cell::{Ref, RefMut};
+
+// t[FREELIST] heads the chain of released references in a table used
+// by r#ref, each free slot holding the next one
+const FREELIST: i64 = 0;
+
+// the auxiliary library (lauxlib) for writing Rust functions; names that
+// clash with LuaAPI get a "2", errors go through arg_error
+pub trait AuxAPI {
+    // error report
+    fn error2(&self, msg: &str) -> LuaError;
+    fn arg_error(&self, arg: isize, extra_msg: &str) -> LuaError;
+    fn type_error2(&self, arg: isize, tname: &str) -> LuaError;
+    fn _tag_error(&self, arg: isize, tag: i8) -> LuaError;
+    // arg check
+    fn check_stack2(&mut self, size: usize, msg: &str) -> LuaResult<()>;
+    fn arg_check(&self, cond: bool, arg: isize, extra_msg: &str) -> LuaResult<()>;
+    fn check_any(&self, arg: isize) -> LuaResult<()>;
+    fn check_type(&self, arg: isize, t: i8) -> LuaResult<()>;
+    fn check_integer(&self, arg: isize) -> LuaResult<i64>;
+    fn check_number(&self, arg: isize) -> LuaResult<f64>;
+    fn check_string(&self, arg: isize) -> LuaResult<String>;
+    fn opt_integer(&self, arg: isize, d: i64) -> LuaResult<i64>;
+    fn opt_number(&self, arg: isize, d: f64) -> LuaResult<f64>;
+    fn opt_string(&self, arg: isize, d: &str) -> LuaResult<String>;
+    fn check_option(&self, arg: isize, def: Option<&str>, lst: &[&str]) -> LuaResult<usize>;
+    // misc
+    fn type_name2(&self, idx: isize) -> &str;
+    fn tolstring(&mut self, idx: isize) -> LuaResult<String>;
+    fn len2(&mut self, idx: isize) -> LuaResult<i64>;
+    // references
+    fn r#ref(&mut self, t: isize) -> LuaResult<i64>;
+    fn unref(&mut self, t: isize, r: i64) -> LuaResult<()>;
+    // library
+    fn new_lib(&mut self, funcs: &[(&str, RustFn)]);
+    fn set_funcs(&mut self, funcs: &[(&str, RustFn)], n_up: usize) -> LuaResult<()>;
+    // metatables of userdata types, kept in the registry by name
+    fn new_metatable(&mut self, tname: &str) -> LuaResult<bool>;
+    fn get_metatable2(&mut self, tname: &str) -> LuaResult<i8>;
+    fn set_metatable2(&mut self, tname: &str) -> LuaResult<()>;
+    fn test_udata(&self, arg: isize, tname: &str) -> bool;
+    fn check_udata<T: 'static>(&self, arg: isize, tname: &str) -> LuaResult<Ref<'_, T>>;
+    fn check_udata_mut<T: 'static>(&self, arg: isize, tname: &str) -> LuaResult<RefMut<'_, T>>;
+}
+
+impl AuxAPI for LuaState {
+    // prefixed with the position of the caller, see luaL_error
+    fn error2(&self, msg: &str) -> LuaError {
+        LuaError::runtime(format!("{}{}", self.location(1), msg))
+    }
+
+    // bad argument #<arg> to '<name>' (<extra_msg>), see luaL_argerror
+    fn arg_error(&self, mut arg: isize, extra_msg: &str) -> LuaError {
+        let f = match self.frame_at(0) {
+            Some(frame) => LuaValue::Function(frame.closure.unwrap()),
+            None => return self.error2(&format!("bad argument #{} ({})", arg, extra_msg)),
+        };
+        let name = match self.func_name_at(0) {
+            Some(("method", name)) => {
+                arg -= 1; // do not count 'self'
+                if arg == 0 {
+                    return self.error2(&format!("calling '{}' on bad self ({})", name, extra_msg));
+                }
+                name
+            },
+            Some((_, name)) => name,
+            None => self.global_func_name(&f).unwrap_or_else(|| String::from("?")),
+        };
+        self.error2(&format!("bad argument #{} to '{}' ({})", arg, name, extra_msg))
+    }
+
+    // <tname> expected, got <type>, see luaL_typeerror
+    fn type_error2(&self, arg: isize, tname: &str) -> LuaError {
+        let val = self.get_value(arg);
+        let type_arg = match self.get_metafield(&val, "__name") {
+            LuaValue::LuaString(name) => name.to_string(),
+            _ if self.type_id(arg) == LUA_TLIGHTUSERDATA => String::from("light userdata"),
+            _ => String::from(self.type_name2(arg)),
+        };
+        self.arg_error(arg, &format!("{} expected, got {}", tname, type_arg))
+    }
+
+    fn _tag_error(&self, arg: isize, tag: i8) -> LuaError {
+        self.type_error2(arg, self.type_name(tag))
+    }
+
+    fn check_stack2(&mut self, size: usize, msg: &str) -> LuaResult<()> {
+        if self.check_stack(size) {
+            return Ok(());
+        }
+        if msg.is_empty() {
+            Err(self.error2("stack overflow"))
+        } else {
+            Err(self.error2(&format!("stack overflow ({})", msg)))
+        }
+    }
+
+    fn arg_check(&self, cond: bool, arg: isize, extra_msg: &str) -> LuaResult<()> {
+        if cond {
+            Ok(())
+        } else {
+            Err(self.arg_error(arg, extra_msg))
+        }
+    }
+
+    fn check_any(&self, arg: isize) -> LuaResult<()> {
+        if self.type_id(arg) == LUA_TNONE {
+            return Err(self.arg_error(arg, "value expected"));
+        }
+        Ok(())
+    }
+
+    fn check_type(&self, arg: isize, t: i8) -> LuaResult<()> {
+        if self.type_id(arg) != t {
+            return Err(self._tag_error(arg, t));
+        }
+        Ok(())
+    }
+
+    fn check_integer(&self, arg: isize) -> LuaResult<i64> {
+        match self.to_integerx(arg) {
+            (i, true) => Ok(i),
+            _ if self.is_number(arg) => Err(self.arg_error(arg, "number has no integer representation")),
+            _ => Err(self._tag_error(arg, LUA_TNUMBER)),
+        }
+    }
+
+    fn check_number(&self, arg: isize) -> LuaResult<f64> {
+        match self.to_numberx(arg) {
+            (n, true) => Ok(n),
+            _ => Err(self._tag_error(arg, LUA_TNUMBER)),
+        }
+    }
+
+    // numbers are converted
+    fn check_string(&self, arg: isize) -> LuaResult<String> {
+        match self.to_stringx(arg) {
+            (s, true) => Ok(s),
+            _ => Err(self._tag_error(arg, LUA_TSTRING)),
+        }
+    }
+
+    fn opt_integer(&self, arg: isize, d: i64) -> LuaResult<i64> {
+        if self.is_none_or_nil(arg) {
+            return Ok(d);
+        }
+        self.check_integer(arg)
+    }
+
+    fn opt_number(&self, arg: isize, d: f64) -> LuaResult<f64> {
+        if self.is_none_or_nil(arg) {
+            return Ok(d);
+        }
+        self.check_number(arg)
+    }
+
+    fn opt_string(&self, arg: isize, d: &str) -> LuaResult<String> {
+        if self.is_none_or_nil(arg) {
+            return Ok(String::from(d));
+        }
+        self.check_string(arg)
+    }
+
+    // index in `lst` of the string argument, or of `def` if it is absent
+    fn check_option(&self, arg: isize, def: Option<&str>, lst: &[&str]) -> LuaResult<usize> {
+        let name = match def {
+            Some(def) if self.is_none_or_nil(arg) => String::from(def),
+            _ => self.check_string(arg)?,
+        };
+        match lst.iter().position(|opt| *opt == name) {
+            Some(i) => Ok(i),
+            None => Err(self.arg_error(arg, &format!("invalid option '{}'", name))),
+        }
+    }
+
+    fn type_name2(&self, idx: isize) -> &str {
+        self.type_name(self.type_id(idx))
+    }
+
+    // converts any value to a string, honouring __tostring and __name;
+    // the result is also pushed (see luaL_tolstring)
+    fn tolstring(&mut self, idx: isize) -> LuaResult<String> {
+        let val = self.get_value(idx);
+        let mm = self.get_metafield(&val, "__tostring");
+        if !mm.is_nil() {
+            self.stack.push(mm);
+            self.stack.push(val);
+            self.call(1, 1)?;
+            if !self.is_string(-1) {
+                return Err(self.error2("'__tostring' must return a string"));
+            }
+            return Ok(self.to_string(-1));
+        }
+
+        let s = match val {
+            LuaValue::Nil => String::from("nil"),
+            LuaValue::Bool(b) => b.to_string(),
+            LuaValue::Int64(_) | LuaValue::Float64(_) | LuaValue::LuaString(_) => val.to_stringx().0.to_string(),
+            _ => {
+                let name = match self.get_metafield(&val, "__name") {
+                    LuaValue::LuaString(name) => name.to_string(),
+                    _ => String::from(self.type_name(val.get_type())),
+                };
+                format!("{}: {:#x}", name, val.to_pointer())
+            }
+        };
+        self.push_string(s.clone());
+        Ok(s)
+    }
+
+    // the length of the value at `idx` as an integer, honouring __len
+    fn len2(&mut self, idx: isize) -> LuaResult<i64> {
+        self.len(idx)?;
+        let (n, is_num) = self.to_integerx(-1);
+        if !is_num {
+            return Err(self.error2("object length is not an integer"));
+        }
+        self.pop(1)?;
+        Ok(n)
+    }
+
+    // pop the value on top into the table at `t` under a fresh integer key
+    // and return it; the value stays alive until unref (see luaL_ref)
+    fn r#ref(&mut self, t: isize) -> LuaResult<i64> {
+        if self.is_nil(-1) {
+            self.pop(1)?; // remove it from stack
+            return Ok(LUA_REFNIL);
+        }
+        let t = self.abs_index(t);
+        self.raw_get_i(t, FREELIST)?; // get first free element
+        let r = self.to_integer(-1);
+        self.pop(1)?;
+        let r = if r != 0 {
+            self.raw_get_i(t, r)?; // remove it from list
+            self.raw_set_i(t, FREELIST)?; // (t[freelist] = t[ref])
+            r
+        } else {
+            self.raw_len(t) as i64 + 1 // get a new reference
+        };
+        self.raw_set_i(t, r)?;
+        Ok(r)
+    }
+
+    // release a reference, its key is reused by a later r#ref
+    fn unref(&mut self, t: isize, r: i64) -> LuaResult<()> {
+        if r >= 0 {
+            let t = self.abs_index(t);
+            self.raw_get_i(t, FREELIST)?;
+            self.raw_set_i(t, r)?; // t[ref] = t[freelist]
+            self.push_integer(r);
+            self.raw_set_i(t, FREELIST)?; // t[freelist] = ref
+        }
+        Ok(())
+    }
+
+    fn new_lib(&mut self, funcs: &[(&str, RustFn)]) {
+        self.create_table(0, funcs.len());
+        // string keys on a fresh table cannot fail
+        let _ = self.set_funcs(funcs, 0);
+    }
+
+    // register `funcs` into the table below the `n_up` values on top,
+    // which all of them share as upvalues
+    fn set_funcs(&mut self, funcs: &[(&str, RustFn)], n_up: usize) -> LuaResult<()> {
+        self.check_stack2(n_up, "too many upvalues")?;
+        for (name, f) in funcs.iter() {
+            for _ in 0..n_up {
+                self.push_value(-(n_up as isize));
+            }
+            self.push_rust_closure(*f, n_up)?;
+            self.set_field(-(n_up as isize) - 2, String::from(*name))?;
+        }
+        self.pop(n_up)
+    }
+
+    // push registry[tname]; if it is new, first set it to a table with
+    // __name = tname and return true (see luaL_newmetatable)
+    fn new_metatable(&mut self, tname: &str) -> LuaResult<bool> {
+        if self.get_metatable2(tname)? != LUA_TNIL {
+            return Ok(false); // leave previous value on top
+        }
+        self.pop(1)?;
+        self.create_table(0, 2);
+        self.push_string(String::from(tname));
+        self.set_field(-2, String::from("__name"))?;
+        self.push_value(-1);
+        self.set_field(LUA_REGISTRYINDEX, String::from(tname))?;
+        Ok(true)
+    }
+
+    fn get_metatable2(&mut self, tname: &str) -> LuaResult<i8> {
+        let k = LuaValue::new_string(&mut self.heap, tname);
+        self._raw_get(self.registry, k)
+    }
+
+    // give the value on top the metatable registered as `tname`
+    fn set_metatable2(&mut self, tname: &str) -> LuaResult<()> {
+        self.get_metatable2(tname)?;
+        self.set_metatable(-2)
+    }
+
+    // whether the value at `arg` is a userdata with the metatable `tname`;
+    // false while it is borrowed mutably, as its metatable cannot be read
+    fn test_udata(&self, arg: isize, tname: &str) -> bool {
+        let mt = match self.get_value(arg) {
+            LuaValue::UserData(u) => match u.try_borrow() {
+                Ok(u) => u.metatable,
+                Err(_) => return false,
+            },
+            _ => return false,
+        };
+        let expected = match &self.registry {
+            LuaValue::Table(registry) => registry.borrow().get_str(tname),
+            _ => LuaValue::Nil,
+        };
+        matches!((mt, expected), (Some(mt), LuaValue::Table(t)) if Gc::ptr_eq(&mt, &t))
+    }
+
+    // borrow the Rust value of a userdata with the metatable `tname`;
+    // a value borrowed elsewhere is reported apart from a wrong type
+    fn check_udata<T: 'static>(&self, arg: isize, tname: &str) -> LuaResult<Ref<'_, T>> {
+        if self._userdata_busy(arg, false) {
+            return Err(self.arg_error(arg, &format!("{} is already borrowed", tname)));
+        }
+        if self.test_udata(arg, tname) {
+            if let Some(r) = self.borrow_userdata(arg) {
+                return Ok(r);
+            }
+        }
+        Err(self.type_error2(arg, tname))
+    }
+
+    fn check_udata_mut<T: 'static>(&self, arg: isize, tname: &str) -> LuaResult<RefMut<'_, T>> {
+        if self._userdata_busy(arg, true) {
+            return Err(self.arg_error(arg, &format!("{} is already borrowed", tname)));
+        }
+        if self.test_udata(arg, tname) {
+            if let Some(r) = self.borrow_userdata_mut(arg) {
+                return Ok(r);
+            }
+        }
+        Err(self.type_error2(arg, tname))
+    }
+}
+
+// a string built piece by piece, see luaL_Buffer; the pieces are kept
+// here instead of on the stack
+pub struct LuaBuffer {
+    b: String,
+}
+
+impl Default for LuaBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LuaBuffer {
+    pub fn new() -> LuaBuffer {
+        LuaBuffer { b: String::new() }
+    }
+
+    pub fn add_char(&mut self, c: char) {
+        self.b.push(c);
+    }
+
+    pub fn add_string(&mut self, s: &str) {
+        self.b.push_str(s);
+    }
+
+    // pop the string or number on top of the stack into the buffer
+    pub fn add_value(&mut self, ls: &mut LuaState) -> LuaResult<()> {
+        let (s, is_string) = ls.to_stringx(-1);
+        if !is_string {
+            return Err(ls.error2(&format!("attempt to add a {} value to a buffer", ls.type_name2(-1))));
+        }
+        self.b.push_str(&s);
+        ls.pop(1)
+    }
+
+    // the contents so far, see luaL_buffaddr
+    pub fn as_str(&self) -> &str {
+        &self.b
+    }
+
+    // push the contents as a Lua string
+    pub fn push_result(self, ls: &mut LuaState) {
+        ls.push_string(self.b);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refs_reuse_freed_keys() {
+        let mut ls = LuaState::new();
+        ls.new_table();
+        let refs: Option<Vec<i64>> = (0..3).map(|i| {
+            ls.push_integer(i * 10);
+            ls.r#ref(1).ok()
+        }).collect();
+        assert_eq!(refs, Some(vec![1, 2, 3]));
+        assert_eq!(ls.get_top(), 1);
+
+        assert!(ls.unref(1, 2).is_ok());
+        assert!(ls.unref(1, 1).is_ok());
+        // last freed, first reused
+        ls.push_integer(40);
+        assert_eq!(ls.r#ref(1).ok(), Some(1));
+        ls.push_integer(50);
+        assert_eq!(ls.r#ref(1).ok(), Some(2));
+        ls.push_integer(60);
+        assert_eq!(ls.r#ref(1).ok(), Some(4));
+
+        assert!(ls.raw_get_i(1, 2).is_ok());
+        assert_eq!(ls.to_integer(-1), 50);
+        assert!(ls.raw_get_i(1, 3).is_ok());
+        assert_eq!(ls.to_integer(-1), 20);
+    }
+
+    #[test]
+    fn nil_is_not_referenced() {
+        let mut ls = LuaState::new();
+        ls.new_table();
+        ls.push_nil();
+        assert_eq!(ls.r#ref(1).ok(), Some(LUA_REFNIL));
+        assert_eq!(ls.get_top(), 1);
+        // releasing these does nothing
+        assert!(ls.unref(1, LUA_REFNIL).is_ok());
+        assert!(ls.unref(1, LUA_NOREF).is_ok());
+        ls.push_boolean(true);
+        assert_eq!(ls.r#ref(1).ok(), Some(1));
+    }
+
+    #[test]
+    fn registry_refs_keep_the_predefined_keys() {
+        let mut ls = LuaState::new();
+        ls.push_boolean(true);
+        let r = ls.r#ref(LUA_REGISTRYINDEX).ok().unwrap();
+        assert!(r != LUA_RIDX_MAINTHREAD && r != LUA_RIDX_GLOBALS);
+        assert!(ls.unref(LUA_REGISTRYINDEX, r).is_ok());
+        ls.push_boolean(false);
+        assert_eq!(ls.r#ref(LUA_REGISTRYINDEX).ok(), Some(r));
+
+        assert_eq!(ls.raw_get_i(LUA_REGISTRYINDEX, LUA_RIDX_MAINTHREAD).ok(), Some(LUA_TTHREAD));
+        assert_eq!(ls.raw_get_i(LUA_REGISTRYINDEX, LUA_RIDX_GLOBALS).ok(), Some(LUA_TTABLE));
+    }
+
+    #[test]
+    fn buffers_take_strings_and_numbers() {
+        let mut ls = LuaState::new();
+        let mut b = LuaBuffer::new();
+        b.add_string("a");
+        ls.push_integer(1);
+        assert!(b.add_value(&mut ls).is_ok());
+        b.add_char('-');
+        ls.push_number(1.5);
+        assert!(b.add_value(&mut ls).is_ok());
+        ls.push_nil();
+        let err = b.add_value(&mut ls).expect_err("nil added to a buffer");
+        assert_eq!(err.status, LUA_ERRRUN);
+        let msg = err.into_value(&mut ls.heap);
+        ls.stack.push(msg);
+        assert!(ls.to_string(-1).ends_with("attempt to add a nil value to a buffer"));
+        assert!(ls.pop(2).is_ok());
+        b.push_result(&mut ls);
+        assert_eq!(ls.get_top(), 1);
+        assert_eq!(ls.to_string(1), "a1-1.5");
+    }
+
+    fn error_text(ls: &mut LuaState, err: LuaError) -> String {
+        let msg = err.into_value(&mut ls.heap);
+        ls.stack.push(msg);
+        let s = ls.to_string(-1);
+        let _ = ls.pop(1);
+        s
+    }
+
+    #[test]
+    fn udata_borrow_conflicts_are_not_type_errors() {
+        let mut ls = LuaState::new();
+        ls.new_userdata_uv(1i64, 0);
+        assert!(ls.new_metatable("Counter").is_ok());
+        assert!(ls.set_metatable(-2).is_ok());
+        ls.push_integer(1);
+
+        let err = {
+            let _r = ls.check_udata_mut::<i64>(1, "Counter").ok().unwrap();
+            ls.check_udata::<i64>(1, "Counter").err().unwrap()
+        };
+        assert!(error_text(&mut ls, err).ends_with("(Counter is already borrowed)"));
+        let err = {
+            let _r = ls.check_udata::<i64>(1, "Counter").ok().unwrap();
+            assert!(ls.check_udata::<i64>(1, "Counter").is_ok());
+            ls.check_udata_mut::<i64>(1, "Counter").err().unwrap()
+        };
+        assert!(error_text(&mut ls, err).ends_with("(Counter is already borrowed)"));
+        let err = ls.check_udata::<i64>(2, "Counter").err().unwrap();
+        assert!(error_text(&mut ls, err).ends_with("(Counter expected, got number)"));
+
+        let err = {
+            let _r = ls.check_userdata_mut::<i64>(1).ok().unwrap();
+            ls.check_userdata::<i64>(1).err().unwrap()
+        };
+        assert!(error_text(&mut ls, err).ends_with("(i64 is already borrowed)"));
+        let err = ls.check_userdata::<u8>(1).err().unwrap();
+        assert!(error_text(&mut ls, err).ends_with("(u8 expected)"));
+    }
+}
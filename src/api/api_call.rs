@@ -0,0 +1,315 @@
+use crate::state::lua_state::LuaState;
+use crate::state::lua_stack::LuaStack;
+use crate::state::lua_value::LuaValue;
+use crate::state::lua_error::{LuaError, LuaResult};
+use crate::state::lua_thread::LuaThread;
+use crate::api::api_vm::VmAPI;
+use crate::api::api_stack::LuaAPI;
+use crate::api::consts::*;
+use crate::vm::instruction::Op;
+use crate::vm::inst_call::finish_op;
+use crate::state::gc::Gc;
+use crate::state::closure::Closure;
+use std::cell::RefCell;
+use std::mem;
+
+impl LuaState {
+    // call the function below the top `n_args` values. A Lua function only gets
+    // its frame pushed (true is returned); a Rust function runs to completion
+    // and leaves its results on the caller's stack.
+    pub fn precall(&mut self, n_args: usize, n_results: isize) -> LuaResult<bool> {
+        let (closure, n_args) = self.callee(n_args)?;
+        if self.frames.len() >= LUAI_MAXCALLS {
+            return Err(self.runtime_error("stack overflow"));
+        }
+        self.stack.sync_upvalues();
+
+        if let Some(proto) = &closure.proto {
+            let n_regs = proto.max_stack_size as usize;
+            let n_params = proto.num_params as usize;
+            let is_vararg = proto.is_vararg == 1;
+
+            let mut new_stack = LuaStack::new(n_regs, Some(closure));
+            let mut args = self.stack.pop_n(n_args)?;
+            self.stack.pop()?; // pop func
+            if n_args > n_params && is_vararg {
+                new_stack.varargs = args.split_off(n_params);
+            }
+            new_stack.push_n(args, n_params as isize);
+            new_stack.push_n(Vec::new(), (n_regs - n_params) as isize);
+            new_stack.n_results = n_results;
+            self.push_frame(new_stack);
+            return Ok(true);
+        }
+
+        let f = closure.rust_fn.unwrap();
+        let mut new_stack = LuaStack::new(n_args + LUA_MINSTACK, Some(closure));
+        let args = self.stack.pop_n(n_args)?;
+        self.stack.pop()?; // pop func
+        new_stack.push_n(args, -1);
+        new_stack.n_results = n_results;
+        self.push_frame(new_stack);
+
+        // on error the frame stays for the message handler, pcall unwinds it
+        let r = f(self)?;
+        self.post_ccall(r)?;
+        Ok(false)
+    }
+
+    // move the `r` results of the running Rust function to its caller
+    fn post_ccall(&mut self, r: usize) -> LuaResult<()> {
+        let results = self.stack.pop_n(r)?;
+        let frame = self.pop_frame();
+        self.stack.reload_upvalues();
+        self.stack.check(results.len());
+        self.stack.push_n(results, frame.n_results);
+        Ok(())
+    }
+
+    // like `call`, but a yield inside is not an error: the caller gets
+    // finished after the resume, by finish_op or the continuation of its frame
+    pub fn call_yieldable(&mut self, n_args: usize, n_results: isize) -> LuaResult<()> {
+        if self.n_ccalls >= LUAI_MAXCCALLS {
+            return Err(self.runtime_error("C stack overflow"));
+        }
+        self.n_ccalls += 1;
+        let result = match self.precall(n_args, n_results) {
+            Ok(true) => self.execute(),
+            Ok(false) => Ok(()),
+            Err(err) => Err(err),
+        };
+        self.n_ccalls -= 1;
+        result
+    }
+
+    // call a metamethod; the ones run for an instruction may yield (see luaT_callTM)
+    pub fn call_tm(&mut self, n_args: usize, n_results: isize) -> LuaResult<()> {
+        if self.stack.is_lua() {
+            self.call_yieldable(n_args, n_results)
+        } else {
+            self.call(n_args, n_results)
+        }
+    }
+
+    // the function below the top `n_args` values and the number of
+    // arguments it gets; a value with a __call metamethod becomes the
+    // first argument of the metamethod, which takes its place
+    fn callee(&mut self, n_args: usize) -> LuaResult<(Gc<Closure>, usize)> {
+        let val = self.stack.get(-(n_args as isize + 1));
+        if let LuaValue::Function(c) = val {
+            return Ok((c, n_args));
+        }
+        match self.get_metafield(&val, "__call") {
+            LuaValue::Function(c) => {
+                self.stack.push(val);
+                self.insert(-(n_args as isize + 1))?;
+                self.stack.set(-(n_args as isize + 2), LuaValue::Function(c))?;
+                Ok((c, n_args + 1))
+            },
+            _ => Err(self.type_error(&val, "call")),
+        }
+    }
+
+    // like `precall`, for a call whose results the running Lua function
+    // returns: a Lua callee takes over the frame of the running function,
+    // so tail calls do not use up the stack
+    pub fn pretailcall(&mut self, n_args: usize) -> LuaResult<bool> {
+        let (closure, n_args) = self.callee(n_args)?;
+        if closure.proto.is_none() {
+            return self.precall(n_args, LUA_MULTRET);
+        }
+        let func_and_args = self.stack.pop_n(n_args + 1)?;
+        self.stack.close_upvalues(0);
+        let frame = self.pop_frame();
+        self.stack.reload_upvalues();
+        self.stack.check(n_args + 1);
+        self.stack.push_n(func_and_args, -1);
+        self.precall(n_args, frame.n_results)?;
+        self.stack.is_tail = true;
+        Ok(true)
+    }
+
+    // run the frame pushed by `precall` until it returns; calls made by
+    // its instructions are run by this same loop
+    pub fn execute(&mut self) -> LuaResult<()> {
+        self.execute_until(self.frames.len())
+    }
+
+    // run Lua frames until fewer than `depth` frames are left below the current one
+    fn execute_until(&mut self, depth: usize) -> LuaResult<()> {
+        loop {
+            let op = self.fetch();
+            op.execute(self)?;
+            if let Op::Return { .. } = op {
+                self.post_return()?;
+                if self.frames.len() < depth {
+                    return Ok(());
+                }
+                let pc = self.stack.pc as usize;
+                let caller_op = self.stack.proto().ops[pc - 1];
+                finish_op(caller_op, self)?;
+            }
+        }
+    }
+
+    // move the values left by RETURN down to the caller's stack
+    fn post_return(&mut self) -> LuaResult<()> {
+        let n_regs = self.register_count();
+        let n = (self.stack.top() - n_regs) as usize;
+        self.stack.close_upvalues(0);
+        let results = self.stack.pop_n(n)?;
+        let frame = self.pop_frame();
+        self.stack.reload_upvalues();
+        self.stack.check(results.len());
+        self.stack.push_n(results, frame.n_results);
+        Ok(())
+    }
+
+    // run the message handler while the failed frames are still active
+    pub fn handle_error(&mut self, err: LuaError, handler: LuaValue) -> LuaError {
+        if handler.is_nil() || err.status != LUA_ERRRUN {
+            return err;
+        }
+        self.stack.push(handler);
+        let val = err.into_value(&mut self.heap);
+        self.stack.push(val);
+        match self.call(1, 1).and_then(|()| self.stack.pop()) {
+            Ok(val) => LuaError::new(LUA_ERRRUN, val),
+            Err(_) => {
+                LuaError::message(LUA_ERRERR, String::from("error in error handling"))
+            },
+        }
+    }
+
+    // run the suspended coroutine `co` with `args` until it yields, returns
+    // or fails; gives the status and the values it yielded or returned
+    pub fn resume_thread(&mut self, co: Gc<RefCell<LuaThread>>, args: Vec<LuaValue>) -> (u8, Vec<LuaValue>) {
+        // it runs with LUA_OK, so while it resumes another coroutine it is
+        // seen as normal and cannot be resumed itself
+        let from_yield = mem::replace(&mut co.borrow_mut().status, LUA_OK) == LUA_YIELD;
+        let from = self.switch_thread(co);
+        let old_nny = mem::replace(&mut self.nny, 0);
+        self.n_ccalls += 1;
+        let mut result = if from_yield {
+            self.unroll_thread(args)
+        } else {
+            self.start_thread(args)
+        };
+        // an error after a yield may belong to a pcall that was interrupted
+        while let Err(err) = result {
+            match self.find_ypcall() {
+                Some(depth) if err.status != LUA_YIELD => result = self.recover(err, depth),
+                _ => {
+                    result = Err(err);
+                    break;
+                },
+            }
+        }
+        self.n_ccalls -= 1;
+        self.nny = old_nny;
+
+        let status = match result {
+            Ok(()) => LUA_OK,
+            Err(err) if err.status == LUA_YIELD => LUA_YIELD,
+            Err(err) => {
+                // the coroutine is dead, leave only the error value
+                self.unwind(0);
+                self.stack.truncate(0);
+                let status = err.status;
+                let val = err.into_value(&mut self.heap);
+                self.stack.push(val);
+                status
+            },
+        };
+        // returned values are on the base stack, yielded ones on the frame of the yield
+        let n = self.stack.top() as usize;
+        let results = self.stack.pop_n(n).unwrap_or_default();
+        co.borrow_mut().status = status;
+        self.switch_thread(from);
+        (status, results)
+    }
+
+    // call the body of a coroutine, it is the only value on its base stack
+    fn start_thread(&mut self, args: Vec<LuaValue>) -> LuaResult<()> {
+        let n_args = args.len();
+        self.stack.check(n_args);
+        self.stack.push_n(args, -1);
+        if self.precall(n_args, LUA_MULTRET)? {
+            self.execute()?;
+        }
+        Ok(())
+    }
+
+    // return `args` from the function that yielded and carry on with its caller
+    fn unroll_thread(&mut self, args: Vec<LuaValue>) -> LuaResult<()> {
+        let frame = self.pop_frame();
+        self.stack.reload_upvalues();
+        self.stack.check(args.len());
+        self.stack.push_n(args, frame.n_results);
+        self.unroll()
+    }
+
+    // finish the frames a yield interrupted, down to the body of the
+    // coroutine: the instruction of a Lua frame, the continuation of a
+    // Rust one (see unroll)
+    fn unroll(&mut self) -> LuaResult<()> {
+        while !self.frames.is_empty() {
+            if self.stack.is_lua() {
+                let pc = self.stack.pc as usize;
+                let caller_op = self.stack.proto().ops[pc - 1];
+                finish_op(caller_op, self)?;
+                self.execute_until(self.frames.len())?;
+            } else {
+                self.finish_ccall(LUA_YIELD)?;
+            }
+        }
+        Ok(())
+    }
+
+    // run the continuation of the Rust function whose call came back,
+    // then return its results (see finishCcall)
+    fn finish_ccall(&mut self, status: u8) -> LuaResult<()> {
+        let r = match self.stack.k.take() {
+            Some(k) => {
+                self.stack.ypcall = None;
+                let ctx = self.stack.ctx;
+                k(self, status, ctx)?
+            },
+            // it yielded itself, what it got back are its results
+            None => self.stack.top() as usize,
+        };
+        self.post_ccall(r)
+    }
+
+    // the depth of the innermost frame with a pcall interrupted by a yield
+    fn find_ypcall(&self) -> Option<usize> {
+        if self.stack.ypcall.is_some() {
+            return Some(self.frames.len());
+        }
+        self.frames.iter().rposition(|frame| frame.ypcall.is_some())
+    }
+
+    // catch `err` in the pcall at `depth` as lua_pcallk would have, then
+    // carry on from there (see recover)
+    fn recover(&mut self, err: LuaError, depth: usize) -> LuaResult<()> {
+        let frame = if depth == self.frames.len() { &self.stack } else { &self.frames[depth] };
+        let (old_top, msgh) = frame.ypcall.unwrap();
+        let handler = if msgh == 0 { LuaValue::Nil } else { frame.get(msgh) };
+        let err = self.handle_error(err, handler);
+        self.unwind(depth);
+        self.stack.truncate(old_top as usize);
+        let status = err.status;
+        let val = err.into_value(&mut self.heap);
+        self.stack.push(val);
+        self.finish_ccall(status)?;
+        self.unroll()
+    }
+
+    // drop the frames above `depth` left behind by an error
+    pub fn unwind(&mut self, depth: usize) {
+        while self.frames.len() > depth {
+            self.stack.close_upvalues(0);
+            self.pop_frame();
+        }
+    }
+}
@@ -1,50 +1,221 @@
 use crate::state::lua_value::LuaValue;
+use std::cmp::Ordering;
+use std::ffi::CString;
+use std::os::raw::{c_char, c_int};
 
-macro_rules! cmp {
-    ($a:ident $op:tt $b:ident) => {
-        match $a {
-            LuaValue::LuaString(x) => match $b {
-                LuaValue::LuaString(y) => Some(x $op y),
-                _ => None,
-            },
-            LuaValue::Int64(x) => match $b {
-                LuaValue::Int64(y) => Some(x $op y),
-                LuaValue::Float64(y) => Some((*x as f64) $op *y),
-                _ => None,
-            },
-            LuaValue::Float64(x) => match $b {
-                LuaValue::Float64(y) => Some(x $op y),
-                LuaValue::Int64(y) => Some(*x $op (*y as f64)),
-                _ => None,
-            },
-            _ => None,
-        }
-    }
+extern "C" {
+    fn strcoll(s1: *const c_char, s2: *const c_char) -> c_int;
 }
 
+// how strings are ordered, str_cmp or str_coll
+pub type StrOrder = fn(&str, &str) -> Ordering;
+
+// integers of at most this magnitude convert to floats exactly
+const MAXINTFITSF: i64 = 1 << 53;
 
 pub fn _eq(a: &LuaValue, b: &LuaValue) -> bool {
-    if let Some(x) = cmp!(a == b) {
-        return x;
+    match (a, b) {
+        (LuaValue::Int64(i), LuaValue::Float64(f)) | (LuaValue::Float64(f), LuaValue::Int64(i)) => {
+            matches!(float_to_int_mode(*f, F2I::Eq), Some(fi) if fi == *i)
+        },
+        // tables and functions compare by reference
+        _ => a == b,
+    }
+}
+
+pub fn _lt(a: &LuaValue, b: &LuaValue, order: StrOrder) -> Option<bool> {
+    match (a, b) {
+        (LuaValue::Int64(x), LuaValue::Int64(y)) => Some(x < y),
+        (LuaValue::Float64(x), LuaValue::Float64(y)) => Some(x < y),
+        (LuaValue::Int64(i), LuaValue::Float64(f)) => Some(lt_int_float(*i, *f)),
+        // f < i <=> not (i <= f)
+        (LuaValue::Float64(f), LuaValue::Int64(i)) => Some(!f.is_nan() && !le_int_float(*i, *f)),
+        (LuaValue::LuaString(x), LuaValue::LuaString(y)) => Some(order(x, y) == Ordering::Less),
+        _ => None,
+    }
+}
+
+pub fn _le(a: &LuaValue, b: &LuaValue, order: StrOrder) -> Option<bool> {
+    match (a, b) {
+        (LuaValue::Int64(x), LuaValue::Int64(y)) => Some(x <= y),
+        (LuaValue::Float64(x), LuaValue::Float64(y)) => Some(x <= y),
+        (LuaValue::Int64(i), LuaValue::Float64(f)) => Some(le_int_float(*i, *f)),
+        // f <= i <=> not (i < f)
+        (LuaValue::Float64(f), LuaValue::Int64(i)) => Some(!f.is_nan() && !lt_int_float(*i, *f)),
+        (LuaValue::LuaString(x), LuaValue::LuaString(y)) => Some(order(x, y) != Ordering::Greater),
+        _ => None,
+    }
+}
+
+// how a float is rounded to an integer
+#[derive(Clone, Copy)]
+enum F2I {
+    Eq,
+    Floor,
+    Ceil,
+}
+
+// see luaV_tointeger; None for NaN, infinities and values out of range
+fn float_to_int_mode(n: f64, mode: F2I) -> Option<i64> {
+    let f = match mode {
+        F2I::Eq | F2I::Floor => n.floor(),
+        F2I::Ceil => n.ceil(),
+    };
+    if f != n && matches!(mode, F2I::Eq) {
+        return None;
+    }
+    if (-9223372036854775808.0..9223372036854775808.0).contains(&f) {
+        Some(f as i64)
     } else {
-        match a {
-            LuaValue::Nil => match b {
-                LuaValue::Nil => true,
-                _ => false
-            },
-            LuaValue::Bool(cur_bool) => match b {
-                LuaValue::Bool(cur_bool2) => cur_bool == cur_bool2,
-                _ => false
-            },
-            _ => false
-        }
+        None
+    }
+}
+
+// i < f <=> i < ceil(f), unless i converts exactly (see LTintfloat)
+fn lt_int_float(i: i64, f: f64) -> bool {
+    if (-MAXINTFITSF..=MAXINTFITSF).contains(&i) {
+        return (i as f64) < f;
+    }
+    match float_to_int_mode(f, F2I::Ceil) {
+        Some(fi) => i < fi,
+        // NaN or out of range: less only than large positive values
+        None => f > 0.0,
+    }
+}
+
+// i <= f <=> i <= floor(f) (see LEintfloat)
+fn le_int_float(i: i64, f: f64) -> bool {
+    if (-MAXINTFITSF..=MAXINTFITSF).contains(&i) {
+        return (i as f64) <= f;
+    }
+    match float_to_int_mode(f, F2I::Floor) {
+        Some(fi) => i <= fi,
+        None => f > 0.0,
     }
 }
 
-pub fn _lt(a: &LuaValue, b: &LuaValue) -> Option<bool> {
-    cmp!(a < b)
+// strings order byte by byte
+pub fn str_cmp(a: &str, b: &str) -> Ordering {
+    a.as_bytes().cmp(b.as_bytes())
 }
 
-pub fn _le(a: &LuaValue, b: &LuaValue) -> Option<bool> {
-    cmp!(a <= b)
-}
\ No newline at end of file
+// order by the current locale's collation, as the reference l_strcmp
+// does; strcoll stops at '\0', so embedded zeros are compared chunk by chunk
+pub fn str_coll(a: &str, b: &str) -> Ordering {
+    let mut a_parts = a.split('\0');
+    let mut b_parts = b.split('\0');
+    loop {
+        let (x, y) = match (a_parts.next(), b_parts.next()) {
+            (Some(x), Some(y)) => (x, y),
+            // the one with fewer chunks is a prefix of the other
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (None, None) => return Ordering::Equal,
+        };
+        // the chunks hold no '\0'
+        let (cx, cy) = (CString::new(x).unwrap(), CString::new(y).unwrap());
+        let res = unsafe { strcoll(cx.as_ptr(), cy.as_ptr()) };
+        if res != 0 {
+            return res.cmp(&0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::gc::Heap;
+    use crate::state::lua_state::LuaState;
+    use crate::api::api_stack::LuaAPI;
+    use crate::api::consts::*;
+    use LuaValue::{Float64, Int64};
+
+    const NAN: LuaValue = Float64(f64::NAN);
+
+    fn lt(a: &LuaValue, b: &LuaValue) -> Option<bool> {
+        _lt(a, b, str_cmp)
+    }
+
+    fn le(a: &LuaValue, b: &LuaValue) -> Option<bool> {
+        _le(a, b, str_cmp)
+    }
+
+    #[test]
+    fn integers_and_floats_compare_exactly() {
+        // 2^53 + 1 has no float, so it is not 2^53 even if it converts to it
+        let i = Int64((1 << 53) + 1);
+        let f = Float64(2f64.powi(53));
+        assert!(!_eq(&i, &f));
+        assert_eq!(lt(&f, &i), Some(true));
+        assert_eq!(le(&i, &f), Some(false));
+        assert!(_eq(&Int64(1 << 53), &f));
+        assert!(_eq(&Float64(1.0), &Int64(1)));
+        assert!(!_eq(&Float64(1.5), &Int64(1)));
+    }
+
+    #[test]
+    fn integers_against_floats_out_of_range() {
+        let max = Int64(i64::MAX);
+        let min = Int64(i64::MIN);
+        let two63 = Float64(2f64.powi(63));
+        assert_eq!(lt(&max, &two63), Some(true));
+        assert_eq!(le(&two63, &max), Some(false));
+        assert!(!_eq(&max, &two63));
+        assert_eq!(le(&Float64(-2f64.powi(63)), &min), Some(true));
+        assert_eq!(lt(&Float64(-2f64.powi(63)), &min), Some(false));
+        assert_eq!(lt(&max, &Float64(f64::INFINITY)), Some(true));
+        assert_eq!(lt(&Float64(f64::NEG_INFINITY), &min), Some(true));
+    }
+
+    #[test]
+    fn nan_is_unordered() {
+        for x in [Int64(1), Int64(i64::MAX), Float64(1.0), NAN].iter() {
+            assert_eq!(lt(x, &NAN), Some(false));
+            assert_eq!(le(x, &NAN), Some(false));
+            assert_eq!(lt(&NAN, x), Some(false));
+            assert_eq!(le(&NAN, x), Some(false));
+            assert!(!_eq(x, &NAN));
+        }
+    }
+
+    #[test]
+    fn strings_compare_byte_by_byte() {
+        assert_eq!(str_cmp("a", "b"), Ordering::Less);
+        assert_eq!(str_cmp("B", "a"), Ordering::Less);
+        assert_eq!(str_cmp("a", "a\0"), Ordering::Less);
+        assert_eq!(str_cmp("a\0b", "a\0a"), Ordering::Greater);
+        assert_eq!(str_cmp("z", "\u{e9}"), Ordering::Less);
+        assert_eq!(str_cmp("", ""), Ordering::Equal);
+
+        let mut heap = Heap::new();
+        let a = LuaValue::new_string(&mut heap, "abc");
+        let b = LuaValue::new_string(&mut heap, "abd");
+        assert_eq!(lt(&a, &b), Some(true));
+        assert_eq!(le(&b, &a), Some(false));
+        assert_eq!(le(&a, &a), Some(true));
+        assert_eq!(lt(&a, &Int64(1)), None);
+    }
+
+    #[test]
+    fn collation_goes_chunk_by_chunk() {
+        // the tests run in the "C" locale, where strcoll is strcmp
+        assert_eq!(str_coll("a", "b"), Ordering::Less);
+        assert_eq!(str_coll("abc", "abc"), Ordering::Equal);
+        assert_eq!(str_coll("a", "a\0"), Ordering::Less);
+        assert_eq!(str_coll("a\0", "a"), Ordering::Greater);
+        assert_eq!(str_coll("a\0b", "a\0a"), Ordering::Greater);
+        assert_eq!(str_coll("a\0\0", "a\0b"), Ordering::Less);
+        assert_eq!(str_coll("b", "a\0z"), Ordering::Greater);
+    }
+
+    #[test]
+    fn the_state_picks_the_order() {
+        let mut ls = LuaState::new();
+        ls.push_string(String::from("a\0b"));
+        ls.push_string(String::from("a\0a"));
+        assert!(matches!(ls.compare(1, 2, LUA_OPLT), Ok(false)));
+        ls.str_order = str_coll;
+        assert!(matches!(ls.compare(1, 2, LUA_OPLT), Ok(false)));
+        assert!(matches!(ls.compare(2, 1, LUA_OPLE), Ok(true)));
+    }
+}
@@ -0,0 +1,387 @@
+use crate::state::lua_state::LuaState;
+use crate::state::lua_stack::LuaStack;
+use crate::state::lua_value::LuaValue;
+use crate::binary_chunk::prototype::{Prototype, Constant};
+use crate::vm::instruction::Instruction_impl;
+use crate::vm::opcodes::*;
+use crate::api::consts::*;
+use crate::api::api_arith;
+
+const LUA_IDSIZE: usize = 60;
+// a traceback longer than LEVELS1 + LEVELS2 skips the levels in between
+const LEVELS1: usize = 10;
+const LEVELS2: usize = 11;
+
+// printable form of a chunk name, see luaO_chunkid
+pub fn chunk_id(source: &str) -> String {
+    let chars: Vec<char> = source.chars().collect();
+    if let Some(c) = chars.first() {
+        if *c == '=' {
+            // literal source
+            return chars[1..].iter().take(LUA_IDSIZE - 1).collect();
+        }
+        if *c == '@' {
+            // file name, keep its end
+            if chars.len() <= LUA_IDSIZE {
+                return chars[1..].iter().collect();
+            }
+            let keep = LUA_IDSIZE - 3;
+            let rest: String = chars[chars.len() - keep..].iter().collect();
+            return format!("...{}", rest);
+        }
+    }
+    // source code, keep its first line
+    let max_len = LUA_IDSIZE - "[string \"...\"]".len() - 1;
+    let first_line: Vec<char> = chars.iter().cloned().take_while(|c| *c != '\n').collect();
+    if first_line.len() == chars.len() && chars.len() < max_len {
+        return format!("[string \"{}\"]", source);
+    }
+    let head: String = first_line.iter().take(max_len).collect();
+    format!("[string \"{}...\"]", head)
+}
+
+impl LuaState {
+    // level 0 is the running function, level 1 its caller and so on
+    pub fn frame_at(&self, level: usize) -> Option<&LuaStack> {
+        let frame = if level == 0 {
+            &self.stack
+        } else if level <= self.frames.len() {
+            &self.frames[self.frames.len() - level]
+        } else {
+            return None;
+        };
+        frame.closure.as_ref()?;
+        Some(frame)
+    }
+
+    // "chunkname:currentline: " of the function at `level`, see luaL_where
+    pub fn location(&self, level: usize) -> String {
+        if let Some(frame) = self.frame_at(level) {
+            if let Some(line) = current_line(frame) {
+                return format!("{}:{}: ", chunk_id(&frame.proto().source), line);
+            }
+        }
+        String::new()
+    }
+
+    // the message followed by one line per active frame, see luaL_traceback
+    pub fn traceback(&self, msg: Option<&str>, mut level: usize) -> String {
+        let mut last = level;
+        while self.frame_at(last + 1).is_some() {
+            last += 1;
+        }
+        let mut n1 = if last.saturating_sub(level) > LEVELS1 + LEVELS2 { LEVELS1 as isize } else { -1 };
+
+        let mut s = String::new();
+        if let Some(msg) = msg {
+            s.push_str(msg);
+            s.push('\n');
+        }
+        s.push_str("stack traceback:");
+        while let Some(frame) = self.frame_at(level) {
+            level += 1;
+            if n1 == 0 {
+                s.push_str("\n\t...");
+                level = last - LEVELS2 + 1;
+                n1 -= 1;
+                continue;
+            }
+            n1 -= 1;
+            let closure = frame.closure.as_ref().unwrap();
+            match &closure.proto {
+                Some(proto) => {
+                    s.push_str(&format!("\n\t{}:", chunk_id(&proto.source)));
+                    if let Some(line) = current_line(frame) {
+                        s.push_str(&format!("{}:", line));
+                    }
+                },
+                None => s.push_str("\n\t[C]:"),
+            }
+            s.push_str(" in ");
+            s.push_str(&self.func_name(frame, level - 1));
+            if frame.is_tail {
+                s.push_str("\n\t(...tail calls...)");
+            }
+        }
+        s
+    }
+
+    fn func_name(&self, frame: &LuaStack, level: usize) -> String {
+        let f = LuaValue::Function(frame.closure.unwrap());
+        if let Some(name) = self.global_func_name(&f) {
+            return format!("function '{}'", name);
+        }
+        if let Some((kind, name)) = self.func_name_at(level) {
+            return format!("{} '{}'", kind, name);
+        }
+        match &frame.closure.as_ref().unwrap().proto {
+            Some(proto) if proto.line_defined == 0 => String::from("main chunk"),
+            Some(proto) => format!("function <{}:{}>", chunk_id(&proto.source), proto.line_defined),
+            None => String::from("?"),
+        }
+    }
+
+    // how the function at `level` was called, worked out from its caller's code
+    pub fn func_name_at(&self, level: usize) -> Option<(&'static str, String)> {
+        if self.frame_at(level)?.is_tail {
+            return None; // the caller is gone
+        }
+        let caller = self.frame_at(level + 1)?;
+        let proto = caller.closure.as_ref()?.proto.as_ref()?;
+        func_name_from_code(proto, caller.pc as usize - 1)
+    }
+
+    // " (kind 'name')" for a value the running instruction failed on, see varinfo
+    pub fn var_info(&self, val: &LuaValue) -> String {
+        let proto = match self.stack.closure.as_ref().and_then(|c| c.proto.as_ref()) {
+            Some(proto) => proto,
+            None => return String::new(),
+        };
+        let pc = self.stack.pc as usize - 1;
+        for slot in operand_slots(proto, pc, |r| self.stack.get(r + 1)) {
+            let (slot_val, info) = match slot {
+                Slot::Reg(r) => (self.stack.get(r + 1), obj_name(proto, pc, r)),
+                Slot::Upval(u) => {
+                    let v = self.stack.get(lua_upvalue_index(u + 1));
+                    (v, Some(("upvalue", upval_name(proto, u as usize))))
+                },
+                Slot::Const(v) => (v, None),
+            };
+            if slot_val == *val {
+                return match info {
+                    Some((kind, name)) => format!(" ({} '{}')", kind, name),
+                    None => String::new(),
+                };
+            }
+        }
+        String::new()
+    }
+
+    // a global holding `f`, or a field of a global table ("string.format")
+    pub fn global_func_name(&self, f: &LuaValue) -> Option<String> {
+        let globals = self.registry_get(LUA_RIDX_GLOBALS);
+        if let Some(name) = find_field(&globals, f) {
+            return Some(name);
+        }
+        let mut k = LuaValue::Nil;
+        while let Some((lib_name, lib)) = next_entry(&globals, &k) {
+            if let (LuaValue::LuaString(lib_name), LuaValue::Table(_)) = (&lib_name, &lib) {
+                if let Some(name) = find_field(&lib, f) {
+                    return Some(format!("{}.{}", lib_name, name));
+                }
+            }
+            k = lib_name;
+        }
+        None
+    }
+}
+
+// line of the instruction being run, None for Rust functions and stripped chunks
+pub fn current_line(frame: &LuaStack) -> Option<u32> {
+    let proto = frame.closure.as_ref()?.proto.as_ref()?;
+    let pc = frame.pc as usize;
+    if pc == 0 {
+        return None;
+    }
+    proto.line_info.get(pc - 1).cloned()
+}
+
+fn next_entry(t: &LuaValue, k: &LuaValue) -> Option<(LuaValue, LuaValue)> {
+    match t {
+        LuaValue::Table(tbl) => tbl.borrow().next(k).unwrap_or(None),
+        _ => None,
+    }
+}
+
+fn find_field(t: &LuaValue, f: &LuaValue) -> Option<String> {
+    let mut k = LuaValue::Nil;
+    while let Some((key, val)) = next_entry(t, &k) {
+        if let LuaValue::LuaString(name) = &key {
+            if val == *f {
+                return Some(name.to_string());
+            }
+        }
+        k = key;
+    }
+    None
+}
+
+// where an operand of the instruction at `pc` lives
+enum Slot {
+    Reg(isize),
+    Upval(isize),
+    Const(LuaValue),
+}
+
+fn rk_slot(proto: &Prototype, rk: isize) -> Slot {
+    if rk > 0xFF {
+        Slot::Const(proto.k[(rk & 0xFF) as usize])
+    } else {
+        Slot::Reg(rk)
+    }
+}
+
+// the operands an error raised by the instruction at `pc` can be about
+fn operand_slots<F: Fn(isize) -> LuaValue>(proto: &Prototype, pc: usize, reg: F) -> Vec<Slot> {
+    let i = proto.code[pc];
+    let (a, b, c) = i.ABC();
+    match i.opcode() {
+        OP_GETTABLE | OP_SELF => vec![Slot::Reg(b)],
+        OP_GETTABUP => vec![Slot::Upval(b)],
+        OP_SETTABLE => vec![Slot::Reg(a)],
+        OP_SETTABUP => vec![Slot::Upval(a)],
+        OP_ADD..=OP_SHR => vec![rk_slot(proto, b), rk_slot(proto, c)],
+        OP_UNM | OP_BNOT | OP_LEN => vec![Slot::Reg(b)],
+        OP_CALL | OP_TAILCALL => vec![Slot::Reg(a)],
+        OP_CONCAT => {
+            // values are joined from the right, the left one of a bad pair is blamed first
+            let is_bad = |r: isize| !reg(r).to_stringx().1;
+            match (b..=c).rev().find(|r| is_bad(*r)) {
+                Some(j) if j == c && j > b && is_bad(j - 1) => vec![Slot::Reg(j - 1)],
+                Some(j) => vec![Slot::Reg(j)],
+                None => Vec::new(),
+            }
+        },
+        _ => Vec::new(),
+    }
+}
+
+// name of the `local_number`-th local active at `pc`, see luaF_getlocalname
+fn local_name(proto: &Prototype, mut local_number: usize, pc: usize) -> Option<String> {
+    for var in proto.loc_vars.iter() {
+        if var.start_pc as usize > pc {
+            break;
+        }
+        if pc < var.end_pc as usize {
+            local_number -= 1;
+            if local_number == 0 {
+                return Some(var.var_name.clone());
+            }
+        }
+    }
+    None
+}
+
+fn upval_name(proto: &Prototype, uv: usize) -> String {
+    match proto.up_value_names.get(uv) {
+        Some(name) => name.clone(),
+        None => String::from("?"),
+    }
+}
+
+// an instruction inside a jump may not have run
+fn filter_pc(pc: usize, jmp_target: usize) -> Option<usize> {
+    if pc < jmp_target {
+        None
+    } else {
+        Some(pc)
+    }
+}
+
+// the last instruction before `last_pc` that changed register `reg`
+fn find_set_reg(proto: &Prototype, last_pc: usize, reg: isize) -> Option<usize> {
+    let mut set_reg = None;
+    let mut jmp_target = 0;
+    for pc in 0..last_pc {
+        let i = proto.code[pc];
+        let (a, b, _) = i.ABC();
+        match i.opcode() {
+            OP_LOADNIL => {
+                if a <= reg && reg <= a + b {
+                    set_reg = filter_pc(pc, jmp_target);
+                }
+            },
+            OP_TFORCALL => {
+                if reg >= a + 2 {
+                    set_reg = filter_pc(pc, jmp_target);
+                }
+            },
+            OP_CALL | OP_TAILCALL => {
+                if reg >= a {
+                    set_reg = filter_pc(pc, jmp_target);
+                }
+            },
+            OP_JMP => {
+                let (_, sbx) = i.AsBx();
+                let dest = (pc as isize + 1 + sbx) as usize;
+                // forward jump that does not skip `last_pc`
+                if pc < dest && dest <= last_pc && dest > jmp_target {
+                    jmp_target = dest;
+                }
+            },
+            op => {
+                if OPCODES[op as usize].set_a_flag == 1 && reg == a {
+                    set_reg = filter_pc(pc, jmp_target);
+                }
+            },
+        }
+    }
+    set_reg
+}
+
+// kind and name of the value in register `reg` at `last_pc`, see getobjname
+fn obj_name(proto: &Prototype, last_pc: usize, reg: isize) -> Option<(&'static str, String)> {
+    if let Some(name) = local_name(proto, reg as usize + 1, last_pc) {
+        return Some(("local", name));
+    }
+    // else try symbolic execution
+    let pc = find_set_reg(proto, last_pc, reg)?;
+    let i = proto.code[pc];
+    let (a, b, c) = i.ABC();
+    match i.opcode() {
+        OP_MOVE if b < a => obj_name(proto, pc, b),
+        op @ (OP_GETTABUP | OP_GETTABLE) => {
+            let table_name = if op == OP_GETTABLE {
+                local_name(proto, b as usize + 1, pc)
+            } else {
+                Some(upval_name(proto, b as usize))
+            };
+            let kind = if table_name.as_deref() == Some("_ENV") { "global" } else { "field" };
+            Some((kind, k_name(proto, pc, c)))
+        },
+        OP_GETUPVAL => Some(("upvalue", upval_name(proto, b as usize))),
+        op @ (OP_LOADK | OP_LOADKX) => {
+            let bx = if op == OP_LOADK { i.ABx().1 } else { proto.code[pc + 1].Ax() };
+            match &proto.constants[bx as usize] {
+                Constant::LuaStr(s) => Some(("constant", s.to_string())),
+                _ => None,
+            }
+        },
+        OP_SELF => Some(("method", k_name(proto, pc, c))),
+        _ => None,
+    }
+}
+
+// name of a table key, when it is a string constant
+fn k_name(proto: &Prototype, pc: usize, c: isize) -> String {
+    if c > 0xFF {
+        if let Constant::LuaStr(s) = &proto.constants[(c & 0xFF) as usize] {
+            return s.to_string();
+        }
+    } else if let Some(("constant", name)) = obj_name(proto, pc, c) {
+        return name;
+    }
+    String::from("?")
+}
+
+// name of the function called by the instruction at `pc`, see funcnamefromcode
+fn func_name_from_code(proto: &Prototype, pc: usize) -> Option<(&'static str, String)> {
+    let i = proto.code[pc];
+    let event = match i.opcode() {
+        OP_CALL | OP_TAILCALL => return obj_name(proto, pc, i.ABC().0),
+        OP_TFORCALL => return Some(("for iterator", String::from("for iterator"))),
+        // other instructions can call through metamethods
+        OP_SELF | OP_GETTABUP | OP_GETTABLE => "__index",
+        OP_SETTABUP | OP_SETTABLE => "__newindex",
+        op @ OP_ADD..=OP_SHR => api_arith::METAMETHODS[(op - OP_ADD) as usize],
+        OP_UNM => "__unm",
+        OP_BNOT => "__bnot",
+        OP_LEN => "__len",
+        OP_CONCAT => "__concat",
+        OP_EQ => "__eq",
+        OP_LT => "__lt",
+        OP_LE => "__le",
+        _ => return None,
+    };
+    Some(("metamethod", String::from(event)))
+}
@@ -0,0 +1,60 @@
+use crate::state::lua_state::LuaState;
+use crate::state::lua_value::LuaValue;
+use crate::state::lua_error::LuaError;
+use crate::api::api_stack::LuaAPI;
+use crate::api::consts::*;
+use std::any;
+
+impl LuaState {
+    // prefixed with the position of the running Lua function, see luaG_runerror
+    pub fn runtime_error(&self, msg: &str) -> LuaError {
+        LuaError::runtime(format!("{}{}", self.location(0), msg))
+    }
+
+    // attempt to <op> a <type> value (<kind> '<name>')
+    pub fn type_error(&self, val: &LuaValue, op: &str) -> LuaError {
+        let t = self.type_name(val.get_type());
+        self.runtime_error(&format!("attempt to {} a {} value{}", op, t, self.var_info(val)))
+    }
+
+    pub fn arith_error(&self, a: &LuaValue, b: &LuaValue, op: u8) -> LuaError {
+        let is_bitwise = (LUA_OPBAND..=LUA_OPSHR).contains(&op) || op == LUA_OPBNOT;
+        if is_bitwise && a.get_type() == LUA_TNUMBER && b.get_type() == LUA_TNUMBER {
+            // blame the first operand that is not integral
+            let culprit = if a.to_integerx().1 { b } else { a };
+            let info = self.var_info(culprit);
+            return self.runtime_error(&format!("number{} has no integer representation", info));
+        }
+        // blame the first operand that is not a number
+        let culprit = if a.to_numberx().1 { b } else { a };
+        if is_bitwise {
+            self.type_error(culprit, "perform bitwise operation on")
+        } else {
+            self.type_error(culprit, "perform arithmetic on")
+        }
+    }
+
+    pub fn order_error(&self, a: &LuaValue, b: &LuaValue) -> LuaError {
+        let t1 = self.type_name(a.get_type());
+        let t2 = self.type_name(b.get_type());
+        if t1 == t2 {
+            self.runtime_error(&format!("attempt to compare two {} values", t1))
+        } else {
+            self.runtime_error(&format!("attempt to compare {} with {}", t1, t2))
+        }
+    }
+
+    // bad argument #<idx> (<T> expected)
+    pub fn userdata_error<T>(&self, idx: isize) -> LuaError {
+        self.runtime_error(&format!("bad argument #{} ({} expected)", idx, short_type_name::<T>()))
+    }
+
+    // bad argument #<idx> (<T> is already borrowed)
+    pub fn borrow_error<T>(&self, idx: isize) -> LuaError {
+        self.runtime_error(&format!("bad argument #{} ({} is already borrowed)", idx, short_type_name::<T>()))
+    }
+}
+
+fn short_type_name<T>() -> &'static str {
+    any::type_name::<T>().rsplit("::").next().unwrap_or("userdata")
+}
@@ -0,0 +1,82 @@
+use crate::state::lua_state::LuaState;
+use crate::state::lua_value::LuaValue;
+use crate::state::lua_error::{LuaError, LuaResult};
+use crate::api::api_stack::LuaAPI;
+use crate::api::consts::*;
+use std::mem;
+
+const GCFINALIZENUM: usize = 4; // finalizers to call per step
+
+impl LuaState {
+    // a collector step if enough was allocated since the last one; only
+    // call it where every live value is reachable from the roots
+    pub fn check_gc(&mut self) -> LuaResult<()> {
+        if self.heap.needs_step() {
+            self.gc_step()?;
+        }
+        Ok(())
+    }
+
+    pub fn gc_step(&mut self) -> LuaResult<()> {
+        self.collect(false);
+        for _ in 0..GCFINALIZENUM {
+            if !self.heap.has_tobefnz() {
+                break;
+            }
+            self.call_finalizer(true)?;
+        }
+        Ok(())
+    }
+
+    pub fn full_gc(&mut self) -> LuaResult<()> {
+        self.collect(true);
+        while self.heap.has_tobefnz() {
+            self.call_finalizer(true)?;
+        }
+        Ok(())
+    }
+
+    // call every pending finalizer before the state goes away
+    pub fn close(&mut self) {
+        self.heap.separate_all();
+        while self.heap.has_tobefnz() {
+            let _ = self.call_finalizer(false);
+        }
+    }
+
+    // __gc of the next unreachable object; with `propagate` its errors are
+    // raised as LUA_ERRGCMM, otherwise they are dropped
+    fn call_finalizer(&mut self, propagate: bool) -> LuaResult<()> {
+        let obj = match self.heap.pop_tobefnz() {
+            Some(obj) => obj,
+            None => return Ok(()),
+        };
+        let tm = self.get_metafield(&obj, "__gc");
+        if tm.is_nil() {
+            return Ok(());
+        }
+        // no collector steps inside a finalizer
+        let running = mem::replace(&mut self.heap.running, false);
+        self.stack.check(2);
+        self.stack.push(tm);
+        self.stack.push(obj);
+        let status = self.pcall(1, 0, 0);
+        self.heap.running = running;
+        if status == LUA_OK {
+            return Ok(());
+        }
+        let err = self.stack.pop()?;
+        if !propagate {
+            return Ok(());
+        }
+        if status == LUA_ERRRUN {
+            let msg = match err {
+                LuaValue::LuaString(s) => s.to_string(),
+                _ => String::from("no message"),
+            };
+            let msg = format!("error in __gc metamethod ({})", msg);
+            return Err(LuaError::message(LUA_ERRGCMM, msg));
+        }
+        Err(LuaError::new(status, err))
+    }
+}
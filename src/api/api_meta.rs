@@ -0,0 +1,75 @@
+use crate::state::lua_state::LuaState;
+use crate::state::lua_table::LuaTable;
+use crate::state::lua_value::LuaValue;
+use crate::state::lua_error::LuaResult;
+use crate::state::gc::Gc;
+use std::cell::RefCell;
+
+// tables and full userdata carry their own metatable, other types share
+// one per type in the registry
+fn type_mt_key(val: &LuaValue) -> String {
+    format!("_MT{}", val.get_type())
+}
+
+impl LuaState {
+    pub fn _get_metatable(&self, val: &LuaValue) -> Option<Gc<RefCell<LuaTable>>> {
+        if let LuaValue::Table(t) = val {
+            return t.borrow().metatable;
+        }
+        if let LuaValue::UserData(u) = val {
+            return u.borrow().metatable;
+        }
+        if let LuaValue::Table(registry) = &self.registry {
+            if let LuaValue::Table(mt) = registry.borrow().get_str(&type_mt_key(val)) {
+                return Some(mt);
+            }
+        }
+        None
+    }
+
+    pub fn _set_metatable(&mut self, val: &LuaValue, mt: Option<Gc<RefCell<LuaTable>>>) {
+        let has_gc = mt.is_some_and(|mt| mt.borrow().has_field("__gc"));
+        match val {
+            LuaValue::Table(t) => t.borrow_mut().metatable = mt,
+            LuaValue::UserData(u) => u.borrow_mut().metatable = mt,
+            _ => {
+                let key = LuaValue::new_string(&mut self.heap, &type_mt_key(val));
+                if let LuaValue::Table(registry) = &self.registry {
+                    let mt = match mt {
+                        Some(mt) => LuaValue::Table(mt),
+                        None => LuaValue::Nil,
+                    };
+                    registry.borrow_mut().put(key, mt).unwrap();
+                }
+                return;
+            },
+        }
+        if has_gc {
+            self.heap.check_finalizer(val);
+        }
+    }
+
+    pub fn get_metafield(&self, val: &LuaValue, name: &str) -> LuaValue {
+        match self._get_metatable(val) {
+            Some(mt) => mt.borrow().get_str(name),
+            None => LuaValue::Nil,
+        }
+    }
+
+    // look up `name` on a, then on b, and call it with (a, b)
+    pub fn call_metamethod(&mut self, a: &LuaValue, b: &LuaValue, name: &str) -> LuaResult<Option<LuaValue>> {
+        let mut mm = self.get_metafield(a, name);
+        if mm.is_nil() {
+            mm = self.get_metafield(b, name);
+            if mm.is_nil() {
+                return Ok(None);
+            }
+        }
+        self.stack.check(4);
+        self.stack.push(mm);
+        self.stack.push(*a);
+        self.stack.push(*b);
+        self.call_tm(2, 1)?;
+        Ok(Some(self.stack.pop()?))
+    }
+}
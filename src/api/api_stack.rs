@@ -1,32 +1,46 @@
 use crate::state::lua_state::LuaState;
-use crate::state::lua_value::LuaValue;
-use crate::api::consts::*;
-use crate::api::api_arith;
+use crate::state::lua_string::TString;
+use crate::state::lua_value::{LuaValue, str_to_number};
 use crate::api::api_cmp;
+use crate::api::consts::*;
+use crate::api::api_vm::VmAPI;
+use crate::state::closure::{Closure, KFunction, RustFn};
+use crate::state::lua_error::{LuaError, LuaResult};
+use crate::state::lua_thread::LuaThread;
+use crate::binary_chunk;
+use crate::state::gc::Gc;
+use crate::state::lua_userdata::Userdata;
+use crate::state::lua_table::LuaTable;
+use std::rc::Rc;
+use std::cell::{Ref, RefCell, RefMut};
+use std::ffi::c_void;
+use std::ptr;
+use std::mem;
 
 pub trait LuaAPI {
     // basic operation
-    fn compare(&mut self, idx1: isize, idx2: isize, op: u8) -> bool;
-    fn arith(&mut self, op: u8);
-    fn concat(&mut self, n: isize);
-    fn len(&mut self, idx: isize);
+    fn compare(&mut self, idx1: isize, idx2: isize, op: u8) -> LuaResult<bool>;
+    fn arith(&mut self, op: u8) -> LuaResult<()>;
+    fn concat(&mut self, n: isize) -> LuaResult<()>;
+    fn len(&mut self, idx: isize) -> LuaResult<()>;
     fn get_top(&self) -> isize;
     fn abs_index(&self, idx: isize) -> isize;
     fn check_stack(&mut self, size: usize) -> bool;
-    fn pop(&mut self, size: usize);
-    fn copy(&mut self, from: isize, to: isize);
+    fn pop(&mut self, size: usize) -> LuaResult<()>;
+    fn copy(&mut self, from: isize, to: isize) -> LuaResult<()>;
     fn push_value(&mut self, idx: isize);
-    fn replace(&mut self, idx: isize);
-    fn insert(&mut self, idx: isize);
-    fn remove(&mut self, idx: isize);
-    fn rotate(&mut self, idx: isize, n: isize);
-    fn set_top(&mut self, idx: isize);
+    fn replace(&mut self, idx: isize) -> LuaResult<()>;
+    fn insert(&mut self, idx: isize) -> LuaResult<()>;
+    fn remove(&mut self, idx: isize) -> LuaResult<()>;
+    fn rotate(&mut self, idx: isize, n: isize) -> LuaResult<()>;
+    fn set_top(&mut self, idx: isize) -> LuaResult<()>;
     fn push_nil(&mut self);
     // push basic type
     fn push_boolean(&mut self, b: bool);
     fn push_integer(&mut self, n: i64);
     fn push_number(&mut self, n: f64);
     fn push_string(&mut self, s: String);
+    fn push_light_userdata(&mut self, p: *mut c_void);
     // access
     fn type_name(&self, cur_type: i8) -> &str;
     fn type_id(&self, idx: isize) -> i8;
@@ -37,6 +51,11 @@ pub trait LuaAPI {
     fn is_string(&self, idx: isize) -> bool;
     fn is_number(&self, idx: isize) -> bool;
     fn is_integer(&self, idx: isize) -> bool;
+    fn is_function(&self, idx: isize) -> bool;
+    fn is_table(&self, idx: isize) -> bool;
+    fn is_light_userdata(&self, idx: isize) -> bool;
+    fn is_userdata(&self, idx: isize) -> bool;
+    fn is_thread(&self, idx: isize) -> bool;
     fn to_boolean(&self, idx: isize) -> bool;
     fn to_number(&self, idx: isize) -> f64;
     fn to_numberx(&self, idx: isize) -> (f64, bool);
@@ -44,82 +63,136 @@ pub trait LuaAPI {
     fn to_integerx(&self, idx: isize) -> (i64, bool);
     fn to_string(&self, idx: isize) -> String;
     fn to_stringx(&self, idx: isize) -> (String, bool);
+    fn to_userdata(&self, idx: isize) -> *mut c_void;
+    fn to_pointer(&self, idx: isize) -> *const c_void;
+    fn raw_len(&self, idx: isize) -> usize;
+    fn raw_equal(&self, idx1: isize, idx2: isize) -> bool;
+    fn string_to_number(&mut self, s: &str) -> usize;
     // table function
     fn create_table(&mut self, n_arr: usize, n_rec: usize);
     fn new_table(&mut self);
-    fn get_table(&mut self, idx: isize) -> i8;
-    fn _get_table(&mut self, t: LuaValue, k: LuaValue) -> i8;
-    fn get_field(&mut self, idx: isize, k: String) -> i8;
-    fn get_i(&mut self, idx: isize, i: i64) -> i8;
-    fn _set_table(&mut self, t: &LuaValue, k: LuaValue, v: LuaValue);
-    fn set_table(&mut self, idx: isize);
-    fn set_field(&mut self, idx: isize, k: String);
-    fn set_i(&mut self, idx: isize, i: i64);
+    fn get_table(&mut self, idx: isize) -> LuaResult<i8>;
+    fn _get_table(&mut self, t: LuaValue, k: LuaValue, raw: bool) -> LuaResult<i8>;
+    fn get_field(&mut self, idx: isize, k: String) -> LuaResult<i8>;
+    fn get_i(&mut self, idx: isize, i: i64) -> LuaResult<i8>;
+    fn _set_table(&mut self, t: &LuaValue, k: LuaValue, v: LuaValue, raw: bool) -> LuaResult<()>;
+    fn _finish_set_table(&mut self, t: LuaValue, k: LuaValue, v: LuaValue) -> LuaResult<()>;
+    fn set_table(&mut self, idx: isize) -> LuaResult<()>;
+    fn set_field(&mut self, idx: isize, k: String) -> LuaResult<()>;
+    fn set_i(&mut self, idx: isize, i: i64) -> LuaResult<()>;
+    fn next(&mut self, idx: isize) -> LuaResult<bool>;
+    fn raw_get(&mut self, idx: isize) -> LuaResult<i8>;
+    fn raw_get_i(&mut self, idx: isize, i: i64) -> LuaResult<i8>;
+    fn _raw_get(&mut self, t: LuaValue, k: LuaValue) -> LuaResult<i8>;
+    fn raw_set(&mut self, idx: isize) -> LuaResult<()>;
+    fn raw_set_i(&mut self, idx: isize, i: i64) -> LuaResult<()>;
+    // metatable
+    fn get_metatable(&mut self, idx: isize) -> bool;
+    fn set_metatable(&mut self, idx: isize) -> LuaResult<()>;
+    // function call
+    fn load(&mut self, chunk: Vec<u8>) -> u8;
+    fn call(&mut self, n_args: usize, n_results: isize) -> LuaResult<()>;
+    fn pcall(&mut self, n_args: usize, n_results: isize, msgh: isize) -> u8;
+    fn pcallk(&mut self, n_args: usize, n_results: isize, msgh: isize, ctx: isize, k: KFunction) -> LuaResult<u8>;
+    fn error(&mut self) -> LuaError;
+    fn push_rust_function(&mut self, f: RustFn);
+    fn push_rust_closure(&mut self, f: RustFn, n: usize) -> LuaResult<()>;
+    fn is_rust_function(&self, idx: isize) -> bool;
+    // userdata
+    fn new_userdata<T: 'static>(&mut self, data: T);
+    fn new_userdata_uv<T: 'static>(&mut self, data: T, n_uvalue: usize);
+    fn _get_userdata(&self, idx: isize) -> Option<&RefCell<Userdata>>;
+    fn borrow_userdata<T: 'static>(&self, idx: isize) -> Option<Ref<'_, T>>;
+    fn borrow_userdata_mut<T: 'static>(&self, idx: isize) -> Option<RefMut<'_, T>>;
+    fn check_userdata<T: 'static>(&self, idx: isize) -> LuaResult<Ref<'_, T>>;
+    fn check_userdata_mut<T: 'static>(&self, idx: isize) -> LuaResult<RefMut<'_, T>>;
+    fn _userdata_busy(&self, idx: isize, mutable: bool) -> bool;
+    fn get_i_user_value(&mut self, idx: isize, n: usize) -> i8;
+    fn set_i_user_value(&mut self, idx: isize, n: usize) -> LuaResult<bool>;
+    // coroutine
+    fn new_thread(&mut self);
+    fn resume(&mut self, n_args: usize) -> u8;
+    fn r#yield(&mut self, n_results: usize) -> LuaError;
+    fn is_yieldable(&self) -> bool;
+    fn push_thread(&mut self) -> bool;
+    // unlike lua_xmove, `from` and `to` are stack indices of the two
+    // threads in this state, not the threads themselves
+    fn xmove(&mut self, from: isize, to: isize, n: usize) -> LuaResult<()>;
+    // global
+    fn push_global_table(&mut self);
+    fn get_global(&mut self, name: String) -> LuaResult<i8>;
+    fn set_global(&mut self, name: String) -> LuaResult<()>;
+    fn register(&mut self, name: String, f: RustFn);
+    // garbage collection
+    fn gc(&mut self, what: u8, data: i64) -> LuaResult<i64>;
 }
 
 impl LuaAPI for LuaState {
-    fn compare(&mut self, idx1: isize, idx2: isize, op: u8) -> bool {
-        let a = self.stack.get(idx1);
-        let b = self.stack.get(idx2);
-        match op {
-            LUA_OPEQ => api_cmp::_eq(&a, &b),
-            LUA_OPLT => {
-                if let Some(result) = api_cmp::_lt(&a, &b) {
-                    return result;
-                }
-                panic!("invalid cmp op!");
-            },
-            LUA_OPLE => {
-                if let Some(result) = api_cmp::_le(&a, &b) {
-                    return result;
-                }
-                panic!("invalid cmp op!");
-
-            },
-            _ => panic!("invalid cmp op!")
-        }
+    fn compare(&mut self, idx1: isize, idx2: isize, op: u8) -> LuaResult<bool> {
+        let a = self.get_value(idx1);
+        let b = self.get_value(idx2);
+        self.compare_values(a, b, op)
     }
 
-    fn len(&mut self, idx: isize) {
-        let tmp = self.stack.get(idx);
-        let result = match tmp {
-            LuaValue::LuaString(s) => s.len(),
-            LuaValue::Table(t) => t.borrow().len(),
-            _ => panic!("length error!")
-        };
-        self.stack.push(LuaValue::Int64(result as i64));
+    fn len(&mut self, idx: isize) -> LuaResult<()> {
+        let val = self.get_value(idx);
+        let result = self.len_value(val)?;
+        self.stack.push(result);
+        Ok(())
     }
 
-    fn concat(&mut self, n: isize) {
+    // like luaV_concat: works from the top, joining each run of strings
+    // and numbers with one allocation and the rest pairwise with __concat
+    fn concat(&mut self, n: isize) -> LuaResult<()> {
         if n == 0 {
-            self.stack.push(LuaValue::LuaString(String::new()));
-        } else {
-            for i in 1..n {
-                if self.is_string(-1) && self.is_string(-2) {
-                    let s2 = self.to_string(-1);
-                    let mut s1 = self.to_string(-2);
-                    self.stack.pop();
-                    self.stack.pop();
-                    s1.push_str(s2.as_str());
-                    self.stack.push(LuaValue::LuaString(s1.clone()));
+            let s = LuaValue::new_string(&mut self.heap, "");
+            self.stack.push(s);
+            return Ok(());
+        }
+        let mut total = n as usize;
+        while total > 1 {
+            let (a, b) = (self.stack.get(-2), self.stack.get(-1));
+            if !is_concatable(&a) || !is_concatable(&b) {
+                self.stack.pop_n(2)?;
+                if let Some(result) = self.call_metamethod(&a, &b, "__concat")? {
+                    self.stack.push(result);
+                    total -= 1;
                     continue;
                 }
-                panic!("concatenation error!");
+                let culprit = if is_concatable(&a) { b } else { a };
+                return Err(self.type_error(&culprit, "concatenate"));
             }
 
+            // as many values as possible below the top two
+            let mut k = 2;
+            while k < total && is_concatable(&self.stack.get(-(k as isize) - 1)) {
+                k += 1;
+            }
+            let vals = self.stack.pop_n(k)?;
+            let parts: Vec<_> = vals.iter().map(|v| v.to_stringx().0).collect();
+            let len = parts.iter().map(|p| p.len()).sum();
+            let mut s = String::new();
+            if s.try_reserve_exact(len).is_err() {
+                return Err(LuaError::memory());
+            }
+            for p in parts.iter() {
+                s.push_str(p);
+            }
+            let s = LuaValue::LuaString(TString::from_string(&mut self.heap, s));
+            self.stack.push(s);
+            total -= k - 1;
         }
+        Ok(())
     }
 
-    fn arith(&mut self, op: u8) {
-        let mut a: LuaValue;
-        let b = self.stack.pop();
-        if op != LUA_OPUNM && op != LUA_OPBNOT {
-            a = self.stack.pop();
-        } else {
-            a = b.clone();
-        }
-        let result = api_arith::_arith(&a, &b, op);
+    fn arith(&mut self, op: u8) -> LuaResult<()> {
+        // unary operators get their operand twice
+        let n = if op == LUA_OPUNM || op == LUA_OPBNOT { 1 } else { 2 };
+        let vals = self.stack.pop_n(n)?;
+        let (a, b) = (vals[0], vals[n - 1]);
+        let result = self.arith_values(a, b, op)?;
         self.stack.push(result);
+        Ok(())
     }
 
     fn get_top(&self) -> isize {
@@ -131,64 +204,67 @@ impl LuaAPI for LuaState {
     }
 
     fn check_stack(&mut self, size: usize) -> bool {
-        self.stack.check(size);
-        true
+        self.stack.check(size)
     }
 
-    fn pop(&mut self, size: usize) {
-        for _ in 0..size {
-            self.stack.pop();
-        }
+    fn pop(&mut self, size: usize) -> LuaResult<()> {
+        self.stack.pop_n(size)?;
+        Ok(())
     }
 
-    fn copy(&mut self, from: isize, to: isize) {
-        let tmp = self.stack.get(from);
-        self.stack.set(to, tmp);
+    fn copy(&mut self, from: isize, to: isize) -> LuaResult<()> {
+        let tmp = self.get_value(from);
+        self.set_value(to, tmp)
     }
 
     fn push_value(&mut self, idx: isize) {
-        let tmp = self.stack.get(idx);
+        let tmp = self.get_value(idx);
         self.stack.push(tmp);
     }
 
-    fn replace(&mut self, idx: isize) {
-        let tmp = self.stack.pop();
-        self.stack.set(idx, tmp);
+    fn replace(&mut self, idx: isize) -> LuaResult<()> {
+        self.copy(-1, idx)?;
+        self.pop(1)
     }
 
-    fn insert(&mut self, idx: isize) {
-        self.rotate(idx, 1);
+    fn insert(&mut self, idx: isize) -> LuaResult<()> {
+        self.rotate(idx, 1)
     }
 
-    fn remove(&mut self, idx: isize) {
-        self.rotate(idx, -1);
-        self.pop(1);
+    fn remove(&mut self, idx: isize) -> LuaResult<()> {
+        self.rotate(idx, -1)?;
+        self.pop(1)
     }
 
-    fn rotate(&mut self, idx: isize, n: isize) {
+    // idx must be a stack slot and n at most the number of values from it to the top
+    fn rotate(&mut self, idx: isize, n: isize) -> LuaResult<()> {
         let t = self.stack.top() - 1;
         let p = self.stack.abs_index(idx) - 1;
+        if idx <= LUA_REGISTRYINDEX || p < 0 || p > t || n.abs() > t - p + 1 {
+            return Err(self.runtime_error("invalid index"));
+        }
         let m = if n >= 0 {t - n} else {p - n - 1};
-        self.stack.reverse(p as usize, m as usize);
-        self.stack.reverse((m+1) as usize, t as usize);
-        self.stack.reverse(p as usize, t as usize);
+        self.stack.reverse(p, m);
+        self.stack.reverse(m + 1, t);
+        self.stack.reverse(p, t);
+        Ok(())
     }
 
-    fn set_top(&mut self, idx: isize) {
+    // a negative idx must not go below the function's first slot
+    fn set_top(&mut self, idx: isize) -> LuaResult<()> {
         let new_top = self.stack.abs_index(idx);
         if new_top < 0 {
-            panic!("stack underflow!");
+            return Err(self.runtime_error("stack underflow"));
         }
         let n = self.stack.top() - new_top;
         if n > 0 {
-            for _ in 0..n {
-                self.stack.pop();
-            }
+            self.stack.truncate(new_top as usize);
         } else if n < 0 {
             for _ in n..0 {
                 self.stack.push(LuaValue::Nil);
             }
         }
+        Ok(())
     }
 
     fn push_nil(&mut self) {
@@ -199,6 +275,10 @@ impl LuaAPI for LuaState {
         self.stack.push(LuaValue::Bool(b));
     }
 
+    fn push_light_userdata(&mut self, p: *mut c_void) {
+        self.stack.push(LuaValue::LightUserData(p));
+    }
+
     fn push_integer(&mut self, n: i64) {
         self.stack.push(LuaValue::Int64(n));
     }
@@ -208,7 +288,8 @@ impl LuaAPI for LuaState {
     }
 
     fn push_string(&mut self, s: String) {
-        self.stack.push(LuaValue::LuaString(s));
+        let s = LuaValue::LuaString(TString::from_string(&mut self.heap, s));
+        self.stack.push(s);
     }
 
     fn type_name(&self, cur_type: i8) -> &str {
@@ -221,15 +302,15 @@ impl LuaAPI for LuaState {
             LUA_TTABLE => "table",
             LUA_TFUNCTION => "function",
             LUA_TTHREAD => "thread",
-            _ => "user data"
+            _ => "userdata"
         }
     }
 
     fn type_id(&self, idx: isize) -> i8 {
-        if self.stack.is_valid(idx) {
-            return self.stack.get(idx).get_type();
+        if self.is_valid_index(idx) {
+            return self.get_value(idx).get_type();
         }
-        return LUA_TNONE;
+        LUA_TNONE
     }
 
     fn is_none(&self, idx: isize) -> bool {
@@ -264,8 +345,30 @@ impl LuaAPI for LuaState {
         result
     }
 
+    fn is_function(&self, idx: isize) -> bool {
+        self.type_id(idx) == LUA_TFUNCTION
+    }
+
+    fn is_table(&self, idx: isize) -> bool {
+        self.type_id(idx) == LUA_TTABLE
+    }
+
+    fn is_light_userdata(&self, idx: isize) -> bool {
+        self.type_id(idx) == LUA_TLIGHTUSERDATA
+    }
+
+    // full or light
+    fn is_userdata(&self, idx: isize) -> bool {
+        let cur_type_id = self.type_id(idx);
+        cur_type_id == LUA_TUSERDATA || cur_type_id == LUA_TLIGHTUSERDATA
+    }
+
+    fn is_thread(&self, idx: isize) -> bool {
+        self.type_id(idx) == LUA_TTHREAD
+    }
+
     fn to_boolean(&self, idx: isize) -> bool {
-        let val = self.stack.get(idx);
+        let val = self.get_value(idx);
         val.to_bool()
     }
 
@@ -275,7 +378,7 @@ impl LuaAPI for LuaState {
     }
 
     fn to_numberx(&self, idx: isize) -> (f64, bool) {
-        let val = self.stack.get(idx);
+        let val = self.get_value(idx);
         val.to_numberx()
     }
 
@@ -285,7 +388,7 @@ impl LuaAPI for LuaState {
     }
 
     fn to_integerx(&self, idx: isize) -> (i64, bool) {
-        let val = self.stack.get(idx);
+        let val = self.get_value(idx);
         val.to_integerx()
     }
 
@@ -295,67 +398,846 @@ impl LuaAPI for LuaState {
     }
 
     fn to_stringx(&self, idx: isize) -> (String, bool) {
-        let val = self.stack.get(idx);
-        val.to_stringx()
+        let val = self.get_value(idx);
+        let (s, ok) = val.to_stringx();
+        (s.into_owned(), ok)
+    }
+
+    // the pointer of a light userdata, or the address of the Rust value of a
+    // full one; null for other values
+    fn to_userdata(&self, idx: isize) -> *mut c_void {
+        match self.get_value(idx) {
+            LuaValue::LightUserData(p) => p,
+            LuaValue::UserData(u) => {
+                let data: *const dyn std::any::Any = &*u.borrow().data;
+                data as *mut c_void
+            },
+            _ => ptr::null_mut(),
+        }
+    }
+
+    // only for hashing and debug information; null for other values
+    fn to_pointer(&self, idx: isize) -> *const c_void {
+        self.get_value(idx).to_pointer() as *const c_void
+    }
+
+    // the length without __len: the size of the Rust value for full
+    // userdata (0 while it is borrowed mutably), 0 for other values
+    fn raw_len(&self, idx: isize) -> usize {
+        match self.get_value(idx) {
+            LuaValue::LuaString(s) => s.len(),
+            LuaValue::Table(t) => t.borrow().len(),
+            LuaValue::UserData(u) => u.try_borrow().map_or(0, |u| mem::size_of_val(&*u.data)),
+            _ => 0,
+        }
+    }
+
+    // false if either index is not valid
+    fn raw_equal(&self, idx1: isize, idx2: isize) -> bool {
+        if !self.is_valid_index(idx1) || !self.is_valid_index(idx2) {
+            return false;
+        }
+        api_cmp::_eq(&self.get_value(idx1), &self.get_value(idx2))
+    }
+
+    // pushes the number and returns the length plus one, or returns 0
+    // without pushing if `s` is not a numeral
+    fn string_to_number(&mut self, s: &str) -> usize {
+        match str_to_number(s) {
+            Some(n) => {
+                self.stack.push(n);
+                s.len() + 1
+            },
+            None => 0,
+        }
     }
 
     fn create_table(&mut self, n_arr: usize, n_rec: usize) {
-        self.stack.push(LuaValue::new_table(n_arr, n_rec));
+        self.stack.push(LuaValue::new_table(&mut self.heap, n_arr, n_rec));
     }
 
     fn new_table(&mut self) {
         self.create_table(0, 0);
     }
 
-    fn get_table(&mut self, idx: isize) -> i8 {
-        let t = self.stack.get(idx);
-        let k = self.stack.pop();
-        self._get_table(t, k)
+    fn get_table(&mut self, idx: isize) -> LuaResult<i8> {
+        let t = self.get_value(idx);
+        let k = self.stack.pop()?;
+        self._get_table(t, k, false)
     }
 
-    fn _get_table(&mut self, t: LuaValue, k: LuaValue) -> i8 {
+    fn _get_table(&mut self, t: LuaValue, k: LuaValue, raw: bool) -> LuaResult<i8> {
+        let v = if raw {
+            match t {
+                LuaValue::Table(tbl) => tbl.borrow().get(&k),
+                _ => return Err(self.type_error(&t, "index")),
+            }
+        } else {
+            self.index(t, k)?
+        };
+        let type_id = v.get_type();
+        self.stack.push(v);
+        Ok(type_id)
+    }
+
+    fn get_field(&mut self, idx: isize, k: String) -> LuaResult<i8> {
+        let t = self.get_value(idx);
+        let k = LuaValue::LuaString(TString::from_string(&mut self.heap, k));
+        self._get_table(t, k, false)
+    }
+
+    fn get_i(&mut self, idx: isize, i: i64) -> LuaResult<i8> {
+        let t = self.get_value(idx);
+        self._get_table(t, LuaValue::Int64(i), false)
+    }
+
+    fn _set_table(&mut self, t: &LuaValue, k: LuaValue, v: LuaValue, raw: bool) -> LuaResult<()> {
         if let LuaValue::Table(tbl) = t {
-            let v = tbl.borrow().get(&k);
-            self.stack.push(v.clone());
-            return v.get_type();
+            if raw || plain_store(&tbl.borrow(), &k) {
+                let result = tbl.borrow_mut().put(k, v);
+                return result.map_err(|msg| self.runtime_error(msg));
+            }
+        }
+        if raw {
+            return Err(self.type_error(t, "index"));
+        }
+        self._finish_set_table(*t, k, v)
+    }
+
+    // t[k] = v once t is known to lack the key, starting from its
+    // __newindex (see luaV_finishset)
+    fn _finish_set_table(&mut self, mut t: LuaValue, k: LuaValue, v: LuaValue) -> LuaResult<()> {
+        for _ in 0..MAXTAGLOOP {
+            let mf = self.get_metafield(&t, "__newindex");
+            match mf {
+                LuaValue::Table(_) => t = mf,
+                LuaValue::Function(_) => {
+                    self.stack.push(mf);
+                    self.stack.push(t);
+                    self.stack.push(k);
+                    self.stack.push(v);
+                    return self.call_tm(3, 0);
+                },
+                _ => return Err(self.type_error(&t, "index")),
+            }
+
+            if let LuaValue::Table(tbl) = &t {
+                if plain_store(&tbl.borrow(), &k) {
+                    let result = tbl.borrow_mut().put(k, v);
+                    return result.map_err(|msg| self.runtime_error(msg));
+                }
+            }
+        }
+        Err(self.runtime_error("'__newindex' chain too long; possible loop"))
+    }
+
+    fn set_table(&mut self, idx: isize) -> LuaResult<()> {
+        let t = self.get_value(idx);
+        let kv = self.stack.pop_n(2)?;
+        let (k, v) = (kv[0], kv[1]);
+        self._set_table(&t, k, v, false)
+    }
+
+    fn set_field(&mut self, idx: isize, k: String) -> LuaResult<()> {
+        let t = self.get_value(idx);
+        let v = self.stack.pop()?;
+        let k = LuaValue::LuaString(TString::from_string(&mut self.heap, k));
+        self._set_table(&t, k, v, false)
+    }
+
+    fn set_i(&mut self, idx: isize, i: i64) -> LuaResult<()> {
+        let t = self.get_value(idx);
+        let v = self.stack.pop()?;
+        self._set_table(&t, LuaValue::Int64(i), v, false)
+    }
+
+    fn raw_get(&mut self, idx: isize) -> LuaResult<i8> {
+        let t = self.get_value(idx);
+        let k = self.stack.pop()?;
+        self._raw_get(t, k)
+    }
+
+    fn raw_get_i(&mut self, idx: isize, i: i64) -> LuaResult<i8> {
+        let t = self.get_value(idx);
+        self._raw_get(t, LuaValue::Int64(i))
+    }
+
+    // no metamethods; t must be a table
+    fn _raw_get(&mut self, t: LuaValue, k: LuaValue) -> LuaResult<i8> {
+        let v = match t {
+            LuaValue::Table(tbl) => tbl.borrow().get(&k),
+            _ => return Err(self.runtime_error("table expected")),
+        };
+        self.stack.push(v);
+        Ok(v.get_type())
+    }
+
+    // the value at idx must be a table
+    fn raw_set(&mut self, idx: isize) -> LuaResult<()> {
+        let t = self.get_value(idx);
+        let kv = self.stack.pop_n(2)?;
+        let (k, v) = (kv[0], kv[1]);
+        if !matches!(t, LuaValue::Table(_)) {
+            return Err(self.runtime_error("table expected"));
+        }
+        self._set_table(&t, k, v, true)
+    }
+
+    // the value at idx must be a table; integer keys are always valid
+    fn raw_set_i(&mut self, idx: isize, i: i64) -> LuaResult<()> {
+        let t = self.get_value(idx);
+        let v = self.stack.pop()?;
+        match t {
+            LuaValue::Table(tbl) => {
+                tbl.borrow_mut().put(LuaValue::Int64(i), v).unwrap();
+                Ok(())
+            },
+            _ => Err(self.runtime_error("table expected")),
         }
-        panic!("not a table!");
     }
 
-    fn get_field(&mut self, idx: isize, k: String) -> i8 {
-        let t = self.stack.get(idx);
-        self._get_table(t, LuaValue::LuaString(k))
+    fn get_metatable(&mut self, idx: isize) -> bool {
+        let val = self.get_value(idx);
+        if let Some(mt) = self._get_metatable(&val) {
+            self.stack.push(LuaValue::Table(mt));
+            return true;
+        }
+        false
+    }
+
+    // the metatable on top must be a table or nil
+    fn set_metatable(&mut self, idx: isize) -> LuaResult<()> {
+        let val = self.get_value(idx);
+        let mt = match self.stack.pop()? {
+            LuaValue::Table(mt) => Some(mt),
+            mt => {
+                debug_assert!(mt.is_nil(), "table expected");
+                None
+            },
+        };
+        self._set_metatable(&val, mt);
+        Ok(())
+    }
+
+    // the value at idx must be a table
+    fn next(&mut self, idx: isize) -> LuaResult<bool> {
+        let t = self.get_value(idx);
+        let k = self.stack.pop()?;
+        let entry = match t {
+            LuaValue::Table(tbl) => tbl.borrow().next(&k),
+            _ => Err("table expected"),
+        };
+        match entry {
+            Ok(Some((next_k, next_v))) => {
+                self.stack.push(next_k);
+                self.stack.push(next_v);
+                Ok(true)
+            },
+            Ok(None) => Ok(false),
+            Err(msg) => Err(self.runtime_error(msg)),
+        }
+    }
+
+    fn load(&mut self, chunk: Vec<u8>) -> u8 {
+        let loaded = binary_chunk::undump(chunk).and_then(|mut proto| {
+            proto.load(&mut self.heap)?;
+            Ok(proto)
+        });
+        let proto = match loaded {
+            Ok(proto) => proto,
+            Err(why) => {
+                self.push_string(format!("bad binary format ({})", why));
+                return LUA_ERRSYNTAX;
+            },
+        };
+        let c = Closure::new_lua_closure(&mut self.heap, Rc::new(proto));
+        if !c.upvals.is_empty() {
+            // _ENV
+            let env = self.registry_get(LUA_RIDX_GLOBALS);
+            *c.upvals[0].borrow_mut() = env;
+        }
+        self.stack.push(LuaValue::Function(self.heap.alloc(c)));
+        LUA_OK
     }
 
-    fn get_i(&mut self, idx: isize, i: i64) -> i8 {
-        let t = self.stack.get(idx);
-        self._get_table(t, LuaValue::Int64(i))
+    fn call(&mut self, n_args: usize, n_results: isize) -> LuaResult<()> {
+        // a Rust caller cannot be suspended, so no yields until it gets control back
+        self.nny += 1;
+        let result = self.call_yieldable(n_args, n_results);
+        self.nny -= 1;
+        result
+    }
+
+    fn pcall(&mut self, n_args: usize, n_results: isize, msgh: isize) -> u8 {
+        let depth = self.frames.len();
+        let old_top = self.stack.top() - n_args as isize - 1;
+        let handler = if msgh == 0 { LuaValue::Nil } else { self.get_value(msgh) };
+
+        let err = match self.call(n_args, n_results) {
+            Ok(()) => return LUA_OK,
+            Err(err) => err,
+        };
+        // the failed frames are still in place for the message handler
+        let err = self.handle_error(err, handler);
+        self.unwind(depth);
+        self.stack.truncate(old_top as usize);
+        let status = err.status;
+        let val = err.into_value(&mut self.heap);
+        self.stack.push(val);
+        status
     }
 
-    fn _set_table(&mut self, t: &LuaValue, k: LuaValue, v: LuaValue) {
+    // like pcall, but where yields are allowed a yield inside leaves the
+    // running Rust function to `k`, which gets LUA_YIELD and `ctx` once the
+    // call comes back, or the error status if it fails after the resume.
+    // Err is only that yield and must be passed up to the resume.
+    fn pcallk(&mut self, n_args: usize, n_results: isize, msgh: isize, ctx: isize, k: KFunction) -> LuaResult<u8> {
+        if self.nny > 0 {
+            return Ok(self.pcall(n_args, n_results, msgh));
+        }
+        let depth = self.frames.len();
+        let old_top = self.stack.top() - n_args as isize - 1;
+        let msgh = if msgh == 0 { 0 } else { self.abs_index(msgh) };
+        let handler = if msgh == 0 { LuaValue::Nil } else { self.get_value(msgh) };
+        self.stack.k = Some(k);
+        self.stack.ctx = ctx;
+        self.stack.ypcall = Some((old_top, msgh));
+
+        let err = match self.call_yieldable(n_args, n_results) {
+            Ok(()) => None,
+            Err(err) if err.status == LUA_YIELD => return Err(err),
+            // the failed frames are still in place for the message handler
+            Err(err) => Some(self.handle_error(err, handler)),
+        };
+        self.unwind(depth);
+        self.stack.k = None;
+        self.stack.ypcall = None;
+        let err = match err {
+            Some(err) => err,
+            None => return Ok(LUA_OK),
+        };
+        self.stack.truncate(old_top as usize);
+        let status = err.status;
+        let val = err.into_value(&mut self.heap);
+        self.stack.push(val);
+        Ok(status)
+    }
+
+    fn error(&mut self) -> LuaError {
+        match self.stack.pop() {
+            Ok(err) => LuaError::new(LUA_ERRRUN, err),
+            Err(err) => err,
+        }
+    }
+
+    fn push_rust_function(&mut self, f: RustFn) {
+        let c = Closure::new_rust_closure(&mut self.heap, f, 0);
+        self.stack.push(LuaValue::Function(self.heap.alloc(c)));
+    }
+
+    // the closure gets the `n` values on top of the stack as upvalues
+    fn push_rust_closure(&mut self, f: RustFn, n: usize) -> LuaResult<()> {
+        let upvals = self.stack.pop_n(n)?;
+        let c = Closure::new_rust_closure(&mut self.heap, f, n);
+        for (i, val) in upvals.into_iter().enumerate() {
+            *c.upvals[i].borrow_mut() = val;
+        }
+        self.stack.push(LuaValue::Function(self.heap.alloc(c)));
+        Ok(())
+    }
+
+    fn is_rust_function(&self, idx: isize) -> bool {
+        if let LuaValue::Function(c) = self.get_value(idx) {
+            return c.rust_fn.is_some();
+        }
+        false
+    }
+
+    fn new_userdata<T: 'static>(&mut self, data: T) {
+        self.new_userdata_uv(data, 1);
+    }
+
+    // the value must not hold Lua values, the collector cannot see them;
+    // keep those in the `n_uvalue` user values instead
+    fn new_userdata_uv<T: 'static>(&mut self, data: T, n_uvalue: usize) {
+        let u = Userdata::new(Box::new(data), n_uvalue);
+        self.stack.push(LuaValue::UserData(self.heap.alloc(RefCell::new(u))));
+    }
+
+    fn _get_userdata(&self, idx: isize) -> Option<&RefCell<Userdata>> {
+        match self.get_value(idx) {
+            // SAFETY: the slot keeps it alive while self is borrowed
+            LuaValue::UserData(u) => Some(unsafe { u.as_ref_unchecked() }),
+            _ => None,
+        }
+    }
+
+    // borrow the Rust value of the userdata at `idx` if it is a T
+    fn borrow_userdata<T: 'static>(&self, idx: isize) -> Option<Ref<'_, T>> {
+        let cell = self._get_userdata(idx)?;
+        Ref::filter_map(cell.try_borrow().ok()?, |u| u.data.downcast_ref::<T>()).ok()
+    }
+
+    fn borrow_userdata_mut<T: 'static>(&self, idx: isize) -> Option<RefMut<'_, T>> {
+        let cell = self._get_userdata(idx)?;
+        RefMut::filter_map(cell.try_borrow_mut().ok()?, |u| u.data.downcast_mut::<T>()).ok()
+    }
+
+    // a value borrowed elsewhere is reported apart from a wrong type
+    fn check_userdata<T: 'static>(&self, idx: isize) -> LuaResult<Ref<'_, T>> {
+        match self.borrow_userdata(idx) {
+            Some(r) => Ok(r),
+            None if self._userdata_busy(idx, false) => Err(self.borrow_error::<T>(idx)),
+            None => Err(self.userdata_error::<T>(idx)),
+        }
+    }
+
+    fn check_userdata_mut<T: 'static>(&self, idx: isize) -> LuaResult<RefMut<'_, T>> {
+        match self.borrow_userdata_mut(idx) {
+            Some(r) => Ok(r),
+            None if self._userdata_busy(idx, true) => Err(self.borrow_error::<T>(idx)),
+            None => Err(self.userdata_error::<T>(idx)),
+        }
+    }
+
+    // whether borrowing the userdata at `idx` would conflict with a live borrow
+    fn _userdata_busy(&self, idx: isize, mutable: bool) -> bool {
+        match self._get_userdata(idx) {
+            Some(cell) if mutable => cell.try_borrow_mut().is_err(),
+            Some(cell) => cell.try_borrow().is_err(),
+            None => false,
+        }
+    }
+
+    // push the `n`th user value (1-based), LUA_TNONE if there is none
+    fn get_i_user_value(&mut self, idx: isize, n: usize) -> i8 {
+        let val = match self._get_userdata(idx) {
+            Some(cell) if n >= 1 => cell.borrow().user_values.get(n - 1).cloned(),
+            _ => None,
+        };
+        match val {
+            Some(val) => {
+                let t = val.get_type();
+                self.stack.push(val);
+                t
+            },
+            None => {
+                self.stack.push(LuaValue::Nil);
+                LUA_TNONE
+            },
+        }
+    }
+
+    // pop a value into the `n`th user value, false if there is no such slot
+    fn set_i_user_value(&mut self, idx: isize, n: usize) -> LuaResult<bool> {
+        let ud = self.get_value(idx);
+        let val = self.stack.pop()?;
+        if let LuaValue::UserData(u) = ud {
+            let mut u = u.borrow_mut();
+            if n >= 1 && n <= u.user_values.len() {
+                u.user_values[n - 1] = val;
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn new_thread(&mut self) {
+        let t = LuaThread::new();
+        self.stack.push(LuaValue::Thread(self.heap.alloc(RefCell::new(t))));
+    }
+
+    // resume the coroutine below the top `n_args` values, leaving what it
+    // yielded or returned, or its error value, in their place
+    fn resume(&mut self, n_args: usize) -> u8 {
+        let args = match self.stack.pop_n(n_args) {
+            Ok(args) => args,
+            Err(err) => {
+                let val = err.into_value(&mut self.heap);
+                self.stack.push(val);
+                return LUA_ERRRUN;
+            },
+        };
+        // the coroutine stays on the stack while it runs so the collector
+        // can reach this thread through it
+        let co = match self.stack.get(-1) {
+            LuaValue::Thread(co) => co,
+            _ => {
+                let _ = self.stack.pop(); // whatever is there, if anything
+                self.push_string(String::from("thread expected"));
+                return LUA_ERRRUN;
+            },
+        };
+        let msg = {
+            let t = co.borrow();
+            if Gc::ptr_eq(&co, &self.thread) || (t.status == LUA_OK && !t.frames.is_empty()) {
+                Some("cannot resume non-suspended coroutine")
+            } else if (t.status == LUA_OK && t.stack.top() == 0) || (t.status != LUA_OK && t.status != LUA_YIELD) {
+                Some("cannot resume dead coroutine")
+            } else if self.n_ccalls >= LUAI_MAXCCALLS {
+                Some("C stack overflow")
+            } else {
+                None
+            }
+        };
+        if let Some(msg) = msg {
+            let _ = self.stack.pop();
+            self.push_string(String::from(msg));
+            return LUA_ERRRUN;
+        }
+
+        let (status, results) = self.resume_thread(co, args);
+        let _ = self.stack.pop();
+        self.stack.check(results.len());
+        self.stack.push_n(results, -1);
+        status
+    }
+
+    // keep the top `n_results` values as the ones to yield; the returned
+    // error must be passed up to the resume
+    fn r#yield(&mut self, n_results: usize) -> LuaError {
+        if self.nny > 0 {
+            let is_main = self.registry_get(LUA_RIDX_MAINTHREAD) == LuaValue::Thread(self.thread);
+            if is_main {
+                return self.runtime_error("attempt to yield from outside a coroutine");
+            }
+            return self.runtime_error("attempt to yield across a C-call boundary");
+        }
+        let results = match self.stack.pop_n(n_results) {
+            Ok(results) => results,
+            Err(err) => return err,
+        };
+        self.stack.truncate(0);
+        self.stack.push_n(results, -1);
+        LuaError::new(LUA_YIELD, LuaValue::Nil)
+    }
+
+    fn is_yieldable(&self) -> bool {
+        self.nny == 0
+    }
+
+    // push the running thread, returns whether it is the main thread
+    fn push_thread(&mut self) -> bool {
+        let t = LuaValue::Thread(self.thread);
+        let is_main = self.registry_get(LUA_RIDX_MAINTHREAD) == t;
+        self.stack.push(t);
+        is_main
+    }
+
+    // pops n values from the thread at `from` and pushes them onto the
+    // thread at `to`; either may be the running one (see lua_xmove)
+    fn xmove(&mut self, from: isize, to: isize, n: usize) -> LuaResult<()> {
+        let (from, to) = match (self.get_value(from), self.get_value(to)) {
+            (LuaValue::Thread(from), LuaValue::Thread(to)) => (from, to),
+            _ => return Err(self.runtime_error("thread expected")),
+        };
+        if Gc::ptr_eq(&from, &to) {
+            return Ok(());
+        }
+        // the running thread's values are in self.stack
+        let running = self.thread;
+        let available = if Gc::ptr_eq(&from, &running) {
+            self.stack.top()
+        } else {
+            from.borrow().stack.top()
+        };
+        if available < n as isize {
+            return Err(self.runtime_error("not enough elements to move"));
+        }
+        let fits = if Gc::ptr_eq(&to, &running) {
+            self.stack.check(n)
+        } else {
+            to.borrow_mut().stack.check(n)
+        };
+        if !fits {
+            return Err(self.runtime_error("stack overflow"));
+        }
+        let vals = if Gc::ptr_eq(&from, &running) {
+            self.stack.pop_n(n)?
+        } else {
+            from.borrow_mut().stack.pop_n(n)?
+        };
+        if Gc::ptr_eq(&to, &running) {
+            self.stack.push_n(vals, -1);
+        } else {
+            to.borrow_mut().stack.push_n(vals, -1);
+        }
+        Ok(())
+    }
+
+    fn push_global_table(&mut self) {
+        let env = self.registry_get(LUA_RIDX_GLOBALS);
+        self.stack.push(env);
+    }
+
+    fn get_global(&mut self, name: String) -> LuaResult<i8> {
+        let t = self.registry_get(LUA_RIDX_GLOBALS);
+        let k = LuaValue::LuaString(TString::from_string(&mut self.heap, name));
+        self._get_table(t, k, false)
+    }
+
+    fn set_global(&mut self, name: String) -> LuaResult<()> {
+        let t = self.registry_get(LUA_RIDX_GLOBALS);
+        let v = self.stack.pop()?;
+        let k = LuaValue::LuaString(TString::from_string(&mut self.heap, name));
+        self._set_table(&t, k, v, false)
+    }
+
+    fn register(&mut self, name: String, f: RustFn) {
+        let t = self.registry_get(LUA_RIDX_GLOBALS);
+        let c = Closure::new_rust_closure(&mut self.heap, f, 0);
         if let LuaValue::Table(tbl) = t {
-            tbl.borrow_mut().put(k, v);
-            return
+            let k = LuaValue::LuaString(TString::from_string(&mut self.heap, name));
+            let _ = tbl.borrow_mut().put(k, LuaValue::Function(self.heap.alloc(c)));
+        }
+    }
+
+    fn gc(&mut self, what: u8, data: i64) -> LuaResult<i64> {
+        let res = match what {
+            LUA_GCSTOP => {
+                self.heap.running = false;
+                0
+            },
+            LUA_GCRESTART => {
+                self.heap.running = true;
+                0
+            },
+            LUA_GCCOLLECT => {
+                self.full_gc()?;
+                0
+            },
+            LUA_GCCOUNT => (self.heap.total() >> 10) as i64,
+            LUA_GCCOUNTB => (self.heap.total() & 0x3ff) as i64,
+            LUA_GCSTEP => {
+                // `data` kilobytes count as allocated, 0 means a single step
+                let old_running = mem::replace(&mut self.heap.running, true);
+                let stepped = if data > 0 {
+                    self.heap.add_debt(data as usize * 1024);
+                    let stepped = self.heap.needs_step();
+                    let result = self.check_gc();
+                    self.heap.running = old_running;
+                    result?;
+                    stepped
+                } else {
+                    let result = self.gc_step();
+                    self.heap.running = old_running;
+                    result?;
+                    true
+                };
+                // whether a cycle was finished
+                (stepped && self.heap.is_paused()) as i64
+            },
+            LUA_GCSETPAUSE => mem::replace(&mut self.heap.pause, data.max(0) as usize) as i64,
+            LUA_GCSETSTEPMUL => mem::replace(&mut self.heap.stepmul, data.max(40) as usize) as i64,
+            LUA_GCISRUNNING => self.heap.running as i64,
+            _ => -1,
+        };
+        Ok(res)
+    }
+}
+
+// whether t[k] = v skips __newindex: the key is present or there is no
+// such metamethod; the metatable may be t itself, so only read t here
+fn plain_store(tbl: &LuaTable, k: &LuaValue) -> bool {
+    !tbl.get(k).is_nil() || !tbl.has_metafield("__newindex")
+}
+
+// strings and numbers concatenate without metamethods
+fn is_concatable(val: &LuaValue) -> bool {
+    matches!(val, LuaValue::LuaString(_) | LuaValue::Int64(_) | LuaValue::Float64(_))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn co_top(ls: &LuaState, idx: isize) -> isize {
+        match ls.stack.get(idx) {
+            LuaValue::Thread(co) => co.borrow().stack.top(),
+            _ => panic!("thread expected"),
+        }
+    }
+
+    #[test]
+    fn xmove_goes_both_ways() {
+        let mut ls = LuaState::new();
+        ls.push_thread();
+        ls.new_thread();
+        ls.push_integer(10);
+        ls.push_integer(20);
+        assert!(ls.xmove(1, 2, 2).is_ok());
+        assert_eq!(ls.get_top(), 2);
+        assert_eq!(co_top(&ls, 2), 2);
+
+        assert!(ls.xmove(2, 1, 1).is_ok());
+        assert_eq!(ls.get_top(), 3);
+        assert_eq!(ls.to_integer(3), 20);
+        assert_eq!(co_top(&ls, 2), 1);
+
+        // moving within a thread leaves it alone
+        assert!(ls.xmove(1, 1, 1).is_ok());
+        assert_eq!(ls.get_top(), 3);
+    }
+
+    #[test]
+    fn xmove_checks_its_arguments() {
+        let mut ls = LuaState::new();
+        ls.push_thread();
+        ls.new_thread();
+        assert!(ls.xmove(2, 1, 1).is_err());
+        assert_eq!(ls.get_top(), 2);
+        ls.push_integer(1);
+        assert!(ls.xmove(1, 3, 1).is_err());
+        assert_eq!(ls.get_top(), 3);
+    }
+
+    #[test]
+    fn next_wants_a_table() {
+        let mut ls = LuaState::new();
+        ls.push_integer(1);
+        ls.push_nil();
+        assert!(ls.next(1).is_err());
+
+        ls.new_table();
+        ls.push_nil();
+        assert!(matches!(ls.next(-2), Ok(false)));
+    }
+
+    #[test]
+    fn raw_access_wants_a_table() {
+        let mut ls = LuaState::new();
+        ls.push_integer(1);
+        ls.push_string(String::from("k"));
+        ls.push_integer(2);
+        assert!(ls.raw_set(1).is_err());
+        ls.push_integer(2);
+        assert!(ls.raw_set_i(1, 1).is_err());
+        assert!(ls.raw_get_i(1, 1).is_err());
+        assert_eq!(ls.get_top(), 1);
+
+        ls.new_table();
+        ls.push_integer(2);
+        assert!(ls.raw_set_i(2, 1).is_ok());
+        assert_eq!(ls.raw_get_i(2, 1).ok(), Some(LUA_TNUMBER));
+        assert_eq!(ls.to_integer(-1), 2);
+    }
+
+    #[test]
+    fn raw_len_measures_userdata() {
+        let mut ls = LuaState::new();
+        ls.new_userdata_uv([0u8; 24], 0);
+        assert_eq!(ls.raw_len(1), 24);
+        ls.push_boolean(true);
+        assert_eq!(ls.raw_len(2), 0);
+    }
+
+    #[test]
+    fn userdata_borrows_are_typed() {
+        let mut ls = LuaState::new();
+        ls.new_userdata(vec![1u8, 2]);
+        ls.push_integer(1);
+        assert!(ls.borrow_userdata::<String>(1).is_none());
+        assert!(ls.borrow_userdata::<Vec<u8>>(2).is_none());
+        if let Some(mut v) = ls.borrow_userdata_mut::<Vec<u8>>(1) {
+            v.push(3);
+        }
+        {
+            let a = ls.borrow_userdata::<Vec<u8>>(1).unwrap();
+            let b = ls.borrow_userdata::<Vec<u8>>(1).unwrap();
+            assert_eq!(*a, [1, 2, 3]);
+            assert_eq!(&*a as *const Vec<u8> as *mut c_void, ls.to_userdata(1));
+            // shared borrows shut out mutable ones until they end
+            assert!(ls.borrow_userdata_mut::<Vec<u8>>(1).is_none());
+            assert!(ls.check_userdata_mut::<Vec<u8>>(1).is_err());
+            drop(b);
         }
-        panic!("not a table!");
+        assert!(ls.check_userdata_mut::<Vec<u8>>(1).is_ok());
+        assert!(ls.check_userdata::<Vec<u8>>(2).is_err());
     }
 
-    fn set_table(&mut self, idx: isize) {
-        let t = self.stack.get(idx);
-        let v = self.stack.pop();
-        let k = self.stack.pop();
-        self._set_table(&t, k, v);
+    #[test]
+    fn userdata_user_values() {
+        let mut ls = LuaState::new();
+        ls.new_userdata_uv(0u32, 2);
+        ls.push_integer(7);
+        assert!(matches!(ls.set_i_user_value(1, 2), Ok(true)));
+        assert_eq!(ls.get_top(), 1);
+        assert_eq!(ls.get_i_user_value(1, 2), LUA_TNUMBER);
+        assert_eq!(ls.to_integer(-1), 7);
+        assert_eq!(ls.get_i_user_value(1, 1), LUA_TNIL);
+        assert_eq!(ls.get_i_user_value(1, 3), LUA_TNONE);
+        assert_eq!(ls.get_i_user_value(1, 0), LUA_TNONE);
+        ls.push_boolean(true);
+        assert!(matches!(ls.set_i_user_value(1, 3), Ok(false)));
+        assert_eq!(ls.get_top(), 5);
+
+        // plain new_userdata has one
+        ls.new_userdata(());
+        ls.push_boolean(true);
+        assert!(matches!(ls.set_i_user_value(-2, 1), Ok(true)));
+        assert_eq!(ls.get_i_user_value(-1, 1), LUA_TBOOLEAN);
+        assert_eq!(ls.get_i_user_value(-2, 2), LUA_TNONE);
     }
 
-    fn set_field(&mut self, idx: isize, k: String) {
-        let t = self.stack.get(idx);
-        let v = self.stack.pop();
-        self._set_table(&t, LuaValue::LuaString(k), v);
+    #[test]
+    fn light_userdata_keys_by_address() {
+        static KEY_A: u8 = 0;
+        static KEY_B: u8 = 0;
+        let a = &KEY_A as *const u8 as *mut c_void;
+        let b = &KEY_B as *const u8 as *mut c_void;
+        let mut ls = LuaState::new();
+        for (p, v) in [(a, 1), (b, 2)].iter() {
+            ls.push_light_userdata(*p);
+            ls.push_integer(*v);
+            assert!(ls.raw_set(LUA_REGISTRYINDEX).is_ok());
+        }
+        ls.push_light_userdata(b);
+        assert_eq!(ls.raw_get(LUA_REGISTRYINDEX).ok(), Some(LUA_TNUMBER));
+        assert_eq!(ls.to_integer(-1), 2);
+        ls.push_light_userdata(a);
+        assert_eq!(ls.get_table(LUA_REGISTRYINDEX).ok(), Some(LUA_TNUMBER));
+        assert_eq!(ls.to_integer(-1), 1);
+
+        ls.push_light_userdata(a);
+        ls.push_light_userdata(a);
+        ls.push_light_userdata(b);
+        assert!(ls.raw_equal(3, 4));
+        assert!(!ls.raw_equal(3, 5));
+        assert!(ls.is_light_userdata(3) && ls.is_userdata(3));
+        assert_eq!(ls.type_id(3), LUA_TLIGHTUSERDATA);
+        assert_eq!(ls.to_userdata(3), a);
+        assert!(ls.to_userdata(1).is_null());
     }
 
-    fn set_i(&mut self, idx: isize, i: i64) {
-        let t = self.stack.get(idx);
-        let v = self.stack.pop();
-        self._set_table(&t, LuaValue::Int64(i), v)
+    #[test]
+    fn bad_indices_raise_errors() {
+        let mut ls = LuaState::new();
+        assert!(ls.pop(1).is_err());
+        assert!(ls.set_top(-2).is_err());
+        ls.push_integer(1);
+        ls.push_integer(2);
+        assert!(ls.replace(5).is_err());
+        assert_eq!(ls.get_top(), 2);
+        assert!(ls.copy(1, 3).is_err());
+        assert!(ls.insert(3).is_err());
+        assert!(ls.rotate(1, 3).is_err());
+        assert!(ls.pop(3).is_err());
+        assert!(ls.set_top(1).is_ok());
+        assert!(ls.set_table(1).is_err());
+        assert!(ls.arith(LUA_OPADD).is_err());
+        assert_eq!(ls.get_top(), 1);
+        assert_eq!(ls.to_integer(1), 1);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn rust_functions_cannot_return_more_than_they_pushed() {
+        fn f(ls: &mut LuaState) -> LuaResult<usize> {
+            ls.push_integer(1);
+            Ok(3)
+        }
+        let mut ls = LuaState::new();
+        ls.push_rust_function(f);
+        assert_eq!(ls.pcall(0, 1, 0), LUA_ERRRUN);
+        assert!(ls.to_string(-1).ends_with("stack underflow"));
+    }
+}
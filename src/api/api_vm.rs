@@ -1,54 +1,326 @@
 use crate::state::lua_state::LuaState;
 use crate::state::lua_value::LuaValue;
+use crate::state::closure::Closure;
+use crate::state::lua_table::LuaTable;
+use crate::state::lua_error::LuaResult;
 use crate::api::api_stack::LuaAPI;
-use crate::binary_chunk::prototype::Constant;
+use crate::api::{api_arith, api_cmp};
+use crate::api::consts::*;
+use crate::vm::instruction::{Op, RK};
+use std::cell::Cell;
 
 pub type LuaVM = LuaState;
 
+// what the instructions use; registers are read and written in place,
+// operations take and give values instead of going through the stack
 pub trait VmAPI {
     fn pc(&self) -> isize;
     fn add_pc(&mut self, n: isize);
-    fn fetch(&mut self) -> u32;
-    fn get_const(&mut self, idx: isize);
-    fn get_rk(&mut self, rk: isize);
+    fn jump(&mut self, pc: usize);
+    fn fetch(&mut self) -> Op;
+    fn get_const(&self, idx: usize) -> LuaValue;
+    fn get_rk(&self, rk: RK) -> LuaValue;
+    fn reg(&self, r: usize) -> LuaValue;
+    fn set_reg(&mut self, r: usize, val: LuaValue);
+    fn upval(&self, idx: usize) -> LuaValue;
+    fn set_upval(&mut self, idx: usize, val: LuaValue);
+    fn register_count(&self) -> isize;
+    fn load_vararg(&mut self, n: isize);
+    fn load_proto(&mut self, idx: usize) -> LuaValue;
+    fn close_upvalues(&mut self, a: isize);
+    fn index(&mut self, t: LuaValue, k: LuaValue) -> LuaResult<LuaValue>;
+    fn finish_index(&mut self, t: LuaValue, k: LuaValue) -> LuaResult<LuaValue>;
+    fn index_rk(&mut self, t: LuaValue, rk: RK) -> LuaResult<LuaValue>;
+    fn set_index_rk(&mut self, t: LuaValue, rk: RK, v: LuaValue) -> LuaResult<()>;
+    fn arith_values(&mut self, a: LuaValue, b: LuaValue, op: u8) -> LuaResult<LuaValue>;
+    fn compare_values(&mut self, a: LuaValue, b: LuaValue, op: u8) -> LuaResult<bool>;
+    fn len_value(&mut self, val: LuaValue) -> LuaResult<LuaValue>;
 }
 
 impl VmAPI for LuaState {
     fn pc(&self) -> isize {
-        self.pc
+        self.stack.pc
     }
 
     fn add_pc(&mut self, n: isize) {
-        self.pc += n;
+        self.stack.pc += n;
     }
 
-    fn fetch(&mut self) -> u32 {
-        let result = self.proto.code[self.pc as usize];
-        self.pc += 1;
+    fn jump(&mut self, pc: usize) {
+        self.stack.pc = pc as isize;
+    }
+
+    fn fetch(&mut self) -> Op {
+        let result = self.stack.proto().ops[self.stack.pc as usize];
+        self.stack.pc += 1;
         result
     }
 
-    fn get_const(&mut self, idx: isize) {
-        let tmp = &self.proto.constants[idx as usize];
-        let val = match tmp {
-            Constant::Nil => LuaValue::Nil,
-            Constant::Boolean(b) => LuaValue::Bool(*b),
-            Constant::Integer(i) => LuaValue::Int64(*i),
-            Constant::Number(n) => LuaValue::Float64(*n),
-            Constant::LuaStr(s) => LuaValue::LuaString((*s).clone()),
-        };
-        self.stack.push(val);
+    fn get_const(&self, idx: usize) -> LuaValue {
+        self.stack.proto().k[idx]
+    }
+
+    fn get_rk(&self, rk: RK) -> LuaValue {
+        match rk {
+            RK::Const(idx) => self.get_const(idx),
+            RK::Reg(r) => self.reg(r),
+        }
+    }
+
+    fn reg(&self, r: usize) -> LuaValue {
+        self.stack.reg(r)
+    }
+
+    fn set_reg(&mut self, r: usize, val: LuaValue) {
+        self.stack.set_reg(r, val);
+    }
+
+    fn upval(&self, idx: usize) -> LuaValue {
+        let upvals = &self.stack.closure.as_ref().unwrap().upvals;
+        *upvals[idx].borrow()
+    }
+
+    fn set_upval(&mut self, idx: usize, val: LuaValue) {
+        let upvals = &self.stack.closure.as_ref().unwrap().upvals;
+        *upvals[idx].borrow_mut() = val;
+    }
+
+    fn register_count(&self) -> isize {
+        self.stack.proto().max_stack_size as isize
+    }
+
+    fn load_vararg(&mut self, mut n: isize) {
+        if n < 0 {
+            n = self.stack.varargs.len() as isize;
+        }
+        let varargs = self.stack.varargs.clone();
+        self.stack.check(n as usize);
+        self.stack.push_n(varargs, n);
+    }
+
+    fn load_proto(&mut self, idx: usize) -> LuaValue {
+        let sub_proto = self.stack.proto().protos[idx].clone();
+        let mut closure = Closure::new_lua_closure(&mut self.heap, sub_proto.clone());
+        for (i, uv_info) in sub_proto.up_values.iter().enumerate() {
+            let uv_idx = uv_info.idx as isize;
+            if uv_info.in_stack == 1 {
+                closure.upvals[i] = self.stack.capture(uv_idx, &mut self.heap);
+            } else {
+                let upvals = &self.stack.closure.as_ref().unwrap().upvals;
+                closure.upvals[i] = upvals[uv_idx as usize];
+            }
+        }
+        LuaValue::Function(self.heap.alloc(closure))
+    }
+
+    fn close_upvalues(&mut self, a: isize) {
+        self.stack.close_upvalues(a - 1);
+    }
+
+    // t[k], following __index
+    fn index(&mut self, t: LuaValue, k: LuaValue) -> LuaResult<LuaValue> {
+        if let LuaValue::Table(tbl) = &t {
+            let tbl = tbl.borrow();
+            let v = tbl.get(&k);
+            if !v.is_nil() || !tbl.has_metafield("__index") {
+                return Ok(v);
+            }
+        }
+        self.finish_index(t, k)
+    }
+
+    // t[k] once the raw lookup in t has missed, starting from its __index
+    // (see luaV_finishget)
+    fn finish_index(&mut self, mut t: LuaValue, k: LuaValue) -> LuaResult<LuaValue> {
+        for _ in 0..MAXTAGLOOP {
+            let mf = self.get_metafield(&t, "__index");
+            match mf {
+                LuaValue::Table(_) => t = mf,
+                LuaValue::Function(_) => {
+                    self.stack.push(mf);
+                    self.stack.push(t);
+                    self.stack.push(k);
+                    self.call_tm(2, 1)?;
+                    return self.stack.pop();
+                },
+                _ => return Err(self.type_error(&t, "index")),
+            }
+
+            if let LuaValue::Table(tbl) = &t {
+                let tbl = tbl.borrow();
+                let v = tbl.get(&k);
+                if !v.is_nil() || !tbl.has_metafield("__index") {
+                    return Ok(v);
+                }
+            }
+        }
+        Err(self.runtime_error("'__index' chain too long; possible loop"))
+    }
+
+    // t[RK], through the inline cache of the instruction for string constant keys
+    fn index_rk(&mut self, t: LuaValue, rk: RK) -> LuaResult<LuaValue> {
+        let k = self.get_rk(rk);
+        if let (LuaValue::Table(tbl), RK::Const(_), LuaValue::LuaString(_)) = (t, rk, k) {
+            let cache = &self.stack.proto().caches[self.stack.pc as usize - 1];
+            let tbl = tbl.borrow();
+            let v = match cached_slot(&tbl, cache, &k) {
+                Some(slot) => tbl.slot_value(slot),
+                None => LuaValue::Nil,
+            };
+            if !v.is_nil() || !tbl.has_metafield("__index") {
+                return Ok(v);
+            }
+            drop(tbl);
+            return self.finish_index(t, k);
+        }
+        self.index(t, k)
+    }
+
+    // t[RK] = v, like index_rk; a present field is set in its slot
+    fn set_index_rk(&mut self, t: LuaValue, rk: RK, v: LuaValue) -> LuaResult<()> {
+        let k = self.get_rk(rk);
+        if let (LuaValue::Table(tbl), RK::Const(_), LuaValue::LuaString(_)) = (t, rk, k) {
+            let cache = &self.stack.proto().caches[self.stack.pc as usize - 1];
+            // the metatable may be the table itself, so look before writing
+            let (slot, has_mm) = {
+                let tbl = tbl.borrow();
+                match cached_slot(&tbl, cache, &k) {
+                    Some(slot) if !tbl.slot_value(slot).is_nil() => (Some(slot), false),
+                    _ => (None, tbl.has_metafield("__newindex")),
+                }
+            };
+            if has_mm {
+                return self._finish_set_table(t, k, v);
+            }
+            let mut tbl = tbl.borrow_mut();
+            match slot {
+                Some(slot) => tbl.set_slot(slot, v),
+                // a string key is never nil or NaN
+                None => tbl.put(k, v).map_err(|msg| self.runtime_error(msg))?,
+            }
+            return Ok(());
+        }
+        self._set_table(&t, k, v, false)
+    }
+
+    // b is ignored by the unary operators
+    fn arith_values(&mut self, a: LuaValue, b: LuaValue, op: u8) -> LuaResult<LuaValue> {
+        match api_arith::_arith(&a, &b, op) {
+            Ok(Some(result)) => return Ok(result),
+            Ok(None) => {},
+            Err(msg) => return Err(self.runtime_error(msg)),
+        }
+        let mm = api_arith::METAMETHODS[op as usize];
+        if let Some(result) = self.call_metamethod(&a, &b, mm)? {
+            return Ok(result);
+        }
+        Err(self.arith_error(&a, &b, op))
+    }
+
+    fn compare_values(&mut self, a: LuaValue, b: LuaValue, op: u8) -> LuaResult<bool> {
+        match op {
+            LUA_OPEQ => {
+                if api_cmp::_eq(&a, &b) {
+                    return Ok(true);
+                }
+                match (&a, &b) {
+                    (LuaValue::Table(_), LuaValue::Table(_)) | (LuaValue::UserData(_), LuaValue::UserData(_)) => {
+                        if let Some(result) = self.call_metamethod(&a, &b, "__eq")? {
+                            return Ok(result.to_bool());
+                        }
+                    },
+                    _ => {},
+                }
+                Ok(false)
+            },
+            LUA_OPLT => {
+                if let Some(result) = api_cmp::_lt(&a, &b, self.str_order) {
+                    return Ok(result);
+                }
+                if let Some(result) = self.call_metamethod(&a, &b, "__lt")? {
+                    return Ok(result.to_bool());
+                }
+                Err(self.order_error(&a, &b))
+            },
+            LUA_OPLE => {
+                if let Some(result) = api_cmp::_le(&a, &b, self.str_order) {
+                    return Ok(result);
+                }
+                if let Some(result) = self.call_metamethod(&a, &b, "__le")? {
+                    return Ok(result.to_bool());
+                }
+                // a <= b is not (b < a); finish_op needs to know after a yield
+                self.stack.leq_by_lt = true;
+                let result = self.call_metamethod(&b, &a, "__lt")?;
+                self.stack.leq_by_lt = false;
+                if let Some(result) = result {
+                    return Ok(!result.to_bool());
+                }
+                Err(self.order_error(&a, &b))
+            },
+            _ => panic!("invalid cmp op!")
+        }
     }
 
-    fn get_rk(&mut self, rk: isize) {
-        if rk > 0xFF {
-            self.get_const(rk & 0xFF);
-        } else {
-            self.push_value(rk + 1);
+    fn len_value(&mut self, val: LuaValue) -> LuaResult<LuaValue> {
+        if let LuaValue::LuaString(s) = &val {
+            return Ok(LuaValue::Int64(s.len() as i64));
         }
+        if let Some(result) = self.call_metamethod(&val, &val, "__len")? {
+            return Ok(result);
+        }
+        if let LuaValue::Table(t) = &val {
+            return Ok(LuaValue::Int64(t.borrow().len() as i64));
+        }
+        Err(self.type_error(&val, "get length of"))
+    }
+}
 
+// the slot of `k` in the hash part, trying the one remembered by the cache
+// first; a rehash moves the keys, so a slot is only trusted while it
+// still holds the key
+fn cached_slot(tbl: &LuaTable, cache: &Cell<usize>, k: &LuaValue) -> Option<usize> {
+    if tbl.slot_holds(cache.get(), k) {
+        return Some(cache.get());
     }
+    let slot = tbl.find_slot(k)?;
+    cache.set(slot);
+    Some(slot)
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::gc::Heap;
+
+    #[test]
+    fn cached_slots_survive_until_a_rehash() {
+        let mut heap = Heap::new();
+        let k = LuaValue::new_string(&mut heap, "field");
+        let mut t = LuaTable::new(0, 0);
+        t.put(k, LuaValue::Int64(1)).unwrap();
+        let cache = Cell::new(usize::MAX);
+
+        // a miss fills the cache, a hit keeps it
+        let slot = cached_slot(&t, &cache, &k).unwrap();
+        assert_eq!(cache.get(), slot);
+        assert_eq!(cached_slot(&t, &cache, &k), Some(slot));
 
+        // growing the hash part moves the keys; the stale slot is not trusted
+        for i in 0..64 {
+            let other = LuaValue::new_string(&mut heap, &format!("k{}", i));
+            t.put(other, LuaValue::Bool(true)).unwrap();
+        }
+        let slot = cached_slot(&t, &cache, &k).unwrap();
+        assert!(t.slot_holds(slot, &k));
+        assert!(t.slot_value(slot) == LuaValue::Int64(1));
+        assert_eq!(cache.get(), slot);
 
+        // another table at the same instruction misses too
+        let mut u = LuaTable::new(0, 0);
+        assert_eq!(cached_slot(&u, &cache, &k), None);
+        u.put(k, LuaValue::Int64(2)).unwrap();
+        let slot = cached_slot(&u, &cache, &k).unwrap();
+        assert!(u.slot_value(slot) == LuaValue::Int64(2));
+    }
+}
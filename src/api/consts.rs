@@ -30,3 +30,46 @@ pub const LUA_OPBNOT: u8 = 13; // ~
 pub const LUA_OPEQ: u8 = 0;
 pub const LUA_OPLT: u8 = 1;
 pub const LUA_OPLE: u8 = 2;
+
+// thread status
+pub const LUA_OK: u8 = 0;
+pub const LUA_YIELD: u8 = 1;
+pub const LUA_ERRRUN: u8 = 2;
+pub const LUA_ERRSYNTAX: u8 = 3;
+pub const LUA_ERRMEM: u8 = 4;
+pub const LUA_ERRGCMM: u8 = 5;
+pub const LUA_ERRERR: u8 = 6;
+
+// garbage collector options
+pub const LUA_GCSTOP: u8 = 0;
+pub const LUA_GCRESTART: u8 = 1;
+pub const LUA_GCCOLLECT: u8 = 2;
+pub const LUA_GCCOUNT: u8 = 3;
+pub const LUA_GCCOUNTB: u8 = 4;
+pub const LUA_GCSTEP: u8 = 5;
+pub const LUA_GCSETPAUSE: u8 = 6;
+pub const LUA_GCSETSTEPMUL: u8 = 7;
+pub const LUA_GCISRUNNING: u8 = 9;
+
+// stack
+pub const LUAI_MAXCCALLS: usize = 200; // nested Rust calls
+pub const LUAI_MAXCALLS: usize = 200000; // active frames
+pub const LUA_MINSTACK: usize = 20;
+pub const LUAI_MAXSTACK: isize = 1000000;
+pub const LUA_REGISTRYINDEX: isize = -LUAI_MAXSTACK - 1000;
+pub const LUA_MULTRET: isize = -1;
+
+// metamethods
+pub const MAXTAGLOOP: usize = 2000;
+
+// registry
+pub const LUA_RIDX_MAINTHREAD: i64 = 1;
+pub const LUA_RIDX_GLOBALS: i64 = 2;
+
+// references
+pub const LUA_NOREF: i64 = -2;
+pub const LUA_REFNIL: i64 = -1;
+
+pub fn lua_upvalue_index(i: isize) -> isize {
+    LUA_REGISTRYINDEX - i
+}
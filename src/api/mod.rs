@@ -2,4 +2,10 @@ pub mod consts;
 pub mod api_stack;
 pub mod api_arith;
 pub mod api_cmp;
-pub mod api_vm;
\ No newline at end of file
+pub mod api_vm;
+pub mod api_call;
+pub mod api_meta;
+pub mod api_error;
+pub mod api_debug;
+pub mod api_gc;
+pub mod api_aux;
@@ -1,3 +1,106 @@
 pub mod reader;
 mod header;
 pub mod prototype;
+
+use crate::binary_chunk::prototype::Prototype;
+use crate::binary_chunk::reader::ReadResult;
+
+pub fn undump(data: Vec<u8>) -> ReadResult<Prototype> {
+    let mut r = reader::Reader::new(data);
+    r.check_header()?;
+    r.read_byte()?; // size_upvalues
+    r.read_proto(String::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::api_stack::LuaAPI;
+    use crate::api::consts::*;
+    use crate::state::lua_state::LuaState;
+    use crate::vm::opcodes::*;
+
+    // a main function with no constants, upvalues or debug information
+    fn chunk(code: &[u32]) -> Vec<u8> {
+        let mut b = vec![0x1B, 0x4C, 0x75, 0x61, 0x53, 0x00];
+        b.extend_from_slice(&[0x19, 0x93, 0x0D, 0x0A, 0x1A, 0x0A]);
+        b.extend_from_slice(&[4, 8, 4, 8, 8]);
+        b.extend_from_slice(&0x5678i64.to_le_bytes());
+        b.extend_from_slice(&370.5f64.to_le_bytes());
+        b.push(0); // size_upvalues
+        b.push(0); // source
+        b.extend_from_slice(&[0; 8]); // line_defined, last_line_defined
+        b.extend_from_slice(&[0, 1, 2]); // num_params, is_vararg, max_stack_size
+        b.extend_from_slice(&(code.len() as u32).to_le_bytes());
+        for i in code {
+            b.extend_from_slice(&i.to_le_bytes());
+        }
+        // constants, upvalues, protos, line info, locals, upvalue names
+        for _ in 0..6 {
+            b.extend_from_slice(&0u32.to_le_bytes());
+        }
+        b
+    }
+
+    fn load(chunk: Vec<u8>) -> (u8, String) {
+        let mut ls = LuaState::new();
+        let status = ls.load(chunk);
+        let msg = if status == LUA_OK { String::new() } else { ls.to_string(-1) };
+        (status, msg)
+    }
+
+    const RETURN: u32 = OP_RETURN as u32 | 1 << 23; // RETURN 0 1
+
+    #[test]
+    fn loads_a_chunk() {
+        assert_eq!(load(chunk(&[RETURN])).0, LUA_OK);
+    }
+
+    #[test]
+    fn truncated_chunks_are_rejected() {
+        let full = chunk(&[RETURN]);
+        for n in 0..full.len() {
+            let (status, msg) = load(full[..n].to_vec());
+            assert_eq!(status, LUA_ERRSYNTAX, "{} bytes", n);
+            assert!(msg.starts_with("bad binary format"), "{}", msg);
+        }
+    }
+
+    #[test]
+    fn bad_headers_are_rejected() {
+        let mut b = chunk(&[RETURN]);
+        b[4] = 0x52;
+        assert_eq!(load(b), (LUA_ERRSYNTAX, String::from("bad binary format (version mismatch)")));
+        let mut b = chunk(&[RETURN]);
+        b[12] = 8; // int size
+        assert_eq!(load(b), (LUA_ERRSYNTAX, String::from("bad binary format (int size mismatch)")));
+    }
+
+    #[test]
+    fn huge_counts_are_rejected() {
+        let mut b = chunk(&[RETURN]);
+        let at = b.len() - 6 * 4 - 4 - 4; // size of the code
+        b[at..at + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(load(b), (LUA_ERRSYNTAX, String::from("bad binary format (truncated chunk)")));
+    }
+
+    #[test]
+    fn unknown_opcodes_are_rejected() {
+        let (status, msg) = load(chunk(&[63, RETURN]));
+        assert_eq!(status, LUA_ERRSYNTAX);
+        assert_eq!(msg, "bad binary format (unknown opcode)");
+    }
+
+    #[test]
+    fn extra_args_must_follow() {
+        let loadkx = OP_LOADKX as u32;
+        let setlist = OP_SETLIST as u32 | 1 << 23; // SETLIST 0 1 0
+        for code in [[RETURN, loadkx], [RETURN, setlist], [loadkx, RETURN], [setlist, RETURN]].iter() {
+            let (status, msg) = load(chunk(code));
+            assert_eq!(status, LUA_ERRSYNTAX);
+            assert_eq!(msg, "bad binary format (missing EXTRAARG)");
+        }
+        let extra = OP_EXTRAARG as u32;
+        assert_eq!(load(chunk(&[loadkx, extra, RETURN])).0, LUA_OK);
+        assert_eq!(load(chunk(&[setlist, extra, RETURN])).0, LUA_OK);
+    }
+}
@@ -1,3 +1,10 @@
+use crate::state::lua_value::LuaValue;
+use crate::vm::instruction::{decode, Op};
+use crate::state::gc::{GcPtr, Heap, Trace};
+use std::rc::Rc;
+use std::cell::Cell;
+use std::convert::TryFrom;
+
 // tag
 #[repr(u8)]
 pub enum Tag {
@@ -9,17 +16,18 @@ pub enum Tag {
     LongStr = 0x14,
 }
 
-impl From<u8> for Tag {
-    fn from(data: u8) -> Self {
-        match data {
+impl TryFrom<u8> for Tag {
+    type Error = &'static str;
+    fn try_from(data: u8) -> Result<Self, Self::Error> {
+        Ok(match data {
             0x00 => Tag::Nil,
             0x01 => Tag::Bool,
             0x03 => Tag::Number,
             0x13 => Tag::Integer,
             0x04 => Tag::ShortStr,
             0x14 => Tag::LongStr,
-            _ => panic!("Tag convert fail!")
-        }
+            _ => return Err("bad constant type"),
+        })
     }
 }
 
@@ -52,8 +60,47 @@ pub struct Prototype {
     pub code: Vec<u32>,
     pub constants: Vec<Constant>,
     pub up_values: Vec<UpValue>,
-    pub protos: Vec<Prototype>,
+    pub protos: Vec<Rc<Prototype>>,
     pub line_info: Vec<u32>,
     pub loc_vars: Vec<LocVar>,
-    pub up_value_names: Vec<String>
-}
\ No newline at end of file
+    pub up_value_names: Vec<String>,
+    // filled in when the chunk is loaded into a state: the constants as
+    // values and the code decoded for the interpreter
+    pub k: Vec<LuaValue>,
+    pub ops: Vec<Op>,
+    // per instruction, the hash slot last used by a field access with a
+    // string constant key
+    pub caches: Vec<Cell<usize>>,
+}
+impl Prototype {
+    // put the constants of this function and the nested ones on the heap
+    // and decode their code
+    pub fn load(&mut self, heap: &mut Heap) -> Result<(), &'static str> {
+        self.ops = decode(&self.code)?;
+        self.caches = vec![Cell::new(0); self.ops.len()];
+        self.k = self.constants.iter().map(|c| match c {
+            Constant::Nil => LuaValue::Nil,
+            Constant::Boolean(b) => LuaValue::Bool(*b),
+            Constant::Integer(i) => LuaValue::Int64(*i),
+            Constant::Number(n) => LuaValue::Float64(*n),
+            Constant::LuaStr(s) => LuaValue::new_string(heap, s),
+        }).collect();
+        for p in self.protos.iter_mut() {
+            // not shared with anything yet
+            Rc::get_mut(p).unwrap().load(heap)?;
+        }
+        Ok(())
+    }
+}
+
+// the constants of nested functions are kept for the closures still to be made
+impl Trace for Prototype {
+    fn trace(&self, gray: &mut Vec<GcPtr>) {
+        for val in self.k.iter() {
+            val.trace(gray);
+        }
+        for p in self.protos.iter() {
+            p.trace(gray);
+        }
+    }
+}
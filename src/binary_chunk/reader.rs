@@ -1,9 +1,15 @@
 use crate::binary_chunk::header;
 use crate::binary_chunk::prototype;
 use crate::binary_chunk::prototype::Tag;
+use crate::state::lua_value::float_to_string;
 
 use crate::vm::instruction::Instruction_impl;
 use crate::vm::opcodes;
+use std::convert::TryFrom;
+use std::rc::Rc;
+
+// what is wrong with a chunk
+pub type ReadResult<T> = Result<T, &'static str>;
 
 pub struct Reader {
     data: Vec<u8>,
@@ -13,176 +19,191 @@ pub struct Reader {
 impl Reader {
     pub fn new(data: Vec<u8>) -> Reader {
         Reader {
-            data: data,
+            data,
             loc: 0
         }
     }
     // read basic type
-    pub fn read_byte(&mut self) -> u8 {
-        let result = self.data[self.loc];
+    pub fn read_byte(&mut self) -> ReadResult<u8> {
+        let result = *self.data.get(self.loc).ok_or("truncated chunk")?;
         self.loc += 1;
-        result
+        Ok(result)
     }
 
-    pub fn read_bytes(&mut self, size: usize) -> Vec<u8> {
-        let mut result = Vec::with_capacity(size);
-        for i in 0..size {
-            result.push(self.data[self.loc+i]);
+    pub fn read_bytes(&mut self, size: usize) -> ReadResult<Vec<u8>> {
+        if size > self.data.len() - self.loc {
+            return Err("truncated chunk");
         }
+        let result = self.data[self.loc..self.loc + size].to_vec();
         self.loc += size;
-        result
+        Ok(result)
     }
 
-    pub fn read_uint32(&mut self) -> u32 {
-        let bytes: Vec<u8> = self.read_bytes(4);
+    pub fn read_uint32(&mut self) -> ReadResult<u32> {
+        let bytes: Vec<u8> = self.read_bytes(4)?;
         let mut result: u32 = 0;
-        for i in 0..4 {
-            let tmp: u32 = bytes[i] as u32;
+        for (i, byte) in bytes.iter().enumerate() {
+            let tmp: u32 = *byte as u32;
             result |= tmp << (8 * i);
         }
-        result
+        Ok(result)
     }
 
-    pub fn read_uint64(&mut self) -> u64 {
-        let bytes: Vec<u8> = self.read_bytes(8);
+    pub fn read_uint64(&mut self) -> ReadResult<u64> {
+        let bytes: Vec<u8> = self.read_bytes(8)?;
         let mut result: u64 = 0;
-        for i in 0..8 {
-            let tmp: u64 = bytes[i] as u64;
+        for (i, byte) in bytes.iter().enumerate() {
+            let tmp: u64 = *byte as u64;
             result |= tmp << (8 * i);
         }
-        result
+        Ok(result)
+    }
+
+    pub fn read_lua_integer(&mut self) -> ReadResult<i64> {
+        Ok(self.read_uint64()? as i64)
     }
 
-    pub fn read_lua_integer(&mut self) -> i64 {
-        self.read_uint64() as i64
+    pub fn read_lua_number(&mut self) -> ReadResult<f64> {
+        Ok(f64::from_bits(self.read_uint64()?))
     }
 
-    pub fn read_lua_number(&mut self) -> f64 {
-        f64::from_bits(self.read_uint64())
+    // the length of a list; every item takes at least one byte, so a longer
+    // list cannot be in the chunk
+    fn read_count(&mut self) -> ReadResult<usize> {
+        let size = self.read_uint32()? as usize;
+        if size > self.data.len() - self.loc {
+            return Err("truncated chunk");
+        }
+        Ok(size)
     }
 
-    pub fn read_string(&mut self) -> String {
-        let mut size = self.read_byte() as usize;
+    pub fn read_string(&mut self) -> ReadResult<String> {
+        let mut size = self.read_byte()? as usize;
         if size == 0x00 {
-            return String::new();
+            return Ok(String::new());
         }
 
         if size == 0xFF {
-            size = self.read_uint64() as usize;
+            size = self.read_uint64()? as usize;
+            if size == 0 {
+                return Err("bad string size");
+            }
         }
-        let bytes = self.read_bytes(size - 1);
-        return String::from_utf8(bytes).unwrap();
+        let bytes = self.read_bytes(size - 1)?;
+        String::from_utf8(bytes).map_err(|_| "string is not utf-8")
     }
 
     // read prototype
-    pub fn read_proto(&mut self, parent_source: String) -> prototype::Prototype {
-        let mut source:String = self.read_string();
+    pub fn read_proto(&mut self, parent_source: String) -> ReadResult<prototype::Prototype> {
+        let mut source:String = self.read_string()?;
         if source.is_empty() {
             source = parent_source;
         }
-        return prototype::Prototype {
+        Ok(prototype::Prototype {
             source: source.clone(),
-            line_defined: self.read_uint32(),
-            last_line_defined: self.read_uint32(),
-            num_params: self.read_byte(),
-            is_vararg: self.read_byte(),
-            max_stack_size: self.read_byte(),
-            code: self.read_code(),
-            constants: self.read_constants(),
-            up_values: self.read_up_values(),
-            protos: self.read_protos(source),
-            line_info: self.read_line_info(),
-            loc_vars: self.read_loc_vars(),
-            up_value_names: self.read_up_value_names()
-        };
+            line_defined: self.read_uint32()?,
+            last_line_defined: self.read_uint32()?,
+            num_params: self.read_byte()?,
+            is_vararg: self.read_byte()?,
+            max_stack_size: self.read_byte()?,
+            code: self.read_code()?,
+            constants: self.read_constants()?,
+            up_values: self.read_up_values()?,
+            protos: self.read_protos(source)?,
+            line_info: self.read_line_info()?,
+            loc_vars: self.read_loc_vars()?,
+            up_value_names: self.read_up_value_names()?,
+            k: Vec::new(),
+            ops: Vec::new(),
+            caches: Vec::new(),
+        })
     }
 
-    pub fn read_code(&mut self) -> Vec<u32> {
-        let size = self.read_uint32() as usize;
+    pub fn read_code(&mut self) -> ReadResult<Vec<u32>> {
+        let size = self.read_count()?;
         let mut result = Vec::with_capacity(size);
         for _ in 0..size {
-            result.push(self.read_uint32());
+            result.push(self.read_uint32()?);
         }
-        result
+        Ok(result)
     }
 
-    pub fn read_constants(&mut self) -> Vec<prototype::Constant> {
-        let size = self.read_uint32() as usize;
+    pub fn read_constants(&mut self) -> ReadResult<Vec<prototype::Constant>> {
+        let size = self.read_count()?;
         let mut result = Vec::with_capacity(size);
         for _ in 0..size {
-            result.push(self.read_constant());
+            result.push(self.read_constant()?);
         }
-        result
+        Ok(result)
     }
 
-    pub fn read_constant(&mut self) -> prototype::Constant {
-        let const_type: Tag = Tag::from(self.read_byte());
-        match const_type {
+    pub fn read_constant(&mut self) -> ReadResult<prototype::Constant> {
+        let const_type = Tag::try_from(self.read_byte()?)?;
+        Ok(match const_type {
             Tag::Nil => prototype::Constant::Nil,
-            Tag::Bool => prototype::Constant::Boolean(self.read_byte() != 0),
-            Tag::Integer => prototype::Constant::Integer(self.read_lua_integer()),
-            Tag::Number => prototype::Constant::Number(self.read_lua_number()),
-            Tag::ShortStr => prototype::Constant::LuaStr(self.read_string()),
-            Tag::LongStr => prototype::Constant::LuaStr(self.read_string()),
-            _ => panic!("unknown type!")
-        }
+            Tag::Bool => prototype::Constant::Boolean(self.read_byte()? != 0),
+            Tag::Integer => prototype::Constant::Integer(self.read_lua_integer()?),
+            Tag::Number => prototype::Constant::Number(self.read_lua_number()?),
+            Tag::ShortStr => prototype::Constant::LuaStr(self.read_string()?),
+            Tag::LongStr => prototype::Constant::LuaStr(self.read_string()?),
+        })
     }
 
-    pub fn read_up_values(&mut self) -> Vec<prototype::UpValue> {
-        let size = self.read_uint32() as usize;
+    pub fn read_up_values(&mut self) -> ReadResult<Vec<prototype::UpValue>> {
+        let size = self.read_count()?;
         let mut result = Vec::with_capacity(size);
         for _ in 0..size {
             result.push(prototype::UpValue{
-                in_stack: self.read_byte(),
-                idx: self.read_byte()
+                in_stack: self.read_byte()?,
+                idx: self.read_byte()?
             });
         }
-        result
+        Ok(result)
     }
 
-    pub fn read_protos(&mut self, parent_source: String) -> Vec<prototype::Prototype> {
-        let size = self.read_uint32() as usize;
+    pub fn read_protos(&mut self, parent_source: String) -> ReadResult<Vec<Rc<prototype::Prototype>>> {
+        let size = self.read_count()?;
         let mut result = Vec::with_capacity(size);
         for _ in 0..size {
-            result.push(self.read_proto(parent_source.clone()));
+            result.push(Rc::new(self.read_proto(parent_source.clone())?));
         }
-        result
+        Ok(result)
     }
 
-    pub fn read_line_info(&mut self) -> Vec<u32> {
-        let size = self.read_uint32() as usize;
+    pub fn read_line_info(&mut self) -> ReadResult<Vec<u32>> {
+        let size = self.read_count()?;
         let mut result = Vec::with_capacity(size);
         for _ in 0..size {
-            result.push(self.read_uint32());
+            result.push(self.read_uint32()?);
         }
-        result
+        Ok(result)
     }
 
-    pub fn read_loc_vars(&mut self) -> Vec<prototype::LocVar> {
-        let size = self.read_uint32() as usize;
+    pub fn read_loc_vars(&mut self) -> ReadResult<Vec<prototype::LocVar>> {
+        let size = self.read_count()?;
         let mut result = Vec::with_capacity(size);
         for _ in 0..size {
             result.push(prototype::LocVar {
-                var_name: self.read_string(),
-                start_pc: self.read_uint32(),
-                end_pc: self.read_uint32(),
+                var_name: self.read_string()?,
+                start_pc: self.read_uint32()?,
+                end_pc: self.read_uint32()?,
             });
         }
-        result
+        Ok(result)
     }
 
-    pub fn read_up_value_names(&mut self) -> Vec<String> {
-        let size = self.read_uint32() as usize;
+    pub fn read_up_value_names(&mut self) -> ReadResult<Vec<String>> {
+        let size = self.read_count()?;
         let mut result = Vec::with_capacity(size);
         for _ in 0..size {
-            result.push(self.read_string());
+            result.push(self.read_string()?);
         }
-        result
+        Ok(result)
     }
 
     // print function
     pub fn print_content(&mut self, cur_proto: &prototype::Prototype) {
-        println!("");
+        println!();
         self.print_header(cur_proto);
         self.print_code(cur_proto);
         self.print_detail(cur_proto);
@@ -230,7 +251,7 @@ impl Reader {
     pub fn print_code(&mut self, cur_proto: &prototype::Prototype) {
         for pc in 0..cur_proto.code.len() {
             let mut line:String = String::from("-");
-            if cur_proto.line_info.len() > 0 {
+            if !cur_proto.line_info.is_empty() {
                 line = cur_proto.line_info[pc].to_string();
             }
             print!(
@@ -240,7 +261,7 @@ impl Reader {
                 cur_proto.code[pc].opname()
             );
             self.print_operands(cur_proto.code[pc]);
-            println!("");
+            println!();
         }
     }
 
@@ -290,7 +311,7 @@ impl Reader {
         for i in 0..cur_proto.constants.len() {
             print!("\t{}\t", i+1);
             self.print_constant(&cur_proto.constants[i]);
-            println!("");
+            println!();
         }
 
         println!("locals ({}):", cur_proto.loc_vars.len());
@@ -317,7 +338,7 @@ impl Reader {
     }
 
     pub fn print_up_value_name(&mut self, cur_proto: &prototype::Prototype, idx: usize) {
-        if cur_proto.up_value_names.len() > 0 {
+        if !cur_proto.up_value_names.is_empty() {
             print!("{}", cur_proto.up_value_names[idx]);
         }
     }
@@ -328,57 +349,47 @@ impl Reader {
             Nil => print!("nil"),
             Boolean(a) => print!("{}", a),
             Integer(b) => print!("{}", b),
-            Number(c) => print!("{}", c),
+            Number(c) => print!("{}", float_to_string(*c)),
             LuaStr(d) => print!("\"{}\"", d),
         }
     }
 
     // another
-    pub fn check_header(&mut self) {
-        assert_eq!(
-            self.read_bytes(4), header::SIGNATURE,
-            "not a pre compiled chunk!"
-        );
-        assert_eq!(
-            self.read_byte(), header::VERSION,
-            "version mismatch!"
-        );
-        assert_eq!(
-            self.read_byte(), header::FORMAT,
-            "format mismatch!"
-        );
-        assert_eq!(
-            self.read_bytes(6), header::LUAC_DATA,
-            "corrupted!"
-        );
-        assert_eq!(
-            self.read_byte(), header::CINT_SIZE,
-            "int size mismatch!"
-        );
-        assert_eq!(
-            self.read_byte(), header::SIZET_SIZE,
-            "size_t size mismatch!"
-        );
-        assert_eq!(
-            self.read_byte(), header::INSTRUCTION_SIZE,
-            "instruction size mismatch!"
-        );
-        assert_eq!(
-            self.read_byte(), header::LUA_INT_SIZE,
-            "lua_integer size mismatch!"
-        );
-        assert_eq!(
-            self.read_byte(), header::LUA_NUM_SIZE,
-            "lua_number size mismatch!"
-        );
-        assert_eq!(
-            self.read_lua_integer(), header::LUAC_INT,
-            "endianness mismatch!"
-        );
-        assert_eq!(
-            self.read_lua_number(), header::LUAC_NUM,
-            "float format mismatch!"
-        );
+    pub fn check_header(&mut self) -> ReadResult<()> {
+        if self.read_bytes(4)? != header::SIGNATURE {
+            return Err("not a precompiled chunk");
+        }
+        if self.read_byte()? != header::VERSION {
+            return Err("version mismatch");
+        }
+        if self.read_byte()? != header::FORMAT {
+            return Err("format mismatch");
+        }
+        if self.read_bytes(6)? != header::LUAC_DATA {
+            return Err("corrupted chunk");
+        }
+        if self.read_byte()? != header::CINT_SIZE {
+            return Err("int size mismatch");
+        }
+        if self.read_byte()? != header::SIZET_SIZE {
+            return Err("size_t size mismatch");
+        }
+        if self.read_byte()? != header::INSTRUCTION_SIZE {
+            return Err("instruction size mismatch");
+        }
+        if self.read_byte()? != header::LUA_INT_SIZE {
+            return Err("lua_Integer size mismatch");
+        }
+        if self.read_byte()? != header::LUA_NUM_SIZE {
+            return Err("lua_Number size mismatch");
+        }
+        if self.read_lua_integer()? != header::LUAC_INT {
+            return Err("endianness mismatch");
+        }
+        if self.read_lua_number()? != header::LUAC_NUM {
+            return Err("float format mismatch");
+        }
+        Ok(())
     }
 
-}
\ No newline at end of file
+}
@@ -0,0 +1,5 @@
+pub mod binary_chunk;
+pub mod vm;
+pub mod state;
+pub mod api;
+pub mod stdlib;
@@ -1,72 +1,47 @@
-mod binary_chunk;
-mod vm;
-mod state;
-mod api;
-use crate::api::api_stack::LuaAPI;
-use crate::api::consts::*;
-use crate::state::lua_state::LuaState;
-use crate::binary_chunk::prototype::Prototype;
-use crate::vm::instruction::*;
-use crate::api::api_vm::*;
+use lua_compiler::stdlib;
+use lua_compiler::api::api_stack::LuaAPI;
+use lua_compiler::state::lua_state::LuaState;
+use lua_compiler::state::lua_error::LuaResult;
+use lua_compiler::api::consts::*;
+use std::env;
+use std::process;
 use std::fs::File;
 use std::io;
 use std::io::prelude::*;
 
-fn lua_main(proto: Prototype) {
-    let n_regs = proto.max_stack_size as usize;
-    let mut ls = LuaState::new(n_regs, proto);
-    ls.set_top(n_regs as isize);
-    // while
-    loop {
-        let pc = ls.pc();
-        let inst = ls.fetch();
-        // 38 is return
-        if inst.opcode() != 38 {
-            inst.execute(&mut ls);
-            print!("[{:02}] {}", pc + 1, inst.opname());
-            print_stack(&ls);
-            println!("");
-        } else {
-            break;
-        }
-    }
-
-}
-
-fn undump(data: Vec<u8>) {
-    let mut r = binary_chunk::reader::Reader::new(data);
-    r.check_header();
-    r.read_byte(); // size_upvalues
-    let result = r.read_proto(String::from(""));
-    // print
-    r.print_content(&result);
-}
-
-fn print_stack(cur_state: &LuaState) {
-    let top = cur_state.get_top() + 1;
-    for i in 1..top {
-        let type_id = cur_state.type_id(i);
-        match type_id {
-            LUA_TBOOLEAN => print!("[{}]", cur_state.to_boolean(i)),
-            LUA_TNUMBER => print!("[{}]", cur_state.to_number(i)),
-            LUA_TSTRING=> print!("[\"{}\"]", cur_state.to_string(i)),
-            _ => print!("[{}]", cur_state.type_name(type_id))
-        }
-    }
-    println!("");
+// append a traceback to the error message, as the standalone interpreter does
+fn msg_handler(ls: &mut LuaState) -> LuaResult<usize> {
+    let (msg, is_string) = ls.to_stringx(1);
+    let msg = if is_string {
+        msg
+    } else {
+        format!("(error object is a {} value)", ls.type_name(ls.type_id(1)))
+    };
+    let s = ls.traceback(Some(&msg), 1);
+    ls.push_string(s);
+    Ok(1)
 }
 
 fn main() -> io::Result<()> {
-    let mut file = File::open("./tests/test.out")?;
+    let file_name = env::args().nth(1).unwrap_or_else(|| String::from("./tests/test.out"));
+    let mut file = File::open(file_name)?;
     let mut data = Vec::new();
     file.read_to_end(&mut data)?;
-    // undump
-    let mut r = binary_chunk::reader::Reader::new(data);
-    r.check_header();
-    r.read_byte(); // size_upvalues
-    let result = r.read_proto(String::from(""));
-    // r.print_content(&result);
-    lua_main(result);
+
+    let mut ls = LuaState::new();
+    stdlib::open_libs(&mut ls);
+    // run the chunk with a traceback handler below it
+    ls.push_rust_function(msg_handler);
+    let status = match ls.load(data) {
+        LUA_OK => ls.pcall(0, 0, 1),
+        status => status,
+    };
+    if status != LUA_OK {
+        eprintln!("lua: {}", ls.to_string(-1));
+        ls.close();
+        process::exit(1);
+    }
+    ls.close();
 
     Ok(())
 }
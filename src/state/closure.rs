@@ -0,0 +1,61 @@
+use crate::binary_chunk::prototype::Prototype;
+use crate::state::lua_state::LuaState;
+use crate::state::lua_value::LuaValue;
+use crate::state::lua_error::LuaResult;
+use crate::state::gc::{Gc, GcPtr, Heap, Trace};
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::mem;
+
+pub type RustFn = fn(&mut LuaState) -> LuaResult<usize>;
+// what is left of a Rust function after a call it made yielded; it gets
+// the status of that call and the context it was given (see lua_KFunction)
+pub type KFunction = fn(&mut LuaState, u8, isize) -> LuaResult<usize>;
+
+pub struct Closure {
+    pub proto: Option<Rc<Prototype>>,
+    pub rust_fn: Option<RustFn>,
+    pub upvals: Vec<Gc<RefCell<LuaValue>>>,
+}
+
+impl Closure {
+    pub fn new_lua_closure(heap: &mut Heap, proto: Rc<Prototype>) -> Closure {
+        let n_upvals = proto.up_values.len();
+        Closure {
+            proto: Some(proto),
+            rust_fn: None,
+            upvals: new_upvals(heap, n_upvals),
+        }
+    }
+
+    pub fn new_rust_closure(heap: &mut Heap, f: RustFn, n_upvals: usize) -> Closure {
+        Closure {
+            proto: None,
+            rust_fn: Some(f),
+            upvals: new_upvals(heap, n_upvals),
+        }
+    }
+}
+
+impl Trace for Closure {
+    fn trace(&self, gray: &mut Vec<GcPtr>) {
+        if let Some(proto) = &self.proto {
+            proto.trace(gray);
+        }
+        for uv in self.upvals.iter() {
+            uv.mark(gray);
+        }
+    }
+
+    fn extra_size(&self) -> usize {
+        self.upvals.capacity() * mem::size_of::<Gc<RefCell<LuaValue>>>()
+    }
+}
+
+fn new_upvals(heap: &mut Heap, n: usize) -> Vec<Gc<RefCell<LuaValue>>> {
+    let mut upvals = Vec::with_capacity(n);
+    for _ in 0..n {
+        upvals.push(heap.alloc(RefCell::new(LuaValue::Nil)));
+    }
+    upvals
+}
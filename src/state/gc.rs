@@ -0,0 +1,481 @@
+use crate::state::lua_value::LuaValue;
+use crate::state::lua_string::StringTable;
+use std::cell::{Cell, Ref, RefCell, RefMut};
+use std::collections::VecDeque;
+use std::fmt;
+use std::mem;
+use std::ops::Deref;
+use std::ptr::NonNull;
+use std::rc::Rc;
+
+// collector states
+const GCS_PAUSE: u8 = 0; // waiting to start a cycle
+const GCS_PROPAGATE: u8 = 1; // marking, a few gray objects per step
+const GCS_SWEEP: u8 = 2; // freeing white objects, a few per step
+
+// object colors
+const WHITE: u8 = 0; // not reached (yet)
+const GRAY: u8 = 1; // reached, children not traced
+const BLACK: u8 = 2; // reached and traced
+
+pub const GCPAUSE: usize = 200; // start a cycle when memory doubles
+pub const GCSTEPMUL: usize = 200; // work twice as fast as the program allocates
+const GCSTEPSIZE: usize = 8 * 1024; // bytes allocated between steps
+const GCSWEEPCOST: usize = 16; // work units to sweep one object
+
+// objects to trace again before the sweep, shared by the heap and its objects
+type GrayList = Rc<RefCell<Vec<GcPtr>>>;
+
+pub struct GcHeader {
+    color: Cell<u8>,
+    // waiting for its finalizer to be called
+    fin: Cell<bool>,
+    // where the write barrier puts the object, None for Local objects
+    gray_again: Option<GrayList>,
+}
+
+impl GcHeader {
+    pub fn is_white(&self) -> bool {
+        self.color.get() == WHITE
+    }
+}
+
+pub struct GcBox<T: ?Sized> {
+    header: GcHeader,
+    value: T,
+}
+
+pub type GcPtr = NonNull<GcBox<dyn Trace>>;
+
+// objects the collector can walk; `trace` marks every object directly
+// reachable from this one
+pub trait Trace {
+    fn trace(&self, gray: &mut Vec<GcPtr>);
+
+    // memory owned besides the object itself
+    fn extra_size(&self) -> usize {
+        0
+    }
+
+    // weak objects do not mark everything they refer to; they are traced
+    // again once marking is done and then drop what was not reached
+    fn is_weak(&self) -> bool {
+        false
+    }
+
+    // drop references to objects that were not reached; dead keys only
+    // go when `keys` is set
+    fn clear_dead(&mut self, _keys: bool) {}
+}
+
+impl<T: Trace> Trace for RefCell<T> {
+    fn trace(&self, gray: &mut Vec<GcPtr>) {
+        self.borrow().trace(gray);
+    }
+
+    fn extra_size(&self) -> usize {
+        self.borrow().extra_size()
+    }
+
+    fn is_weak(&self) -> bool {
+        self.borrow().is_weak()
+    }
+
+    fn clear_dead(&mut self, keys: bool) {
+        self.get_mut().clear_dead(keys);
+    }
+}
+
+// handle to an object owned by the Heap; it stays valid as long as the
+// object is reachable from the roots the LuaState gives the collector
+pub struct Gc<T: ?Sized> {
+    ptr: NonNull<GcBox<T>>,
+}
+
+impl<T: ?Sized> Clone for Gc<T> {
+    fn clone(&self) -> Gc<T> {
+        *self
+    }
+}
+
+impl<T: ?Sized> Copy for Gc<T> {}
+
+impl<T: ?Sized> Deref for Gc<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &self.ptr.as_ref().value }
+    }
+}
+
+impl<T: ?Sized + fmt::Display> fmt::Display for Gc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<T: ?Sized> Gc<T> {
+    pub fn ptr_eq(a: &Gc<T>, b: &Gc<T>) -> bool {
+        a.ptr.as_ptr() as *const u8 == b.ptr.as_ptr() as *const u8
+    }
+
+    pub fn as_ptr(this: &Gc<T>) -> *const u8 {
+        this.ptr.as_ptr() as *const u8
+    }
+
+    pub fn header(&self) -> &GcHeader {
+        unsafe { &self.ptr.as_ref().header }
+    }
+
+    // a reference that outlives the handle
+    // SAFETY: the object must stay reachable for 'a, e.g. from a stack slot
+    // that cannot change while the LuaState is borrowed
+    pub(crate) unsafe fn as_ref_unchecked<'a>(self) -> &'a T {
+        &(*self.ptr.as_ptr()).value
+    }
+}
+
+impl<T: Trace + 'static> Gc<T> {
+    pub fn mark(&self, gray: &mut Vec<GcPtr>) {
+        let color = &self.header().color;
+        if color.get() == WHITE {
+            color.set(GRAY);
+            gray.push(self.ptr);
+        }
+    }
+}
+
+impl<T: Trace + 'static> Gc<RefCell<T>> {
+    pub fn borrow(&self) -> Ref<'_, T> {
+        (**self).borrow()
+    }
+
+    // a traced object that changes must be traced again before the sweep
+    // (see luaC_barrierback)
+    pub fn borrow_mut(&self) -> RefMut<'_, T> {
+        let header = self.header();
+        if header.color.get() == BLACK {
+            header.color.set(GRAY);
+            if let Some(list) = &header.gray_again {
+                let p: GcPtr = self.ptr;
+                list.borrow_mut().push(p);
+            }
+        }
+        (**self).borrow_mut()
+    }
+}
+
+// an object the heap does not own, for short-lived keys; it is never
+// collected and its handles must not outlive it
+pub struct Local<T> {
+    b: GcBox<T>,
+}
+
+impl<T> Local<T> {
+    pub fn new(value: T) -> Local<T> {
+        let header = GcHeader { color: Cell::new(BLACK), fin: Cell::new(false), gray_again: None };
+        Local { b: GcBox { header, value } }
+    }
+
+    pub fn handle(&self) -> Gc<T> {
+        Gc { ptr: NonNull::from(&self.b) }
+    }
+}
+
+fn object_size(p: GcPtr) -> usize {
+    let obj = unsafe { p.as_ref() };
+    mem::size_of_val(obj) + obj.value.extra_size()
+}
+
+pub struct Heap {
+    objects: Vec<GcPtr>,
+    gray: Vec<GcPtr>,
+    gray_again: GrayList, // objects changed after being traced
+    weak: Vec<GcPtr>, // weak objects reached in this cycle
+    finobj: Vec<LuaValue>, // objects with a finalizer
+    tobefnz: VecDeque<LuaValue>, // unreachable objects whose finalizer is due
+    pub strt: StringTable,
+    state: u8,
+    sweep_pos: usize,
+    total: usize, // estimated bytes in use
+    live: usize, // bytes kept by the running sweep
+    threshold: usize, // run a step once `total` gets here
+    pub running: bool,
+    pub pause: usize,
+    pub stepmul: usize,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Heap {
+    pub fn new() -> Heap {
+        Heap {
+            objects: Vec::new(),
+            gray: Vec::new(),
+            gray_again: Rc::new(RefCell::new(Vec::new())),
+            weak: Vec::new(),
+            finobj: Vec::new(),
+            tobefnz: VecDeque::new(),
+            strt: StringTable::new(),
+            state: GCS_PAUSE,
+            sweep_pos: 0,
+            total: 0,
+            live: 0,
+            threshold: GCSTEPSIZE,
+            running: true,
+            pause: GCPAUSE,
+            stepmul: GCSTEPMUL,
+        }
+    }
+
+    pub fn alloc<T: Trace + 'static>(&mut self, value: T) -> Gc<T> {
+        // objects created while marking are traced in this cycle, and
+        // objects created while sweeping survive it
+        let color = match self.state {
+            GCS_PAUSE => WHITE,
+            GCS_PROPAGATE => GRAY,
+            _ => BLACK,
+        };
+        let header = GcHeader {
+            color: Cell::new(color),
+            fin: Cell::new(false),
+            gray_again: Some(Rc::clone(&self.gray_again)),
+        };
+        let b = Box::new(GcBox { header, value });
+        let ptr = unsafe { NonNull::new_unchecked(Box::into_raw(b)) };
+        let p: GcPtr = ptr;
+        if color == GRAY {
+            self.gray.push(p);
+        }
+        self.objects.push(p);
+        self.total += object_size(p);
+        Gc { ptr }
+    }
+
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.state == GCS_PAUSE
+    }
+
+    pub fn needs_step(&self) -> bool {
+        self.running && self.total >= self.threshold
+    }
+
+    // pretend `n` more bytes were allocated
+    pub fn add_debt(&mut self, n: usize) {
+        self.threshold = self.threshold.saturating_sub(n);
+    }
+
+    // `val` just got a metatable with a __gc field
+    pub fn check_finalizer(&mut self, val: &LuaValue) {
+        if let Some(h) = val.gc_header() {
+            if !h.fin.get() {
+                h.fin.set(true);
+                self.finobj.push(*val);
+            }
+        }
+    }
+
+    // next object to finalize, it is a normal object again afterwards
+    pub fn pop_tobefnz(&mut self) -> Option<LuaValue> {
+        let val = self.tobefnz.pop_front()?;
+        if let Some(h) = val.gc_header() {
+            h.fin.set(false);
+        }
+        Some(val)
+    }
+
+    pub fn has_tobefnz(&self) -> bool {
+        !self.tobefnz.is_empty()
+    }
+
+    // when closing the state every finalizer is due
+    pub fn separate_all(&mut self) {
+        let all = mem::take(&mut self.finobj);
+        // the last one marked is finalized first
+        self.tobefnz.extend(all.into_iter().rev());
+    }
+
+    // do a bounded amount of work; `mark_roots` marks everything the
+    // program can reach without going through another object
+    pub fn step<F: Fn(&mut Vec<GcPtr>)>(&mut self, mark_roots: F) {
+        let mut work = (GCSTEPSIZE / 100 * self.stepmul) as isize;
+        while work > 0 {
+            work -= self.single_step(&mark_roots) as isize;
+            if self.state == GCS_PAUSE {
+                self.threshold = self.total / 100 * self.pause;
+                return;
+            }
+        }
+        self.threshold = self.total + GCSTEPSIZE;
+    }
+
+    // finish the running cycle, then do a complete one
+    pub fn full_gc<F: Fn(&mut Vec<GcPtr>)>(&mut self, mark_roots: F) {
+        while self.state != GCS_PAUSE {
+            self.single_step(&mark_roots);
+        }
+        loop {
+            self.single_step(&mark_roots);
+            if self.state == GCS_PAUSE {
+                break;
+            }
+        }
+        self.threshold = self.total / 100 * self.pause;
+    }
+
+    fn single_step<F: Fn(&mut Vec<GcPtr>)>(&mut self, mark_roots: &F) -> usize {
+        match self.state {
+            GCS_PAUSE => {
+                // left over from changes during the last sweep
+                self.gray_again.borrow_mut().clear();
+                mark_roots(&mut self.gray);
+                self.mark_tobefnz();
+                self.state = GCS_PROPAGATE;
+                1
+            },
+            GCS_PROPAGATE => {
+                if let Some(p) = self.gray.pop() {
+                    return self.propagate(p);
+                }
+                self.atomic(mark_roots);
+                self.state = GCS_SWEEP;
+                self.sweep_pos = 0;
+                self.live = 0;
+                1
+            },
+            _ => {
+                if self.sweep_pos < self.objects.len() {
+                    self.sweep_one();
+                    return GCSWEEPCOST;
+                }
+                self.total = self.live;
+                self.state = GCS_PAUSE;
+                1
+            },
+        }
+    }
+
+    fn mark_tobefnz(&mut self) {
+        for val in self.tobefnz.iter() {
+            val.trace(&mut self.gray);
+        }
+    }
+
+    fn propagate(&mut self, p: GcPtr) -> usize {
+        let obj = unsafe { p.as_ref() };
+        if obj.header.color.get() == BLACK {
+            return 0;
+        }
+        obj.header.color.set(BLACK);
+        obj.value.trace(&mut self.gray);
+        if obj.value.is_weak() {
+            self.weak.push(p);
+        }
+        object_size(p)
+    }
+
+    // the roots are not watched, so mark them again, then finish marking;
+    // unreachable objects with a finalizer are kept for one more cycle
+    fn atomic<F: Fn(&mut Vec<GcPtr>)>(&mut self, mark_roots: &F) {
+        mark_roots(&mut self.gray);
+        self.mark_tobefnz();
+        self.converge();
+        // weak values pointing to objects about to be finalized are cleared,
+        // weak keys (and ephemeron values) only once the object is really gone
+        self.clear_weak(false);
+        let (dead, live) = mem::take(&mut self.finobj)
+            .into_iter()
+            .partition(|val: &LuaValue| val.is_white());
+        self.finobj = live;
+        self.tobefnz.extend(dead.into_iter().rev());
+        self.mark_tobefnz();
+        self.converge();
+        self.clear_weak(true);
+        self.weak.clear();
+        self.strt.clear_white();
+    }
+
+    // mark until nothing is gray; objects changed after being traced and
+    // ephemeron values whose keys got marked wait on the gray-again list
+    fn converge(&mut self) {
+        loop {
+            while let Some(p) = self.gray.pop() {
+                self.propagate(p);
+            }
+            let mut again = self.gray_again.borrow_mut();
+            if again.is_empty() {
+                for &p in self.weak.iter() {
+                    unsafe { p.as_ref() }.value.trace(&mut again);
+                }
+            }
+            if again.is_empty() {
+                break;
+            }
+            mem::swap(&mut self.gray, &mut again);
+        }
+    }
+
+    fn clear_weak(&mut self, keys: bool) {
+        for &p in self.weak.iter() {
+            // nothing else is borrowed while the collector runs
+            unsafe { (*p.as_ptr()).value.clear_dead(keys) };
+        }
+    }
+
+    fn sweep_one(&mut self) {
+        let p = self.objects[self.sweep_pos];
+        let obj = unsafe { p.as_ref() };
+        if obj.header.color.get() == WHITE {
+            self.total = self.total.saturating_sub(object_size(p));
+            self.objects.swap_remove(self.sweep_pos);
+            unsafe { drop(Box::from_raw(p.as_ptr())) };
+        } else {
+            obj.header.color.set(WHITE);
+            self.live += object_size(p);
+            self.sweep_pos += 1;
+        }
+    }
+}
+
+impl Drop for Heap {
+    fn drop(&mut self) {
+        self.finobj.clear();
+        self.tobefnz.clear();
+        for p in self.objects.drain(..) {
+            unsafe { drop(Box::from_raw(p.as_ptr())) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::lua_table::LuaTable;
+
+    #[test]
+    fn barrier_keeps_objects_stored_after_tracing() {
+        let mut heap = Heap::new();
+        let loose = heap.alloc(RefCell::new(LuaTable::new(0, 0)));
+        let root = heap.alloc(RefCell::new(LuaTable::new(0, 0)));
+        let mark_roots = |gray: &mut Vec<GcPtr>| root.mark(gray);
+        heap.single_step(&mark_roots);
+        heap.single_step(&mark_roots);
+        assert_eq!(root.header().color.get(), BLACK);
+
+        root.borrow_mut().put(LuaValue::Int64(1), LuaValue::Table(loose)).unwrap();
+        assert_eq!(heap.gray_again.borrow().len(), 1);
+        while heap.state != GCS_PAUSE {
+            heap.single_step(&mark_roots);
+        }
+        assert!(heap.gray_again.borrow().is_empty());
+        assert_eq!(heap.objects.len(), 2);
+    }
+}
@@ -0,0 +1,39 @@
+use crate::state::lua_value::LuaValue;
+use crate::state::gc::Heap;
+use crate::api::consts::*;
+
+// a raised Lua error, carried up to the nearest protected call; errors
+// raised from Rust keep their message until it can go on the heap
+pub struct LuaError {
+    pub status: u8,
+    value: LuaValue,
+    msg: Option<String>,
+}
+
+pub type LuaResult<T> = Result<T, LuaError>;
+
+impl LuaError {
+    pub fn new(status: u8, value: LuaValue) -> LuaError {
+        LuaError { status, value, msg: None }
+    }
+
+    pub fn message(status: u8, msg: String) -> LuaError {
+        LuaError { status, value: LuaValue::Nil, msg: Some(msg) }
+    }
+
+    pub fn runtime(msg: String) -> LuaError {
+        LuaError::message(LUA_ERRRUN, msg)
+    }
+
+    pub fn memory() -> LuaError {
+        LuaError::message(LUA_ERRMEM, String::from("not enough memory"))
+    }
+
+    // the error object
+    pub fn into_value(self, heap: &mut Heap) -> LuaValue {
+        match self.msg {
+            Some(msg) => LuaValue::new_string(heap, &msg),
+            None => self.value,
+        }
+    }
+}
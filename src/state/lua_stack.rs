@@ -1,18 +1,63 @@
 use crate::state::lua_value::LuaValue;
+use crate::state::lua_error::{LuaError, LuaResult};
+use crate::state::closure::{Closure, KFunction};
+use crate::binary_chunk::prototype::Prototype;
+use crate::api::consts::*;
+use crate::vm::instruction::Instruction_impl;
+use crate::vm::opcodes::*;
+use std::collections::HashMap;
+use crate::state::gc::{Gc, GcPtr, Heap, Trace};
+use std::cell::RefCell;
 
 pub struct LuaStack {
     vec: Vec<LuaValue>,
+    pub closure: Option<Gc<Closure>>,
+    pub varargs: Vec<LuaValue>,
+    // captured registers, synced with their cells around calls
+    pub openuvs: HashMap<isize, Gc<RefCell<LuaValue>>>,
+    pub pc: isize,
+    pub n_results: isize,
+    pub is_tail: bool, // called by a tail call, its caller's frame is gone
+    pub leq_by_lt: bool, // running __lt for a missing __le, see CIST_LEQ
+    // continuation of a Rust function and its context, set while it makes
+    // a call that may yield
+    pub k: Option<KFunction>,
+    pub ctx: isize,
+    // for a pcallk that may yield: the stack top to restore and the
+    // message handler when an error gets here after a resume
+    pub ypcall: Option<(isize, isize)>,
 }
 
 impl LuaStack {
-    pub fn new(size: usize) -> LuaStack {
+    pub fn new(size: usize, closure: Option<Gc<Closure>>) -> LuaStack {
         LuaStack {
-            vec: Vec::with_capacity(size)
+            vec: Vec::with_capacity(size),
+            closure,
+            varargs: Vec::new(),
+            openuvs: HashMap::new(),
+            pc: 0,
+            n_results: LUA_MULTRET,
+            is_tail: false,
+            leq_by_lt: false,
+            k: None,
+            ctx: 0,
+            ypcall: None,
         }
     }
 
-    pub fn check(&mut self, size: usize) {
-        self.vec.reserve(size);
+    pub fn is_lua(&self) -> bool {
+        matches!(&self.closure, Some(c) if c.proto.is_some())
+    }
+
+    pub fn proto(&self) -> &Prototype {
+        self.closure.as_ref().unwrap().proto.as_ref().unwrap()
+    }
+
+    pub fn check(&mut self, size: usize) -> bool {
+        if self.vec.len() + size > LUAI_MAXSTACK as usize {
+            return false;
+        }
+        self.vec.try_reserve(size).is_ok()
     }
 
     pub fn top(&self) -> isize {
@@ -23,47 +68,174 @@ impl LuaStack {
         self.vec.push(cur_data);
     }
 
-    pub fn pop(&mut self) -> LuaValue {
-        if self.vec.len() == 0 {
-            panic!("stack underflow!");
+    pub fn pop(&mut self) -> LuaResult<LuaValue> {
+        match self.vec.pop() {
+            Some(val) => Ok(val),
+            None => Err(LuaError::runtime(String::from("stack underflow"))),
         }
-        return self.vec.pop().unwrap();
+    }
+
+    pub fn push_n(&mut self, vals: Vec<LuaValue>, n: isize) {
+        let n = if n < 0 { vals.len() } else { n as usize };
+        let mut it = vals.into_iter();
+        for _ in 0..n {
+            self.vec.push(it.next().unwrap_or(LuaValue::Nil));
+        }
+    }
+
+    pub fn truncate(&mut self, n: usize) {
+        self.vec.truncate(n);
+    }
+
+    pub fn pop_n(&mut self, n: usize) -> LuaResult<Vec<LuaValue>> {
+        if n > self.vec.len() {
+            return Err(LuaError::runtime(String::from("stack underflow")));
+        }
+        let at = self.vec.len() - n;
+        Ok(self.vec.split_off(at))
     }
 
     pub fn abs_index(&self, idx: isize) -> isize {
-        if idx > 0 {
+        if idx > 0 || idx <= LUA_REGISTRYINDEX {
             return idx;
         }
         self.top() + 1 + idx
     }
 
     pub fn is_valid(&self, idx: isize) -> bool {
+        if idx < LUA_REGISTRYINDEX {
+            let uv_idx = (LUA_REGISTRYINDEX - idx - 1) as usize;
+            return match &self.closure {
+                Some(c) => uv_idx < c.upvals.len(),
+                None => false,
+            };
+        }
         let cur_abs_idx = self.abs_index(idx);
         cur_abs_idx > 0 && cur_abs_idx <= self.top()
     }
 
     pub fn get(&self, idx: isize) -> LuaValue {
+        if idx < LUA_REGISTRYINDEX {
+            let uv_idx = (LUA_REGISTRYINDEX - idx - 1) as usize;
+            if let Some(c) = &self.closure {
+                if uv_idx < c.upvals.len() {
+                    return *c.upvals[uv_idx].borrow();
+                }
+            }
+            return LuaValue::Nil;
+        }
         if self.is_valid(idx) {
             let cur_abs_idx = self.abs_index(idx) as usize - 1;
-            return self.vec[cur_abs_idx].clone();
+            return self.vec[cur_abs_idx];
         }
         LuaValue::Nil
     }
 
-    pub fn set(&mut self, idx: isize, val: LuaValue) {
-        if self.is_valid(idx) {
+    pub fn set(&mut self, idx: isize, val: LuaValue) -> LuaResult<()> {
+        if idx < LUA_REGISTRYINDEX {
+            let uv_idx = (LUA_REGISTRYINDEX - idx - 1) as usize;
+            if let Some(c) = &self.closure {
+                if uv_idx < c.upvals.len() {
+                    *c.upvals[uv_idx].borrow_mut() = val;
+                    return Ok(());
+                }
+            }
+        } else if self.is_valid(idx) {
             let cur_abs_idx = self.abs_index(idx) as usize - 1;
             self.vec[cur_abs_idx] = val;
-            return;
+            return Ok(());
         }
-        panic!("invalid idx!")
+        Err(LuaError::runtime(String::from("invalid index")))
     }
 
-    pub fn reverse(&mut self, mut from: usize, mut to: usize) {
+    // registers of a Lua function, 0-based
+    pub fn reg(&self, r: usize) -> LuaValue {
+        self.vec[r]
+    }
+
+    pub fn set_reg(&mut self, r: usize, val: LuaValue) {
+        self.vec[r] = val;
+    }
+
+    // `to` may be below `from` for an empty range
+    pub fn reverse(&mut self, mut from: isize, mut to: isize) {
         while from < to {
-            self.vec.swap(from, to);
+            self.vec.swap(from as usize, to as usize);
             from += 1;
             to -= 1;
         }
     }
-}
\ No newline at end of file
+
+    // share register `idx` (0-based) with a new closure
+    pub fn capture(&mut self, idx: isize, heap: &mut Heap) -> Gc<RefCell<LuaValue>> {
+        let val = self.vec[idx as usize];
+        let cell = *self.openuvs
+            .entry(idx)
+            .or_insert_with(|| heap.alloc(RefCell::new(LuaValue::Nil)));
+        *cell.borrow_mut() = val;
+        cell
+    }
+
+    // copy captured registers into their cells before other code can see them
+    pub fn sync_upvalues(&self) {
+        for (idx, cell) in self.openuvs.iter() {
+            *cell.borrow_mut() = self.vec[*idx as usize];
+        }
+    }
+
+    // pick up writes made through the cells while another frame was running
+    pub fn reload_upvalues(&mut self) {
+        for (idx, cell) in self.openuvs.iter() {
+            self.vec[*idx as usize] = *cell.borrow();
+        }
+    }
+
+    // registers above those the current instruction still uses hold
+    // garbage; clear them so the collector does not keep it alive
+    pub fn clear_dead_registers(&mut self) {
+        match &self.closure {
+            Some(c) if c.proto.is_some() && self.pc > 0 => {},
+            _ => return,
+        }
+        let i = self.proto().code[self.pc as usize - 1];
+        let (a, b, _) = i.ABC();
+        let limit = match i.opcode() {
+            OP_CALL | OP_TAILCALL => a,
+            OP_TFORCALL => a + 3,
+            OP_NEWTABLE | OP_CLOSURE => a + 1,
+            OP_CONCAT if a < b => b,
+            OP_CONCAT => a + 1,
+            _ => return,
+        };
+        let n_regs = self.proto().max_stack_size as usize;
+        for val in self.vec.iter_mut().take(n_regs).skip(limit as usize) {
+            *val = LuaValue::Nil;
+        }
+    }
+
+    pub fn close_upvalues(&mut self, from: isize) {
+        let vec = &self.vec;
+        self.openuvs.retain(|idx, cell| {
+            if *idx >= from {
+                *cell.borrow_mut() = vec[*idx as usize];
+                false
+            } else {
+                true
+            }
+        });
+    }
+}
+
+impl Trace for LuaStack {
+    fn trace(&self, gray: &mut Vec<GcPtr>) {
+        for val in self.vec.iter().chain(self.varargs.iter()) {
+            val.trace(gray);
+        }
+        if let Some(c) = &self.closure {
+            c.mark(gray);
+        }
+        for cell in self.openuvs.values() {
+            cell.mark(gray);
+        }
+    }
+}
@@ -1,18 +1,125 @@
 use crate::state::lua_stack::LuaStack;
-use crate::binary_chunk::prototype::Prototype;
+use crate::state::lua_value::LuaValue;
+use crate::state::lua_error::LuaResult;
+use crate::state::lua_thread::LuaThread;
+use crate::state::gc::{Gc, GcPtr, Heap, Trace};
+use crate::api::consts::*;
+use crate::api::api_cmp::{self, StrOrder};
+use std::cell::RefCell;
+use std::mem;
 
 pub struct LuaState {
+    pub heap: Heap,
+    pub registry: LuaValue,
     pub stack: LuaStack,
-    pub proto: Prototype,
-    pub pc: isize,
+    pub frames: Vec<LuaStack>,
+    pub n_ccalls: usize,
+    // the running thread, its call stack is `stack` and `frames`
+    pub thread: Gc<RefCell<LuaThread>>,
+    // number of non-yieldable calls in the running thread
+    pub nny: usize,
+    // how strings order, api_cmp::str_coll to follow the locale
+    pub str_order: StrOrder,
+}
+
+impl Default for LuaState {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl LuaState {
-    pub fn new(size: usize, proto: Prototype) -> LuaState {
+    pub fn new() -> LuaState {
+        let mut heap = Heap::new();
+        let main_thread = heap.alloc(RefCell::new(LuaThread::new()));
+        let registry = LuaValue::new_table(&mut heap, 0, 0);
+        let globals = LuaValue::new_table(&mut heap, 0, 0);
+        if let LuaValue::Table(t) = &registry {
+            let mut t = t.borrow_mut();
+            t.put(LuaValue::Int64(LUA_RIDX_MAINTHREAD), LuaValue::Thread(main_thread)).unwrap();
+            t.put(LuaValue::Int64(LUA_RIDX_GLOBALS), globals).unwrap();
+        }
         LuaState {
-            stack: LuaStack::new(size),
-            proto: proto,
-            pc: 0,
+            heap,
+            registry,
+            stack: LuaStack::new(LUA_MINSTACK, None),
+            frames: Vec::new(),
+            n_ccalls: 0,
+            thread: main_thread,
+            nny: 1, // the main thread cannot yield
+            str_order: api_cmp::str_cmp,
+        }
+    }
+
+    pub fn registry_get(&self, key: i64) -> LuaValue {
+        if let LuaValue::Table(t) = &self.registry {
+            return t.borrow().get(&LuaValue::Int64(key));
         }
+        LuaValue::Nil
     }
-}
\ No newline at end of file
+
+    // a stack index, an upvalue index or LUA_REGISTRYINDEX (see index2addr)
+    pub fn get_value(&self, idx: isize) -> LuaValue {
+        if idx == LUA_REGISTRYINDEX {
+            return self.registry;
+        }
+        self.stack.get(idx)
+    }
+
+    pub fn set_value(&mut self, idx: isize, val: LuaValue) -> LuaResult<()> {
+        if idx == LUA_REGISTRYINDEX {
+            self.registry = val;
+            return Ok(());
+        }
+        self.stack.set(idx, val)
+    }
+
+    pub fn is_valid_index(&self, idx: isize) -> bool {
+        idx == LUA_REGISTRYINDEX || self.stack.is_valid(idx)
+    }
+
+    pub fn push_frame(&mut self, frame: LuaStack) {
+        let caller = mem::replace(&mut self.stack, frame);
+        self.frames.push(caller);
+    }
+
+    pub fn pop_frame(&mut self) -> LuaStack {
+        let caller = self.frames.pop().unwrap();
+        mem::replace(&mut self.stack, caller)
+    }
+
+    // park the running thread's call stack and run `to` instead
+    pub fn switch_thread(&mut self, to: Gc<RefCell<LuaThread>>) -> Gc<RefCell<LuaThread>> {
+        {
+            let mut parked = self.thread.borrow_mut();
+            let mut next = to.borrow_mut();
+            let stack = mem::replace(&mut next.stack, LuaStack::new(0, None));
+            parked.stack = mem::replace(&mut self.stack, stack);
+            parked.frames = mem::replace(&mut self.frames, mem::take(&mut next.frames));
+        }
+        mem::replace(&mut self.thread, to)
+    }
+
+    // the roots: the registry, and the running thread with its call stack;
+    // parked threads are reachable through the values that resumed them
+    pub fn collect(&mut self, full: bool) {
+        self.stack.clear_dead_registers();
+        for frame in self.frames.iter_mut() {
+            frame.clear_dead_registers();
+        }
+        let LuaState { heap, registry, stack, frames, thread, .. } = self;
+        let mark_roots = |gray: &mut Vec<GcPtr>| {
+            registry.trace(gray);
+            thread.mark(gray);
+            stack.trace(gray);
+            for frame in frames.iter() {
+                frame.trace(gray);
+            }
+        };
+        if full {
+            heap.full_gc(mark_roots);
+        } else {
+            heap.step(mark_roots);
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use crate::state::gc::{Gc, GcPtr, Heap, Local, Trace};
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Deref;
+
+// strings up to this length are interned
+pub const LUAI_MAXSHORTLEN: usize = 40;
+
+// immutable string owned by the heap; short ones are interned, so equal
+// short strings are the same object
+pub struct TString {
+    hash: u64,
+    s: Box<str>,
+}
+
+// see luaS_hash
+fn str_hash(s: &str) -> u64 {
+    let mut h = 0x2545F491 ^ s.len() as u64;
+    for &b in s.as_bytes().iter().rev() {
+        h ^= (h << 5).wrapping_add(h >> 2).wrapping_add(b as u64);
+    }
+    h
+}
+
+impl TString {
+    pub fn new(heap: &mut Heap, s: &str) -> Gc<TString> {
+        let hash = str_hash(s);
+        if s.len() > LUAI_MAXSHORTLEN {
+            return heap.alloc(TString { hash, s: Box::from(s) });
+        }
+        if let Some(ts) = heap.strt.find(hash, s) {
+            return ts;
+        }
+        let ts = heap.alloc(TString { hash, s: Box::from(s) });
+        heap.strt.insert(ts);
+        ts
+    }
+
+    pub fn from_string(heap: &mut Heap, s: String) -> Gc<TString> {
+        if s.len() <= LUAI_MAXSHORTLEN {
+            return TString::new(heap, &s);
+        }
+        // long strings take over the buffer
+        let hash = str_hash(&s);
+        heap.alloc(TString { hash, s: s.into_boxed_str() })
+    }
+
+    // a string outside the heap, to look up a key without allocating it
+    pub fn local(s: &str) -> Local<TString> {
+        Local::new(TString { hash: str_hash(s), s: Box::from(s) })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.s
+    }
+
+    pub fn hash(&self) -> u64 {
+        self.hash
+    }
+
+    pub fn eq(a: &Gc<TString>, b: &Gc<TString>) -> bool {
+        Gc::ptr_eq(a, b) || (a.hash == b.hash && a.s == b.s)
+    }
+}
+
+impl Trace for TString {
+    fn trace(&self, _gray: &mut Vec<GcPtr>) {}
+
+    fn extra_size(&self) -> usize {
+        self.s.len()
+    }
+}
+
+impl Deref for TString {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.s
+    }
+}
+
+impl fmt::Display for TString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.s)
+    }
+}
+
+// interned short strings by hash; the collector drops the dead ones
+pub struct StringTable {
+    buckets: HashMap<u64, Vec<Gc<TString>>>,
+}
+
+impl Default for StringTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StringTable {
+    pub fn new() -> StringTable {
+        StringTable { buckets: HashMap::new() }
+    }
+
+    fn find(&self, hash: u64, s: &str) -> Option<Gc<TString>> {
+        let bucket = self.buckets.get(&hash)?;
+        bucket.iter().find(|ts| &*ts.s == s).copied()
+    }
+
+    fn insert(&mut self, ts: Gc<TString>) {
+        self.buckets.entry(ts.hash).or_default().push(ts);
+    }
+
+    // called once marking is done, before the sweep frees white strings
+    pub fn clear_white(&mut self) {
+        self.buckets.retain(|_, bucket| {
+            bucket.retain(|ts| !ts.header().is_white());
+            !bucket.is_empty()
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::lua_table::LuaTable;
+    use crate::state::lua_value::LuaValue;
+
+    #[test]
+    fn short_strings_are_interned() {
+        let mut heap = Heap::new();
+        let a = TString::new(&mut heap, "name");
+        let b = TString::from_string(&mut heap, String::from("name"));
+        assert!(Gc::ptr_eq(&a, &b));
+        assert!(!Gc::ptr_eq(&a, &TString::new(&mut heap, "names")));
+
+        let max = "x".repeat(LUAI_MAXSHORTLEN);
+        assert!(Gc::ptr_eq(&TString::new(&mut heap, &max), &TString::new(&mut heap, &max)));
+    }
+
+    #[test]
+    fn long_strings_are_equal_by_content() {
+        let mut heap = Heap::new();
+        let long = "x".repeat(LUAI_MAXSHORTLEN + 1);
+        let a = TString::new(&mut heap, &long);
+        let b = TString::from_string(&mut heap, long);
+        assert!(!Gc::ptr_eq(&a, &b));
+        assert!(TString::eq(&a, &b));
+        assert_eq!(a.hash(), b.hash());
+
+        // either copy finds the key the other one stored
+        let mut t = LuaTable::new(0, 0);
+        t.put(LuaValue::LuaString(a), LuaValue::Int64(1)).unwrap();
+        assert!(t.get(&LuaValue::LuaString(b)) == LuaValue::Int64(1));
+    }
+
+    #[test]
+    fn dead_strings_leave_the_table() {
+        let mut heap = Heap::new();
+        let kept = TString::new(&mut heap, "kept");
+        TString::new(&mut heap, "dropped");
+        heap.full_gc(|gray: &mut Vec<GcPtr>| kept.mark(gray));
+        assert!(heap.strt.find(str_hash("dropped"), "dropped").is_none());
+        let again = TString::new(&mut heap, "kept");
+        assert!(Gc::ptr_eq(&kept, &again));
+    }
+}
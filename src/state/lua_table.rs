@@ -1,122 +1,571 @@
+use crate::state::lua_string::TString;
 use crate::state::lua_value::{LuaValue, float_to_integer};
-use std::hash::{Hash, Hasher};
 use std::collections::HashMap;
+use crate::state::gc::{Gc, GcPtr, Trace};
+use std::cell::RefCell;
+use std::mem;
 
+// bits of the largest array part
+const MAXABITS: usize = 31;
+const MAXASIZE: usize = 1 << MAXABITS;
+
+// a Lua 5.3 table: an array part for the keys 1..n, which may hold nils,
+// and a hash part for the rest; both are resized together by `rehash`
+// when a new key finds the hash part full
 #[derive(Clone)]
 pub struct LuaTable {
     arr: Vec<LuaValue>,
-    map: HashMap<LuaValue, LuaValue>,
-    rdm: usize, // hash code
+    // hash part: keys keep their slot in `entries` until the next rehash,
+    // so `next` can continue from a field that was set to nil; a collected
+    // weak key stays in `map` as a dead key, its entry is cleared
+    map: HashMap<LuaValue, usize>,
+    entries: Vec<(LuaValue, LuaValue)>,
+    hash_size: usize, // slots in `entries` before the next rehash
+    pub metatable: Option<Gc<RefCell<LuaTable>>>,
 }
 
-impl Hash for LuaTable {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.rdm.hash(state);
+// strings are values, not objects, for weak tables and are never cleared
+fn trace_ref(val: &LuaValue, weak: bool, gray: &mut Vec<GcPtr>) {
+    if !weak || matches!(val, LuaValue::LuaString(_)) {
+        val.trace(gray);
     }
 }
 
-fn random() -> usize{
-    let ptr = Box::into_raw(Box::new(123));
-    ptr as usize
+impl Trace for LuaTable {
+    fn trace(&self, gray: &mut Vec<GcPtr>) {
+        if let Some(mt) = &self.metatable {
+            mt.mark(gray);
+        }
+        let (weak_k, weak_v) = self.mode();
+        for val in self.arr.iter() {
+            trace_ref(val, weak_v, gray);
+        }
+        for (k, v) in self.entries.iter() {
+            trace_ref(k, weak_k, gray);
+            // ephemeron: a value is only reachable through a reachable key
+            trace_ref(v, weak_v || (weak_k && k.is_white()), gray);
+        }
+    }
+
+    fn extra_size(&self) -> usize {
+        self.arr.capacity() * mem::size_of::<LuaValue>()
+            + self.entries.capacity() * mem::size_of::<(LuaValue, LuaValue)>()
+            + self.map.capacity() * mem::size_of::<(LuaValue, usize)>()
+    }
+
+    fn is_weak(&self) -> bool {
+        self.mode() != (false, false)
+    }
+
+    // values are only cleared in tables with weak values, so a table
+    // with only weak keys keeps the entries of objects being finalized
+    fn clear_dead(&mut self, keys: bool) {
+        let (weak_k, weak_v) = self.mode();
+        if weak_v {
+            for val in self.arr.iter_mut() {
+                if val.is_white() {
+                    *val = LuaValue::Nil;
+                }
+            }
+        }
+        for (k, v) in self.entries.iter_mut() {
+            if keys && weak_k && k.is_white() {
+                // `map` only hashes and compares its address from now on
+                *k = LuaValue::Nil;
+                *v = LuaValue::Nil;
+            } else if weak_v && v.is_white() {
+                *v = LuaValue::Nil;
+            }
+        }
+    }
+}
+
+// floats with an integral value are the same key as the integer
+fn normalize(key: &LuaValue) -> LuaValue {
+    if let LuaValue::Float64(n) = key {
+        if let (i, true) = float_to_integer(*n) {
+            return LuaValue::Int64(i);
+        }
+    }
+    *key
+}
+
+// ceil(log2(x)), for x >= 1
+fn ceil_log2(x: usize) -> usize {
+    (usize::BITS - (x - 1).leading_zeros()) as usize
+}
+
+// index in nums[] of a key that could go to the array part
+fn count_int(key: &LuaValue, nums: &mut [usize]) -> bool {
+    match key {
+        LuaValue::Int64(i) if *i >= 1 && *i as u64 <= MAXASIZE as u64 => {
+            nums[ceil_log2(*i as usize)] += 1;
+            true
+        },
+        _ => false,
+    }
+}
+
+// the largest n, a power of 2, such that more than half of 1..n are in
+// use; `na` comes in as the number of integer keys and goes out as the
+// number of them that go to the array part (see computesizes)
+fn compute_sizes(nums: &[usize], na: &mut usize) -> usize {
+    let mut a = 0;
+    let mut n_array = 0;
+    let mut optimal = 0;
+    let mut i = 0;
+    let mut two_to_i: usize = 1;
+    while i <= MAXABITS && *na > two_to_i / 2 {
+        if nums[i] > 0 {
+            a += nums[i];
+            if a > two_to_i / 2 {
+                optimal = two_to_i;
+                n_array = a;
+            }
+        }
+        i += 1;
+        two_to_i *= 2;
+    }
+    *na = n_array;
+    optimal
 }
 
 impl LuaTable {
     pub fn new(narr: usize, nrec: usize) -> LuaTable {
         LuaTable {
-            arr: Vec::with_capacity(narr),
+            arr: vec![LuaValue::Nil; narr],
             map: HashMap::with_capacity(nrec),
-            rdm: random()
+            entries: Vec::with_capacity(nrec),
+            hash_size: nrec,
+            metatable: None,
         }
     }
 
-    fn to_index(key: &LuaValue) -> Option<usize> {
-        if let LuaValue::Int64(i) = key {
-            if *i >= 1 {
-                return Some(*i as usize);
-            }
-        } else if let LuaValue::Float64(n) = key {
-            let (i, i_ok) = float_to_integer(*n);
-            if i >= 1 && i_ok {
-                return Some(i as usize);
-            }
+    // position of an integer key in the array part
+    fn array_index(&self, key: &LuaValue) -> Option<usize> {
+        match key {
+            LuaValue::Int64(i) if *i >= 1 && *i as u64 <= self.arr.len() as u64 => Some(*i as usize - 1),
+            _ => None,
         }
-        None
     }
 
     pub fn get(&self, key: &LuaValue) -> LuaValue {
-        if let Some(idx) = LuaTable::to_index(key) {
-            if idx <= self.arr.len() {
-                return self.arr[idx - 1].clone(); // TODO
-            }
+        let key = normalize(key);
+        if let Some(idx) = self.array_index(&key) {
+            return self.arr[idx];
         }
-        if let Some(val) = self.map.get(key) {
-            val.clone() // TODO
+        if let Some(slot) = self.map.get(&key) {
+            self.entries[*slot].1
         } else {
             LuaValue::Nil
         }
     }
 
-    pub fn put(&mut self, key: LuaValue, val: LuaValue) {
+    pub fn get_int(&self, i: i64) -> LuaValue {
+        self.get(&LuaValue::Int64(i))
+    }
+
+    pub fn put(&mut self, key: LuaValue, val: LuaValue) -> Result<(), &'static str> {
         if key.is_nil() {
-            panic!("table idx is nil!!");
+            return Err("table index is nil");
         }
         if let LuaValue::Float64(n) = key {
             if n.is_nan() {
-                panic!("table index is NaN!");
+                return Err("table index is NaN");
             }
         }
-        if let Some(idx) = LuaTable::to_index(&key) {
-            let arr_len = self.arr.len();
-            if idx <= arr_len {
-                let val_is_nil = val.is_nil();
-                self.arr[idx - 1] = val;
-                if idx == arr_len && val_is_nil {
-                    self.shrink_array();
-                }
-                return;
+        let key = normalize(&key);
+        if let Some(idx) = self.array_index(&key) {
+            self.arr[idx] = val;
+            return Ok(());
+        }
+        if let Some(slot) = self.map.get(&key) {
+            // a new object may reuse the address of a dead key
+            self.entries[*slot] = (key, val);
+            return Ok(());
+        }
+        if val.is_nil() {
+            return Ok(());
+        }
+        if self.entries.len() >= self.hash_size {
+            self.rehash(&key);
+            // the key may belong to the grown array part now
+            if let Some(idx) = self.array_index(&key) {
+                self.arr[idx] = val;
+                return Ok(());
             }
-            if idx == arr_len + 1 {
-                self.map.remove(&key);
-                if !val.is_nil() {
-                    self.arr.push(val);
-                    self.expand_array();
-                }
-                return;
+        }
+        self.map.insert(key, self.entries.len());
+        self.entries.push((key, val));
+        Ok(())
+    }
+
+    // weak keys and weak values, from the __mode metafield
+    fn mode(&self) -> (bool, bool) {
+        if let Some(mt) = &self.metatable {
+            if let LuaValue::LuaString(mode) = mt.borrow().get_str("__mode") {
+                return (mode.contains('k'), mode.contains('v'));
             }
         }
+        (false, false)
+    }
 
-        if !val.is_nil() {
-            self.map.insert(key, val);
-        } else {
-            self.map.remove(&key);
+    pub fn has_field(&self, name: &str) -> bool {
+        !self.get_str(name).is_nil()
+    }
+
+    // look up a string key without putting it on the heap
+    pub fn get_str(&self, key: &str) -> LuaValue {
+        let k = TString::local(key);
+        self.get(&LuaValue::LuaString(k.handle()))
+    }
+
+    pub fn has_metafield(&self, name: &str) -> bool {
+        match &self.metatable {
+            Some(mt) => mt.borrow().has_field(name),
+            None => false,
         }
     }
 
+    // slot of a key in the hash part; keys keep their slot until the next rehash
+    pub fn find_slot(&self, key: &LuaValue) -> Option<usize> {
+        let slot = *self.map.get(key)?;
+        if self.entries[slot].0.is_nil() {
+            return None; // dead key, see put
+        }
+        Some(slot)
+    }
+
+    // whether a remembered slot still holds `key`
+    pub fn slot_holds(&self, slot: usize, key: &LuaValue) -> bool {
+        matches!(self.entries.get(slot), Some((k, _)) if k == key)
+    }
+
+    pub fn slot_value(&self, slot: usize) -> LuaValue {
+        self.entries[slot].1
+    }
+
+    pub fn set_slot(&mut self, slot: usize, val: LuaValue) {
+        self.entries[slot].1 = val;
+    }
+
+    // a border: a non-negative integer n with t[n] ~= nil (or n == 0) and
+    // t[n+1] == nil, found by binary search (see luaH_getn)
     pub fn len(&self) -> usize {
-        self.arr.len()
+        let mut j = self.arr.len();
+        if j > 0 && self.arr[j - 1].is_nil() {
+            let mut i = 0;
+            while j - i > 1 {
+                let m = (i + j) / 2;
+                if self.arr[m - 1].is_nil() {
+                    j = m;
+                } else {
+                    i = m;
+                }
+            }
+            return i;
+        }
+        if self.map.is_empty() {
+            return j;
+        }
+        self.unbound_search(j)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
     }
 
-    fn expand_array(&mut self) {
-        let mut idx = self.arr.len() + 1;
-        loop {
-            let key = LuaValue::Int64(idx as i64);
-            if self.map.contains_key(&key) {
-                let val = self.map.remove(&key).unwrap();
-                self.arr.push(val);
-                idx += 1;
+    // the border is past the array part, `j` is zero or a present index
+    fn unbound_search(&self, j: usize) -> usize {
+        let mut i = j;
+        let mut j = j + 1;
+        // find i present and j absent
+        while !self.get_int(j as i64).is_nil() {
+            i = j;
+            if j > i64::MAX as usize / 2 {
+                // built to defeat the search: go linearly
+                let mut i = 1;
+                while !self.get_int(i as i64).is_nil() {
+                    i += 1;
+                }
+                return i - 1;
+            }
+            j *= 2;
+        }
+        while j - i > 1 {
+            let m = (i + j) / 2;
+            if self.get_int(m as i64).is_nil() {
+                j = m;
             } else {
-                break;
+                i = m;
             }
         }
+        i
     }
 
-    fn shrink_array(&mut self) {
-        while !self.arr.is_empty() {
-            if self.arr.last().unwrap().is_nil() {
-                self.arr.pop();
+    // returns the entry following `key` (nil starts the traversal), array part first
+    pub fn next(&self, key: &LuaValue) -> Result<Option<(LuaValue, LuaValue)>, &'static str> {
+        let mut arr_start = 0;
+        let mut entry_start = 0;
+        if !key.is_nil() {
+            let key = normalize(key);
+            if let Some(idx) = self.array_index(&key) {
+                arr_start = idx + 1;
+            } else if let Some(slot) = self.map.get(&key) {
+                arr_start = self.arr.len();
+                entry_start = slot + 1;
             } else {
+                return Err("invalid key to 'next'");
+            }
+        }
+
+        for i in arr_start..self.arr.len() {
+            if !self.arr[i].is_nil() {
+                return Ok(Some((LuaValue::Int64(i as i64 + 1), self.arr[i])));
+            }
+        }
+        for (k, v) in self.entries.iter().skip(entry_start) {
+            if !v.is_nil() {
+                return Ok(Some((*k, *v)));
+            }
+        }
+        Ok(None)
+    }
+
+    // size both parts for the keys in use plus `extra` (see luaH_resize)
+    fn rehash(&mut self, extra: &LuaValue) {
+        // nums[i]: number of integer keys in (2^(i-1), 2^i]
+        let mut nums = [0; MAXABITS + 1];
+        let mut na = self.count_array(&mut nums);
+        let mut total = na;
+        for (k, v) in self.entries.iter() {
+            if !v.is_nil() {
+                if count_int(k, &mut nums) {
+                    na += 1;
+                }
+                total += 1;
+            }
+        }
+        if count_int(extra, &mut nums) {
+            na += 1;
+        }
+        total += 1;
+        let array_size = compute_sizes(&nums, &mut na);
+        self.resize(array_size, total - na);
+    }
+
+    // non-nil values in the array part, counted into nums[]
+    fn count_array(&self, nums: &mut [usize]) -> usize {
+        let mut total = 0;
+        let mut i = 1;
+        for (lg, num) in nums.iter_mut().enumerate() {
+            let lim = std::cmp::min(1 << lg, self.arr.len());
+            if i > lim {
                 break;
             }
+            let n = self.arr[i - 1..lim].iter().filter(|v| !v.is_nil()).count();
+            *num += n;
+            total += n;
+            i = lim + 1;
+        }
+        total
+    }
+
+    fn resize(&mut self, array_size: usize, hash_size: usize) {
+        let hash_size = if hash_size == 0 { 0 } else { 1 << ceil_log2(hash_size) };
+        let mut old = mem::replace(&mut self.entries, Vec::with_capacity(hash_size));
+        self.map = HashMap::with_capacity(hash_size);
+        self.hash_size = hash_size;
+        if array_size < self.arr.len() {
+            // the vanishing slice goes to the hash part
+            for (i, v) in self.arr.drain(array_size..).enumerate() {
+                old.push((LuaValue::Int64((array_size + i + 1) as i64), v));
+            }
+        } else {
+            self.arr.resize(array_size, LuaValue::Nil);
+        }
+        for (k, v) in old {
+            if v.is_nil() {
+                continue;
+            }
+            if let Some(idx) = self.array_index(&k) {
+                self.arr[idx] = v;
+            } else {
+                self.map.insert(k, self.entries.len());
+                self.entries.push((k, v));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::gc::Heap;
+
+    // a table with `mode` as __mode; fresh objects are white
+    fn weak_table(heap: &mut Heap, mode: &str) -> LuaTable {
+        let mut mt = LuaTable::new(0, 1);
+        let k = LuaValue::new_string(heap, "__mode");
+        mt.put(k, LuaValue::new_string(heap, mode)).unwrap();
+        let mut t = LuaTable::new(0, 0);
+        t.metatable = Some(heap.alloc(RefCell::new(mt)));
+        t
+    }
+
+    #[test]
+    fn next_goes_on_from_a_dead_key() {
+        let mut heap = Heap::new();
+        let mut t = weak_table(&mut heap, "k");
+        let dead = LuaValue::new_table(&mut heap, 0, 0);
+        let live = LuaValue::Bool(true);
+        t.put(dead, LuaValue::Int64(1)).unwrap();
+        t.put(live, LuaValue::Int64(2)).unwrap();
+        t.clear_dead(true);
+
+        assert!(t.get(&dead).is_nil());
+        assert!(t.find_slot(&dead).is_none());
+        match t.next(&dead) {
+            Ok(Some((k, _))) => assert!(k == live),
+            _ => panic!("next lost its place"),
+        }
+        // a key at the same address takes the slot back
+        t.put(dead, LuaValue::Int64(3)).unwrap();
+        assert!(t.get(&dead) == LuaValue::Int64(3));
+        assert!(matches!(t.next(&LuaValue::Nil), Ok(Some((k, _))) if k == dead));
+    }
+
+    #[test]
+    fn weak_keys_keep_their_values_until_the_keys_die() {
+        let mut heap = Heap::new();
+        let mut t = weak_table(&mut heap, "k");
+        let key = LuaValue::new_table(&mut heap, 0, 0);
+        let val = LuaValue::new_table(&mut heap, 0, 0);
+        t.put(key, val).unwrap();
+        t.clear_dead(false);
+        assert!(t.get(&key) == val);
+        t.clear_dead(true);
+        assert!(t.get(&key).is_nil());
+
+        let mut t = weak_table(&mut heap, "v");
+        t.put(key, val).unwrap();
+        t.put(LuaValue::Int64(1), val).unwrap();
+        t.clear_dead(false);
+        assert!(t.get(&key).is_nil());
+        assert!(t.get_int(1).is_nil());
+    }
+
+    #[test]
+    fn array_part_sizes() {
+        // keys 1, 2, 3 and 5: more than half of 1..4 are in use, not of 1..8
+        let mut nums = [0; MAXABITS + 1];
+        for k in [1, 2, 3, 5].iter() {
+            assert!(count_int(&LuaValue::Int64(*k), &mut nums));
+        }
+        let mut na = 4;
+        assert_eq!(compute_sizes(&nums, &mut na), 4);
+        assert_eq!(na, 3);
+
+        let mut nums = [0; MAXABITS + 1];
+        count_int(&LuaValue::Int64(1), &mut nums);
+        count_int(&LuaValue::Int64(100), &mut nums);
+        let mut na = 2;
+        assert_eq!(compute_sizes(&nums, &mut na), 1);
+        assert_eq!(na, 1);
+
+        assert!(!count_int(&LuaValue::Int64(0), &mut nums));
+        assert!(!count_int(&LuaValue::Float64(1.5), &mut nums));
+    }
+
+    #[test]
+    fn rehash_splits_the_keys() {
+        let mut t = LuaTable::new(0, 0);
+        for k in [1, 2, 3, 5].iter() {
+            t.put(LuaValue::Int64(*k), LuaValue::Bool(true)).unwrap();
+        }
+        // the last rehash, for 5, sized the array for 1..4
+        assert_eq!(t.arr.len(), 4);
+        assert_eq!(t.entries.len(), 1);
+        assert_eq!(t.hash_size, 1);
+    }
+
+    #[test]
+    fn reverse_fill_ends_in_the_array_part() {
+        let mut t = LuaTable::new(0, 0);
+        for i in (1..=10).rev() {
+            t.put(LuaValue::Int64(i), LuaValue::Int64(i * 10)).unwrap();
+        }
+        assert_eq!(t.arr.len(), 16);
+        assert!(t.entries.is_empty());
+        for i in 1..=10 {
+            assert!(t.get_int(i) == LuaValue::Int64(i * 10));
+        }
+        assert_eq!(t.len(), 10);
+    }
+
+    #[test]
+    fn integral_float_keys_are_integers() {
+        let mut t = LuaTable::new(0, 0);
+        t.put(LuaValue::Float64(1.0), LuaValue::Int64(1)).unwrap();
+        assert!(t.get_int(1) == LuaValue::Int64(1));
+        t.put(LuaValue::Int64(1), LuaValue::Int64(2)).unwrap();
+        assert!(t.get(&LuaValue::Float64(1.0)) == LuaValue::Int64(2));
+
+        let big = 2f64.powi(60);
+        t.put(LuaValue::Float64(big), LuaValue::Int64(3)).unwrap();
+        assert!(t.get(&LuaValue::Int64(1 << 60)) == LuaValue::Int64(3));
+        t.put(LuaValue::Float64(1.5), LuaValue::Int64(4)).unwrap();
+        assert!(t.get(&LuaValue::Float64(1.5)) == LuaValue::Int64(4));
+
+        // traversal hands out the integer keys
+        let mut keys = Vec::new();
+        let mut k = LuaValue::Nil;
+        while let Ok(Some((next, _))) = t.next(&k) {
+            keys.push(next);
+            k = next;
+        }
+        assert_eq!(keys.len(), 3);
+        assert!(keys.iter().all(|k| !matches!(k, LuaValue::Float64(n) if n.fract() == 0.0)));
+        // and takes float keys back
+        assert!(t.next(&LuaValue::Float64(1.0)).is_ok());
+
+        assert_eq!(t.put(LuaValue::Float64(f64::NAN), LuaValue::Int64(1)), Err("table index is NaN"));
+        assert_eq!(t.put(LuaValue::Nil, LuaValue::Int64(1)), Err("table index is nil"));
+    }
+
+    #[test]
+    fn borders() {
+        let mut t = LuaTable::new(4, 0);
+        assert_eq!(t.len(), 0);
+        for i in 1..=4 {
+            t.put(LuaValue::Int64(i), LuaValue::Bool(true)).unwrap();
+        }
+        assert_eq!(t.len(), 4);
+        t.put(LuaValue::Int64(4), LuaValue::Nil).unwrap();
+        assert_eq!(t.len(), 3);
+        // with holes any border will do
+        t.put(LuaValue::Int64(2), LuaValue::Nil).unwrap();
+        let n = t.len() as i64;
+        assert!(n == 1 || n == 3);
+        assert!(!t.get_int(n).is_nil() && t.get_int(n + 1).is_nil());
+        t.put(LuaValue::Int64(1), LuaValue::Nil).unwrap();
+        t.put(LuaValue::Int64(3), LuaValue::Nil).unwrap();
+        assert_eq!(t.len(), 0);
+
+        // a sequence in the hash part, found by the unbound search
+        let mut t = LuaTable::new(0, 8);
+        for i in 1..=5 {
+            t.put(LuaValue::Int64(i), LuaValue::Bool(true)).unwrap();
+        }
+        assert!(t.arr.is_empty());
+        assert_eq!(t.len(), 5);
+        // and one that goes on from the array part
+        let mut t = LuaTable::new(2, 4);
+        for i in 1..=4 {
+            t.put(LuaValue::Int64(i), LuaValue::Bool(true)).unwrap();
         }
+        assert_eq!(t.arr.len(), 2);
+        assert_eq!(t.len(), 4);
     }
-}
\ No newline at end of file
+
+}
@@ -0,0 +1,35 @@
+use crate::state::lua_stack::LuaStack;
+use crate::state::gc::{GcPtr, Trace};
+use crate::api::consts::*;
+
+// call stack of a coroutine; while it runs it is moved into the LuaState
+pub struct LuaThread {
+    pub stack: LuaStack,
+    pub frames: Vec<LuaStack>,
+    pub status: u8, // LUA_OK, LUA_YIELD or the error that killed it
+}
+
+impl Default for LuaThread {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LuaThread {
+    pub fn new() -> LuaThread {
+        LuaThread {
+            stack: LuaStack::new(LUA_MINSTACK, None),
+            frames: Vec::new(),
+            status: LUA_OK,
+        }
+    }
+}
+
+impl Trace for LuaThread {
+    fn trace(&self, gray: &mut Vec<GcPtr>) {
+        self.stack.trace(gray);
+        for frame in self.frames.iter() {
+            frame.trace(gray);
+        }
+    }
+}
@@ -0,0 +1,38 @@
+use crate::state::lua_value::LuaValue;
+use crate::state::lua_table::LuaTable;
+use crate::state::gc::{Gc, GcPtr, Trace};
+use std::any::Any;
+use std::cell::RefCell;
+use std::mem;
+
+// a Rust value owned by Lua; it is dropped when the collector frees it
+pub struct Userdata {
+    pub data: Box<dyn Any>,
+    pub metatable: Option<Gc<RefCell<LuaTable>>>,
+    pub user_values: Vec<LuaValue>,
+}
+
+impl Userdata {
+    pub fn new(data: Box<dyn Any>, n_uvalue: usize) -> Userdata {
+        Userdata {
+            data,
+            metatable: None,
+            user_values: vec![LuaValue::Nil; n_uvalue],
+        }
+    }
+}
+
+impl Trace for Userdata {
+    fn trace(&self, gray: &mut Vec<GcPtr>) {
+        if let Some(mt) = &self.metatable {
+            mt.mark(gray);
+        }
+        for val in self.user_values.iter() {
+            val.trace(gray);
+        }
+    }
+
+    fn extra_size(&self) -> usize {
+        mem::size_of_val(&*self.data) + self.user_values.capacity() * mem::size_of::<LuaValue>()
+    }
+}
@@ -1,19 +1,31 @@
 use crate::api::consts::*;
 use std::hash::{Hash, Hasher};
 use crate::state::lua_table::LuaTable;
-use std::rc::Rc;
+use crate::state::closure::Closure;
+use crate::state::lua_thread::LuaThread;
+use crate::state::lua_userdata::Userdata;
+use crate::state::lua_string::TString;
+use std::borrow::Cow;
+use crate::state::gc::{Gc, GcHeader, GcPtr, Heap, Trace};
 use std::cell::RefCell;
+use std::ffi::c_void;
 
 
 
-#[derive(Clone)]
+// a tag and a payload of one word, cheap to copy; collectable values
+// are handles to objects owned by the Heap
+#[derive(Clone, Copy)]
 pub enum LuaValue {
     Nil,
     Bool(bool),
+    LightUserData(*mut c_void),
     Int64(i64),
     Float64(f64),
-    LuaString(String),
-    Table(Rc<RefCell<LuaTable>>),
+    LuaString(Gc<TString>),
+    Table(Gc<RefCell<LuaTable>>),
+    Function(Gc<Closure>),
+    Thread(Gc<RefCell<LuaThread>>),
+    UserData(Gc<RefCell<Userdata>>),
 }
 
 // the trait `std::hash::Hash` is not implemented for `f64`
@@ -22,10 +34,14 @@ impl Hash for LuaValue {
         match self {
             LuaValue::Nil => 0.hash(state),
             LuaValue::Bool(b) => b.hash(state),
+            LuaValue::LightUserData(p) => (*p as usize).hash(state),
             LuaValue::Int64(i) => i.hash(state),
             LuaValue::Float64(n) => n.to_bits().hash(state),
-            LuaValue::LuaString(s) => s.hash(state),
-            LuaValue::Table(t) => t.borrow().hash(state),
+            LuaValue::LuaString(s) => s.hash().hash(state),
+            LuaValue::Table(t) => (Gc::as_ptr(t) as usize).hash(state),
+            LuaValue::Function(f) => (Gc::as_ptr(f) as usize).hash(state),
+            LuaValue::Thread(t) => (Gc::as_ptr(t) as usize).hash(state),
+            LuaValue::UserData(u) => (Gc::as_ptr(u) as usize).hash(state),
         }
     }
 }
@@ -36,14 +52,22 @@ impl PartialEq for LuaValue {
             true
         } else if let (LuaValue::Bool(x), LuaValue::Bool(y)) = (self, other) {
             x == y
+        } else if let (LuaValue::LightUserData(x), LuaValue::LightUserData(y)) = (self, other) {
+            x == y
         } else if let (LuaValue::Int64(x), LuaValue::Int64(y)) = (self, other) {
             x == y
         } else if let (LuaValue::Float64(x), LuaValue::Float64(y)) = (self, other) {
             x == y
         } else if let (LuaValue::LuaString(x), LuaValue::LuaString(y)) = (self, other) {
-            x == y
+            TString::eq(x, y)
         } else if let (LuaValue::Table(x), LuaValue::Table(y)) = (self, other) {
-            Rc::ptr_eq(x, y)
+            Gc::ptr_eq(x, y)
+        } else if let (LuaValue::Function(x), LuaValue::Function(y)) = (self, other) {
+            Gc::ptr_eq(x, y)
+        } else if let (LuaValue::Thread(x), LuaValue::Thread(y)) = (self, other) {
+            Gc::ptr_eq(x, y)
+        } else if let (LuaValue::UserData(x), LuaValue::UserData(y)) = (self, other) {
+            Gc::ptr_eq(x, y)
         } else {
             false
         }
@@ -53,100 +77,470 @@ impl PartialEq for LuaValue {
 // the trait `std::cmp::Eq` is not implemented for `f64`
 impl Eq for LuaValue {} // TODO
 
+impl Trace for LuaValue {
+    fn trace(&self, gray: &mut Vec<GcPtr>) {
+        match self {
+            LuaValue::LuaString(s) => s.mark(gray),
+            LuaValue::Table(t) => t.mark(gray),
+            LuaValue::Function(f) => f.mark(gray),
+            LuaValue::Thread(t) => t.mark(gray),
+            LuaValue::UserData(u) => u.mark(gray),
+            _ => {}
+        }
+    }
+}
+
 impl LuaValue {
-    pub fn new_table(narr: usize, nrec: usize) -> LuaValue {
-        LuaValue::Table(Rc::new(RefCell::new(LuaTable::new(narr, nrec))))
+    pub fn new_string(heap: &mut Heap, s: &str) -> LuaValue {
+        LuaValue::LuaString(TString::new(heap, s))
     }
 
-    pub fn is_nil(&self) -> bool {
-        match self{
-            LuaValue::Nil => true,
-            _ => false
+    pub fn new_table(heap: &mut Heap, narr: usize, nrec: usize) -> LuaValue {
+        LuaValue::Table(heap.alloc(RefCell::new(LuaTable::new(narr, nrec))))
+    }
+
+    pub fn gc_header(&self) -> Option<&GcHeader> {
+        match self {
+            LuaValue::LuaString(s) => Some(s.header()),
+            LuaValue::Table(t) => Some(t.header()),
+            LuaValue::Function(f) => Some(f.header()),
+            LuaValue::Thread(t) => Some(t.header()),
+            LuaValue::UserData(u) => Some(u.header()),
+            _ => None,
         }
     }
 
+    // a collectable value the collector has not reached (yet)
+    pub fn is_white(&self) -> bool {
+        self.gc_header().is_some_and(|h| h.is_white())
+    }
+
+    pub fn is_nil(&self) -> bool {
+        matches!(self, LuaValue::Nil)
+    }
+
     pub fn get_type(&self) -> i8 {
         match self {
             LuaValue::Nil => LUA_TNIL,
             LuaValue::Bool(_) => LUA_TBOOLEAN,
+            LuaValue::LightUserData(_) => LUA_TLIGHTUSERDATA,
             LuaValue::Int64(_) => LUA_TNUMBER,
             LuaValue::Float64(_) => LUA_TNUMBER,
             LuaValue::LuaString(_) => LUA_TSTRING,
             LuaValue::Table(_) => LUA_TTABLE,
+            LuaValue::Function(_) => LUA_TFUNCTION,
+            LuaValue::Thread(_) => LUA_TTHREAD,
+            LuaValue::UserData(_) => LUA_TUSERDATA,
+        }
+    }
+
+    pub fn to_pointer(self) -> usize {
+        match self {
+            LuaValue::Table(t) => Gc::as_ptr(&t) as usize,
+            LuaValue::Function(f) => Gc::as_ptr(&f) as usize,
+            LuaValue::Thread(t) => Gc::as_ptr(&t) as usize,
+            LuaValue::UserData(u) => Gc::as_ptr(&u) as usize,
+            LuaValue::LightUserData(p) => p as usize,
+            _ => 0
         }
     }
 
-    pub fn to_bool(&self) -> bool {
+    pub fn to_bool(self) -> bool {
         match self {
             LuaValue::Nil => false,
-            LuaValue::Bool(a) => *a,
+            LuaValue::Bool(a) => a,
             _ => true
         }
     }
 
-    pub fn to_numberx(&self) -> (f64, bool) {
+    pub fn to_numberx(self) -> (f64, bool) {
         match self {
-            LuaValue::Int64(a) => (*a as f64, true),
-            LuaValue::Float64(b) => (*b, true),
-            LuaValue::LuaString(c) => parse_float(c.clone()),
+            LuaValue::Int64(a) => (a as f64, true),
+            LuaValue::Float64(b) => (b, true),
+            LuaValue::LuaString(c) => string_to_float(&c),
             _ => (0.0, false)
         }
     }
 
-    pub fn to_integerx(&self) -> (i64, bool) {
+    pub fn to_integerx(self) -> (i64, bool) {
         match self {
-            LuaValue::Int64(a) => (*a, true),
-            LuaValue::Float64(b) => float_to_integer(*b),
-            LuaValue::LuaString(c) => string_to_integer(c.clone()),
+            LuaValue::Int64(a) => (a, true),
+            LuaValue::Float64(b) => float_to_integer(b),
+            LuaValue::LuaString(c) => string_to_integer(&c),
             _ => (0, false)
         }
     }
 
-    pub fn to_stringx(&self) -> (String, bool) {
+    // strings are borrowed, numbers converted
+    #[allow(clippy::wrong_self_convention)]
+    pub fn to_stringx(&self) -> (Cow<'_, str>, bool) {
         match self {
-            LuaValue::LuaString(a) => (a.clone(), true),
-            LuaValue::Int64(b) => (b.to_string(), true),
-            LuaValue::Float64(c) => (c.to_string(), true),
-            _ => (String::new(), false)
+            LuaValue::LuaString(a) => (Cow::Borrowed(a.as_str()), true),
+            LuaValue::Int64(b) => (Cow::Owned(b.to_string()), true),
+            LuaValue::Float64(c) => (Cow::Owned(float_to_string(*c)), true),
+            _ => (Cow::Borrowed(""), false)
         }
     }
 }
 
+// LUAI_NUMFFORMAT ("%.14g"), with ".0" added to floats that would
+// read back as integers (see tostringbuff)
+pub fn float_to_string(n: f64) -> String {
+    if n.is_infinite() {
+        return String::from(if n > 0.0 { "inf" } else { "-inf" });
+    }
+    if n.is_nan() {
+        return String::from(if n.is_sign_negative() { "-nan" } else { "nan" });
+    }
+    let mut s = format_g(n, 14);
+    if s.bytes().all(|c| c == b'-' || c.is_ascii_digit()) {
+        s.push_str(".0");
+    }
+    s
+}
+
+// printf's %.<precision>g for finite values
+fn format_g(n: f64, precision: usize) -> String {
+    // the exponent after rounding to `precision` significant digits
+    let e_form = format!("{:.*e}", precision - 1, n);
+    let (mantissa, exp) = e_form.split_once('e').unwrap();
+    let exp: i32 = exp.parse().unwrap();
+    if exp < -4 || exp >= precision as i32 {
+        let mantissa = strip_zeros(mantissa);
+        let sign = if exp < 0 { '-' } else { '+' };
+        format!("{}e{}{:02}", mantissa, sign, exp.abs())
+    } else {
+        let decimals = (precision as i32 - 1 - exp) as usize;
+        strip_zeros(&format!("{:.*}", decimals, n)).to_string()
+    }
+}
+
+// trailing zeros of the fraction, and the point if nothing is left of it
+fn strip_zeros(s: &str) -> &str {
+    if s.contains('.') {
+        s.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        s
+    }
+}
+
 pub fn float_to_integer(n: f64) -> (i64, bool){
+    // `as` saturates, so 2^63 would pass the round trip below
+    if !(-9223372036854775808.0..9223372036854775808.0).contains(&n) {
+        return (0, false);
+    }
     let i = n as i64;
     if i as f64 == n {
-        return (i, true);
+        (i, true)
     } else {
-        return (0, false);
+        (0, false)
     }
 }
 
-pub fn parse_integer(s: String) -> (i64, bool) {
-    let i = s.parse::<i64>();
-    match i {
-        Ok(result) => (result, true),
-        Err(_) => (0, false)
+// see lisspace
+fn is_space(c: u8) -> bool {
+    c == b' ' || (b'\t'..=b'\r').contains(&c)
+}
+
+fn trim_spaces(s: &str) -> &str {
+    s.trim_matches(|c: char| c.is_ascii() && is_space(c as u8))
+}
+
+// an optional sign; true if negative
+fn split_sign(s: &[u8]) -> (bool, &[u8]) {
+    match s.first() {
+        Some(b'-') => (true, &s[1..]),
+        Some(b'+') => (false, &s[1..]),
+        _ => (false, s),
     }
 }
 
-pub fn parse_float(s: String) -> (f64, bool) {
-    let i = s.parse::<f64>();
-    match i {
-        Ok(result) => (result, true),
-        Err(_) => (0.0, false)
+// a decimal or hexadecimal integer numeral; hexadecimal ones wrap
+// around, decimal ones that overflow are not integers (see l_str2int)
+pub fn parse_integer(s: &str) -> (i64, bool) {
+    let (neg, s) = split_sign(trim_spaces(s).as_bytes());
+    let mut a: u64 = 0;
+    if s.len() > 2 && s[0] == b'0' && (s[1] == b'x' || s[1] == b'X') {
+        for &c in &s[2..] {
+            match (c as char).to_digit(16) {
+                Some(d) => a = a.wrapping_mul(16).wrapping_add(d as u64),
+                None => return (0, false),
+            }
+        }
+    } else {
+        if s.is_empty() {
+            return (0, false);
+        }
+        for &c in s {
+            if !c.is_ascii_digit() {
+                return (0, false);
+            }
+            a = match a.checked_mul(10).and_then(|a| a.checked_add((c - b'0') as u64)) {
+                Some(a) => a,
+                None => return (0, false),
+            };
+            if a > i64::MAX as u64 + neg as u64 {
+                return (0, false);
+            }
+        }
     }
+    let i = if neg { 0u64.wrapping_sub(a) } else { a } as i64;
+    (i, true)
 }
 
-pub fn string_to_integer(s: String) -> (i64, bool) {
-    let (int_res, int_ok) = parse_integer(s.clone());
-    if int_ok {
-        return (int_res, int_ok);
+// a float numeral as strtod reads it, without "inf" and "nan"; hexadecimal
+// ones may have a binary exponent (see l_str2d)
+pub fn parse_float(s: &str) -> (f64, bool) {
+    let (neg, b) = split_sign(trim_spaces(s).as_bytes());
+    let result = if b.len() > 1 && b[0] == b'0' && (b[1] == b'x' || b[1] == b'X') {
+        parse_hex_float(&b[2..])
+    } else if is_decimal_float(b) {
+        // checked above, so the only difference from Lua is rounding
+        std::str::from_utf8(b).ok().and_then(|t| t.parse::<f64>().ok())
+    } else {
+        None
+    };
+    match result {
+        Some(n) if neg => (-n, true),
+        Some(n) => (n, true),
+        None => (0.0, false),
     }
+}
 
-    let (f_res, f_ok) = parse_float(s.clone());
-    if f_ok {
-        return (f_res as i64, f_ok);
+// digits [. digits] | . digits, then an optional exponent
+fn is_decimal_float(s: &[u8]) -> bool {
+    let mut i = 0;
+    let mut n_digits = 0;
+    while i < s.len() && s[i].is_ascii_digit() {
+        i += 1;
+        n_digits += 1;
     }
-    return (0, false);
+    if i < s.len() && s[i] == b'.' {
+        i += 1;
+        while i < s.len() && s[i].is_ascii_digit() {
+            i += 1;
+            n_digits += 1;
+        }
+    }
+    if n_digits == 0 {
+        return false;
+    }
+    if i < s.len() && (s[i] == b'e' || s[i] == b'E') {
+        i += 1;
+        if i < s.len() && (s[i] == b'+' || s[i] == b'-') {
+            i += 1;
+        }
+        let start = i;
+        while i < s.len() && s[i].is_ascii_digit() {
+            i += 1;
+        }
+        if i == start {
+            return false;
+        }
+    }
+    i == s.len()
+}
+
+// the part after "0x" (see lua_strx2number)
+fn parse_hex_float(s: &[u8]) -> Option<f64> {
+    const MAXSIGDIG: i32 = 30;
+    let mut r = 0.0;
+    let mut e: i32 = 0;
+    let mut sig_digits = 0;
+    let mut non_sig = 0;
+    let mut any_digit = false;
+    let mut has_dot = false;
+    let mut i = 0;
+    while i < s.len() {
+        let c = s[i];
+        if c == b'.' {
+            if has_dot {
+                break;
+            }
+            has_dot = true;
+        } else if let Some(d) = (c as char).to_digit(16) {
+            any_digit = true;
+            if sig_digits == 0 && d == 0 {
+                // leading zeros do not count
+                non_sig += 1;
+            } else {
+                sig_digits += 1;
+                if sig_digits <= MAXSIGDIG {
+                    r = r * 16.0 + d as f64;
+                } else {
+                    // too many digits; ignore but still count for exponent
+                    e += 4;
+                }
+            }
+            if has_dot {
+                e -= 4;
+            }
+        } else {
+            break;
+        }
+        i += 1;
+    }
+    if !any_digit || non_sig + sig_digits == 0 {
+        return None;
+    }
+    if i < s.len() && (s[i] == b'p' || s[i] == b'P') {
+        let (neg, rest) = split_sign(&s[i + 1..]);
+        if rest.is_empty() || !rest.iter().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        let mut exp1: i32 = 0;
+        for &c in rest {
+            exp1 = exp1.saturating_mul(10).saturating_add((c - b'0') as i32);
+        }
+        e = e.saturating_add(if neg { -exp1 } else { exp1 });
+        i = s.len();
+    }
+    if i != s.len() {
+        return None;
+    }
+    Some(ldexp(r, e))
+}
+
+// r * 2^e without overflowing in the intermediate power
+fn ldexp(mut r: f64, mut e: i32) -> f64 {
+    while e > 1000 {
+        r *= 2f64.powi(1000);
+        e -= 1000;
+    }
+    while e < -1000 {
+        r *= 2f64.powi(-1000);
+        e += 1000;
+    }
+    r * 2f64.powi(e)
+}
+
+// a numeral as the lexer reads it, with spaces around: an integer if it
+// looks like one and fits, a float otherwise (see luaO_str2num)
+pub fn str_to_number(s: &str) -> Option<LuaValue> {
+    if let (i, true) = parse_integer(s) {
+        return Some(LuaValue::Int64(i));
+    }
+    if let (n, true) = parse_float(s) {
+        return Some(LuaValue::Float64(n));
+    }
+    None
+}
+
+// integral floats convert, others do not
+pub fn string_to_integer(s: &str) -> (i64, bool) {
+    match str_to_number(s) {
+        Some(LuaValue::Int64(i)) => (i, true),
+        Some(LuaValue::Float64(n)) => float_to_integer(n),
+        _ => (0, false),
+    }
+}
+
+pub fn string_to_float(s: &str) -> (f64, bool) {
+    match str_to_number(s) {
+        Some(LuaValue::Int64(i)) => (i as f64, true),
+        Some(LuaValue::Float64(n)) => (n, true),
+        _ => (0.0, false),
+    }
+}
 
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn int(s: &str) -> Option<i64> {
+        match str_to_number(s) {
+            Some(LuaValue::Int64(i)) => Some(i),
+            _ => None,
+        }
+    }
+
+    fn float(s: &str) -> Option<f64> {
+        match str_to_number(s) {
+            Some(LuaValue::Float64(n)) => Some(n),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn integers() {
+        assert_eq!(int("10"), Some(10));
+        assert_eq!(int(" 10 "), Some(10));
+        assert_eq!(int("\t-10\n"), Some(-10));
+        assert_eq!(int("0x10"), Some(16));
+        assert_eq!(int("0XfF"), Some(255));
+        assert_eq!(int("-0x10"), Some(-16));
+        assert_eq!(int("9223372036854775807"), Some(i64::MAX));
+        assert_eq!(int("-9223372036854775808"), Some(i64::MIN));
+    }
+
+    #[test]
+    fn hexadecimal_integers_wrap_around() {
+        assert_eq!(int("0xffffffffffffffff"), Some(-1));
+        assert_eq!(int("0x7fffffffffffffff"), Some(i64::MAX));
+        assert_eq!(int("0x10000000000000000"), Some(0));
+    }
+
+    #[test]
+    fn decimal_integers_that_overflow_are_floats() {
+        assert_eq!(parse_integer("9223372036854775808"), (0, false));
+        assert_eq!(float("9223372036854775808"), Some(9223372036854775808.0));
+        assert_eq!(float("-9223372036854775809"), Some(-9223372036854775808.0));
+    }
+
+    #[test]
+    fn floats() {
+        assert_eq!(float("1.5"), Some(1.5));
+        assert_eq!(float(".5"), Some(0.5));
+        assert_eq!(float("5."), Some(5.0));
+        assert_eq!(float("1e2"), Some(100.0));
+        assert_eq!(float(" -1E+2 "), Some(-100.0));
+        assert_eq!(float("1e-2"), Some(0.01));
+    }
+
+    #[test]
+    fn hexadecimal_floats() {
+        assert_eq!(float("0x1p4"), Some(16.0));
+        assert_eq!(float("0x.8"), Some(0.5));
+        assert_eq!(float("0xA.8P0"), Some(10.5));
+        assert_eq!(float("0x1P-2"), Some(0.25));
+        assert_eq!(float("-0x1p4"), Some(-16.0));
+        assert_eq!(parse_hex_float(b"1p"), None);
+        assert_eq!(parse_hex_float(b"p4"), None);
+        assert_eq!(parse_hex_float(b"."), None);
+        assert_eq!(parse_hex_float(b"1.2.3"), None);
+        // more digits than fit are dropped but still scale the result
+        assert_eq!(parse_hex_float(b"1000000000000000000000000000000000"), Some(2f64.powi(132)));
+    }
+
+    #[test]
+    fn not_numerals() {
+        for s in ["", " ", "1e", "1e+", "inf", "nan", "-inf", "0x", "1 2", ".", "e1", "1..2", "0x1g", "- 1"].iter() {
+            assert!(str_to_number(s).is_none(), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn floats_to_strings() {
+        assert_eq!(float_to_string(1.0), "1.0");
+        assert_eq!(float_to_string(-0.0), "-0.0");
+        assert_eq!(float_to_string(100.0), "100.0");
+        assert_eq!(float_to_string(-1.5), "-1.5");
+        assert_eq!(float_to_string(0.1), "0.1");
+        assert_eq!(float_to_string(0.0001), "0.0001");
+        assert_eq!(float_to_string(1e-5), "1e-05");
+        assert_eq!(float_to_string(1e15), "1e+15");
+        assert_eq!(float_to_string(1e100), "1e+100");
+        assert_eq!(float_to_string(2f64.powi(63)), "9.2233720368548e+18");
+        assert_eq!(float_to_string(std::f64::consts::PI), "3.1415926535898");
+        assert_eq!(float_to_string(1.0 / 3.0), "0.33333333333333");
+    }
+
+    #[test]
+    fn special_floats_to_strings() {
+        assert_eq!(float_to_string(f64::INFINITY), "inf");
+        assert_eq!(float_to_string(f64::NEG_INFINITY), "-inf");
+        assert_eq!(float_to_string(f64::NAN), "nan");
+        assert_eq!(float_to_string(-f64::NAN), "-nan");
+    }
+
+}
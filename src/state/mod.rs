@@ -1,4 +1,10 @@
-mod lua_stack;
+pub mod lua_stack;
 pub mod lua_value;
 pub mod lua_state;
-pub mod lua_table;
\ No newline at end of file
+pub mod lua_table;
+pub mod closure;
+pub mod lua_error;
+pub mod lua_thread;
+pub mod gc;
+pub mod lua_userdata;
+pub mod lua_string;
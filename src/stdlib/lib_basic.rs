@@ -0,0 +1,309 @@
+use crate::state::lua_state::LuaState;
+use crate::state::lua_value::{LuaValue, str_to_number};
+use crate::state::lua_error::LuaResult;
+use crate::state::closure::RustFn;
+use crate::api::api_stack::LuaAPI;
+use crate::api::api_aux::{AuxAPI, LuaBuffer};
+use crate::api::consts::*;
+
+pub fn open_base(ls: &mut LuaState) {
+    let funcs: [(&str, RustFn); 16] = [
+        ("print", base_print),
+        ("tostring", base_tostring),
+        ("tonumber", base_tonumber),
+        ("getmetatable", base_getmetatable),
+        ("setmetatable", base_setmetatable),
+        ("next", base_next),
+        ("rawequal", base_rawequal),
+        ("rawlen", base_rawlen),
+        ("rawget", base_rawget),
+        ("rawset", base_rawset),
+        ("pairs", base_pairs),
+        ("ipairs", base_ipairs),
+        ("error", base_error),
+        ("pcall", base_pcall),
+        ("xpcall", base_xpcall),
+        ("collectgarbage", base_collectgarbage),
+    ];
+    ls.push_global_table();
+    // the globals table has no metatable yet, so this cannot fail
+    let _ = ls.set_funcs(&funcs, 0).and_then(|()| ls.pop(1));
+}
+
+// the line is built first and written at once
+fn base_print(ls: &mut LuaState) -> LuaResult<usize> {
+    let n_args = ls.get_top();
+    let mut b = LuaBuffer::new();
+    for i in 1..(n_args + 1) {
+        if i > 1 {
+            b.add_char('\t');
+        }
+        ls.tolstring(i)?;
+        b.add_value(ls)?;
+    }
+    b.add_char('\n');
+    print!("{}", b.as_str());
+    Ok(0)
+}
+
+// tostring (v)
+fn base_tostring(ls: &mut LuaState) -> LuaResult<usize> {
+    ls.check_any(1)?;
+    ls.tolstring(1)?;
+    Ok(1)
+}
+
+// tonumber (e [, base])
+fn base_tonumber(ls: &mut LuaState) -> LuaResult<usize> {
+    if ls.is_none_or_nil(2) {
+        // standard conversion
+        let val = ls.stack.get(1);
+        match val {
+            LuaValue::Int64(_) | LuaValue::Float64(_) => ls.stack.push(val),
+            LuaValue::LuaString(s) => match str_to_number(&s) {
+                Some(n) => ls.stack.push(n),
+                None => ls.push_nil(),
+            },
+            _ => {
+                ls.check_any(1)?;
+                ls.push_nil();
+            },
+        }
+        return Ok(1);
+    }
+    let base = ls.check_integer(2)?;
+    ls.check_type(1, LUA_TSTRING)?; // no numbers as strings
+    ls.arg_check((2..=36).contains(&base), 2, "base out of range")?;
+    let s = ls.to_string(1);
+    match str_to_int_base(&s, base as u32) {
+        Some(n) => ls.push_integer(n),
+        None => ls.push_nil(),
+    }
+    Ok(1)
+}
+
+// an integer numeral in base 2-36, with wraparound (see b_str2int)
+fn str_to_int_base(s: &str, base: u32) -> Option<i64> {
+    let is_space = |c: char| c == ' ' || ('\t'..='\r').contains(&c);
+    let s = s.trim_matches(is_space);
+    let (neg, digits) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s.strip_prefix('+').unwrap_or(s)),
+    };
+    if digits.is_empty() {
+        return None;
+    }
+    let mut n: u64 = 0;
+    for c in digits.chars() {
+        let d = c.to_digit(base)?;
+        n = n.wrapping_mul(base as u64).wrapping_add(d as u64);
+    }
+    Some(if neg { 0u64.wrapping_sub(n) } else { n } as i64)
+}
+
+// getmetatable (object)
+fn base_getmetatable(ls: &mut LuaState) -> LuaResult<usize> {
+    ls.check_any(1)?;
+    if !ls.get_metatable(1) {
+        ls.push_nil();
+        return Ok(1);
+    }
+    // a __metatable field hides the real metatable
+    ls.get_field(-1, String::from("__metatable"))?;
+    if ls.is_nil(-1) {
+        ls.pop(1)?;
+    }
+    Ok(1)
+}
+
+// setmetatable (table, metatable)
+fn base_setmetatable(ls: &mut LuaState) -> LuaResult<usize> {
+    let mt_type = ls.type_id(2);
+    ls.check_type(1, LUA_TTABLE)?;
+    ls.arg_check(mt_type == LUA_TNIL || mt_type == LUA_TTABLE, 2, "nil or table expected")?;
+    let t = ls.stack.get(1);
+    if !ls.get_metafield(&t, "__metatable").is_nil() {
+        return Err(ls.error2("cannot change a protected metatable"));
+    }
+    ls.set_top(2)?;
+    ls.set_metatable(1)?;
+    Ok(1)
+}
+
+// rawequal (v1, v2)
+fn base_rawequal(ls: &mut LuaState) -> LuaResult<usize> {
+    ls.check_any(1)?;
+    ls.check_any(2)?;
+    let result = ls.raw_equal(1, 2);
+    ls.push_boolean(result);
+    Ok(1)
+}
+
+// rawlen (v)
+fn base_rawlen(ls: &mut LuaState) -> LuaResult<usize> {
+    let t = ls.type_id(1);
+    ls.arg_check(t == LUA_TTABLE || t == LUA_TSTRING, 1, "table or string expected")?;
+    let n = ls.raw_len(1);
+    ls.push_integer(n as i64);
+    Ok(1)
+}
+
+// rawget (table, index)
+fn base_rawget(ls: &mut LuaState) -> LuaResult<usize> {
+    ls.check_type(1, LUA_TTABLE)?;
+    ls.check_any(2)?;
+    ls.set_top(2)?;
+    ls.raw_get(1)?;
+    Ok(1)
+}
+
+// rawset (table, index, value)
+fn base_rawset(ls: &mut LuaState) -> LuaResult<usize> {
+    ls.check_type(1, LUA_TTABLE)?;
+    ls.check_any(2)?;
+    ls.check_any(3)?;
+    ls.set_top(3)?;
+    ls.raw_set(1)?;
+    Ok(1)
+}
+
+// next (table [, index])
+fn base_next(ls: &mut LuaState) -> LuaResult<usize> {
+    ls.check_type(1, LUA_TTABLE)?;
+    ls.set_top(2)?; // create a 2nd argument if there isn't one
+    if ls.next(1)? {
+        Ok(2)
+    } else {
+        ls.push_nil();
+        Ok(1)
+    }
+}
+
+// pairs (t)
+fn base_pairs(ls: &mut LuaState) -> LuaResult<usize> {
+    ls.check_any(1)?;
+    let t = ls.stack.get(1);
+    let mm = ls.get_metafield(&t, "__pairs");
+    if !mm.is_nil() {
+        // metamethod returns the generator, state and initial value
+        ls.stack.push(mm);
+        ls.push_value(1);
+        ls.call(1, 3)?;
+        return Ok(3);
+    }
+    ls.push_rust_function(base_next); // will return generator,
+    ls.push_value(1); // state,
+    ls.push_nil(); // and initial value
+    Ok(3)
+}
+
+// ipairs (t)
+fn base_ipairs(ls: &mut LuaState) -> LuaResult<usize> {
+    ls.check_any(1)?;
+    ls.push_rust_function(ipairs_aux);
+    ls.push_value(1);
+    ls.push_integer(0);
+    Ok(3)
+}
+
+fn ipairs_aux(ls: &mut LuaState) -> LuaResult<usize> {
+    let i = ls.to_integer(2) + 1;
+    ls.push_integer(i);
+    if ls.get_i(1, i)? == LUA_TNIL {
+        Ok(1)
+    } else {
+        Ok(2)
+    }
+}
+
+// error (message [, level])
+fn base_error(ls: &mut LuaState) -> LuaResult<usize> {
+    let level = ls.opt_integer(2, 1)?;
+    ls.set_top(1)?;
+    if ls.type_id(1) == LUA_TSTRING && level > 0 {
+        // add position information of the caller at `level`
+        let msg = ls.location(level as usize) + &ls.to_string(1);
+        ls.push_string(msg);
+        ls.replace(1)?;
+    }
+    Err(ls.error())
+}
+
+// pcall (f [, arg1, ...])
+fn base_pcall(ls: &mut LuaState) -> LuaResult<usize> {
+    ls.check_any(1)?;
+    ls.push_boolean(true); // first result if no errors
+    ls.insert(1)?;
+    let n_args = ls.get_top() - 2;
+    let status = ls.pcallk(n_args as usize, LUA_MULTRET, 0, 0, finish_pcall)?;
+    finish_pcall(ls, status, 0)
+}
+
+// xpcall (f, msgh [, arg1, ...])
+fn base_xpcall(ls: &mut LuaState) -> LuaResult<usize> {
+    let n = ls.get_top();
+    ls.check_type(2, LUA_TFUNCTION)?; // check error function
+    ls.push_boolean(true); // first result
+    ls.push_value(1); // function
+    ls.rotate(3, 2)?; // move them below function's arguments
+    let status = ls.pcallk((n - 2) as usize, LUA_MULTRET, 2, 2, finish_pcall)?;
+    finish_pcall(ls, status, 2)
+}
+
+// collectgarbage ([opt [, arg]])
+fn base_collectgarbage(ls: &mut LuaState) -> LuaResult<usize> {
+    let opts = ["stop", "restart", "collect", "count", "step", "setpause", "setstepmul", "isrunning"];
+    let opts_num = [
+        LUA_GCSTOP, LUA_GCRESTART, LUA_GCCOLLECT, LUA_GCCOUNT,
+        LUA_GCSTEP, LUA_GCSETPAUSE, LUA_GCSETSTEPMUL, LUA_GCISRUNNING,
+    ];
+    let o = opts_num[ls.check_option(1, Some("collect"), &opts)?];
+    let ex = ls.opt_integer(2, 0)?;
+    let res = ls.gc(o, ex)?;
+    match o {
+        LUA_GCCOUNT => {
+            let b = ls.gc(LUA_GCCOUNTB, 0)?;
+            ls.push_number(res as f64 + b as f64 / 1024.0);
+        },
+        LUA_GCSTEP | LUA_GCISRUNNING => ls.push_boolean(res != 0),
+        _ => ls.push_integer(res),
+    }
+    Ok(1)
+}
+
+// results of a protected call start at `extra` + 1; also the
+// continuation of pcall and xpcall after a yield
+fn finish_pcall(ls: &mut LuaState, status: u8, extra: isize) -> LuaResult<usize> {
+    if status != LUA_OK && status != LUA_YIELD {
+        ls.push_boolean(false);
+        ls.push_value(-2); // error message
+        return Ok(2);
+    }
+    Ok((ls.get_top() - extra) as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::str_to_int_base;
+
+    #[test]
+    fn numerals_in_a_base() {
+        assert_eq!(str_to_int_base("z", 36), Some(35));
+        assert_eq!(str_to_int_base("Z", 36), Some(35));
+        assert_eq!(str_to_int_base("777", 8), Some(511));
+        assert_eq!(str_to_int_base(" -ff ", 16), Some(-255));
+        assert_eq!(str_to_int_base("+101", 2), Some(5));
+        assert_eq!(str_to_int_base("ffffffffffffffff", 16), Some(-1));
+    }
+
+    #[test]
+    fn digits_outside_the_base() {
+        assert_eq!(str_to_int_base("8", 8), None);
+        assert_eq!(str_to_int_base("2", 2), None);
+        assert_eq!(str_to_int_base("1.0", 10), None);
+        assert_eq!(str_to_int_base("1 0", 10), None);
+        assert_eq!(str_to_int_base("", 10), None);
+        assert_eq!(str_to_int_base(" - ", 10), None);
+        assert_eq!(str_to_int_base("0x10", 16), None);
+    }
+}
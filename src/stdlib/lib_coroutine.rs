@@ -0,0 +1,132 @@
+use crate::state::lua_state::LuaState;
+use crate::state::lua_value::LuaValue;
+use crate::state::lua_error::LuaResult;
+use crate::state::closure::RustFn;
+use crate::api::api_stack::LuaAPI;
+use crate::api::api_aux::AuxAPI;
+use crate::api::consts::*;
+use crate::state::gc::Gc;
+
+pub fn open_coroutine(ls: &mut LuaState) {
+    let funcs: [(&str, RustFn); 7] = [
+        ("create", co_create),
+        ("resume", co_resume),
+        ("running", co_running),
+        ("status", co_status),
+        ("wrap", co_wrap),
+        ("yield", co_yield),
+        ("isyieldable", co_isyieldable),
+    ];
+    ls.new_lib(&funcs);
+    let _ = ls.set_global(String::from("coroutine"));
+}
+
+fn check_co(ls: &mut LuaState) -> LuaResult<()> {
+    ls.arg_check(ls.type_id(1) == LUA_TTHREAD, 1, "coroutine expected")
+}
+
+// resume the coroutine at index 1 with the values above it, which are
+// replaced by its results; returns their count, or None on error
+fn aux_resume(ls: &mut LuaState) -> Option<usize> {
+    let n_args = (ls.get_top() - 1) as usize;
+    let status = ls.resume(n_args);
+    if status == LUA_OK || status == LUA_YIELD {
+        Some(ls.get_top() as usize)
+    } else {
+        None
+    }
+}
+
+// create (f)
+fn co_create(ls: &mut LuaState) -> LuaResult<usize> {
+    ls.check_type(1, LUA_TFUNCTION)?;
+    let f = ls.stack.get(1);
+    ls.new_thread();
+    if let LuaValue::Thread(co) = ls.stack.get(-1) {
+        co.borrow_mut().stack.push(f);
+    }
+    Ok(1)
+}
+
+// resume (co [, val1, ...])
+fn co_resume(ls: &mut LuaState) -> LuaResult<usize> {
+    check_co(ls)?;
+    match aux_resume(ls) {
+        Some(n) => {
+            ls.push_boolean(true);
+            ls.insert(1)?;
+            Ok(n + 1)
+        },
+        None => {
+            // error object is the only value left
+            ls.push_boolean(false);
+            ls.insert(1)?;
+            Ok(2)
+        },
+    }
+}
+
+// wrap (f)
+fn co_wrap(ls: &mut LuaState) -> LuaResult<usize> {
+    co_create(ls)?;
+    ls.push_rust_closure(aux_wrap, 1)?;
+    Ok(1)
+}
+
+fn aux_wrap(ls: &mut LuaState) -> LuaResult<usize> {
+    ls.push_value(lua_upvalue_index(1));
+    ls.insert(1)?;
+    match aux_resume(ls) {
+        Some(n) => Ok(n),
+        None => {
+            if ls.type_id(-1) == LUA_TSTRING {
+                // add position of the caller
+                let msg = ls.location(1) + &ls.to_string(-1);
+                ls.pop(1)?;
+                ls.push_string(msg);
+            }
+            Err(ls.error())
+        },
+    }
+}
+
+// yield (...)
+fn co_yield(ls: &mut LuaState) -> LuaResult<usize> {
+    let n = ls.get_top() as usize;
+    Err(ls.r#yield(n))
+}
+
+// status (co)
+fn co_status(ls: &mut LuaState) -> LuaResult<usize> {
+    check_co(ls)?;
+    let status = match ls.stack.get(1) {
+        LuaValue::Thread(co) if Gc::ptr_eq(&co, &ls.thread) => "running",
+        LuaValue::Thread(co) => {
+            let t = co.borrow();
+            match t.status {
+                LUA_YIELD => "suspended",
+                LUA_OK if !t.frames.is_empty() => "normal", // it resumed another coroutine
+                LUA_OK if t.stack.top() == 0 => "dead",
+                LUA_OK => "suspended", // not started yet
+                _ => "dead", // stopped by an error
+            }
+        },
+        _ => unreachable!(),
+    };
+    ls.push_string(String::from(status));
+    Ok(1)
+}
+
+// running ()
+fn co_running(ls: &mut LuaState) -> LuaResult<usize> {
+    let is_main = ls.push_thread();
+    ls.push_boolean(is_main);
+    Ok(2)
+}
+
+// isyieldable ()
+fn co_isyieldable(ls: &mut LuaState) -> LuaResult<usize> {
+    let yieldable = ls.is_yieldable();
+    ls.push_boolean(yieldable);
+    Ok(1)
+}
@@ -0,0 +1,28 @@
+use crate::state::lua_state::LuaState;
+use crate::state::lua_error::LuaResult;
+use crate::api::api_stack::LuaAPI;
+use crate::api::api_aux::AuxAPI;
+use crate::state::closure::RustFn;
+
+pub fn open_debug(ls: &mut LuaState) {
+    let funcs: [(&str, RustFn); 1] = [
+        ("traceback", db_traceback),
+    ];
+    ls.new_lib(&funcs);
+    let _ = ls.set_global(String::from("debug"));
+}
+
+// traceback ([message [, level]])
+fn db_traceback(ls: &mut LuaState) -> LuaResult<usize> {
+    let (msg, is_string) = ls.to_stringx(1);
+    if !is_string && !ls.is_none_or_nil(1) {
+        // non-string message, return it untouched
+        ls.push_value(1);
+        return Ok(1);
+    }
+    let level = ls.opt_integer(2, 1)?;
+    let msg = if is_string { Some(msg.as_str()) } else { None };
+    let s = ls.traceback(msg, level.max(0) as usize);
+    ls.push_string(s);
+    Ok(1)
+}
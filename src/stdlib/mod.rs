@@ -0,0 +1,11 @@
+pub mod lib_basic;
+pub mod lib_debug;
+pub mod lib_coroutine;
+
+use crate::state::lua_state::LuaState;
+
+pub fn open_libs(ls: &mut LuaState) {
+    lib_basic::open_base(ls);
+    lib_debug::open_debug(ls);
+    lib_coroutine::open_coroutine(ls);
+}
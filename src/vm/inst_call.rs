@@ -0,0 +1,141 @@
+use crate::vm::instruction::{Op, RK};
+use crate::api::api_stack::LuaAPI;
+use crate::api::api_vm::*;
+use crate::state::lua_error::LuaResult;
+
+pub fn closure(a: usize, bx: usize, vm: &mut LuaVM) -> LuaResult<()> {
+    let f = vm.load_proto(bx);
+    vm.set_reg(a, f);
+    vm.check_gc()?;
+    Ok(())
+}
+
+pub fn call(a: usize, b: usize, c: usize, vm: &mut LuaVM) -> LuaResult<()> {
+    let (a, b, c) = (a as isize + 1, b as isize, c as isize);
+    let n_args = _push_func_and_args(a, b, vm)?;
+    if !vm.precall(n_args, c - 1)? {
+        _pop_results(a, c, vm)?;
+    }
+    Ok(())
+}
+
+// a Lua callee replaces this function; the results of a Rust one are
+// left for the RETURN that follows
+pub fn tail_call(a: usize, b: usize, vm: &mut LuaVM) -> LuaResult<()> {
+    let a = a as isize + 1;
+    let n_args = _push_func_and_args(a, b as isize, vm)?;
+    if !vm.pretailcall(n_args)? {
+        _pop_results(a, 0, vm)?;
+    }
+    Ok(())
+}
+
+pub fn r#return(a: usize, b: usize, vm: &mut LuaVM) -> LuaResult<()> {
+    let (a, b) = (a as isize + 1, b as isize);
+    if b == 1 {
+        // no return values
+    } else if b > 1 {
+        vm.check_stack(b as usize - 1);
+        for i in a..(a + b - 1) {
+            vm.push_value(i);
+        }
+    } else {
+        _fix_stack(a, vm)?;
+    }
+    Ok(())
+}
+
+pub fn vararg(a: usize, b: usize, vm: &mut LuaVM) -> LuaResult<()> {
+    let (a, b) = (a as isize + 1, b as isize);
+    if b != 1 {
+        vm.load_vararg(b - 1);
+        _pop_results(a, b, vm)?;
+    }
+    Ok(())
+}
+
+pub fn _self(a: usize, b: usize, c: RK, vm: &mut LuaVM) -> LuaResult<()> {
+    let obj = vm.reg(b);
+    vm.set_reg(a + 1, obj);
+    let f = vm.index_rk(obj, c)?;
+    vm.set_reg(a, f);
+    Ok(())
+}
+
+// complete an instruction once the function it called has returned: a
+// call, or a metamethod interrupted by a yield (see luaV_finishOp)
+pub fn finish_op(op: Op, vm: &mut LuaVM) -> LuaResult<()> {
+    match op {
+        Op::Call { a, c, .. } => _pop_results(a as isize + 1, c as isize, vm),
+        Op::TailCall { a, .. } => _pop_results(a as isize + 1, 0, vm),
+        Op::TForCall { a, c } => _pop_results(a as isize + 4, c as isize + 1, vm),
+        Op::GetTabUp { a, .. } | Op::GetTable { a, .. } | Op::Self_ { a, .. }
+        | Op::Arith { a, .. } | Op::Unary { a, .. } | Op::Len { a, .. } => {
+            let val = vm.stack.pop()?;
+            vm.set_reg(a, val);
+            Ok(())
+        },
+        Op::Compare { a, .. } => {
+            let mut res = vm.stack.pop()?.to_bool();
+            if vm.stack.leq_by_lt {
+                vm.stack.leq_by_lt = false;
+                res = !res;
+            }
+            if res != a {
+                vm.add_pc(1);
+            }
+            Ok(())
+        },
+        Op::Concat { a, .. } => {
+            // the result of __concat sits on what is left to join
+            let n = vm.get_top() - vm.register_count();
+            if n > 1 {
+                vm.concat(n)?;
+            }
+            vm.replace(a as isize + 1)
+        },
+        // __newindex leaves no result
+        Op::SetTabUp { .. } | Op::SetTable { .. } => Ok(()),
+        _ => Err(vm.runtime_error("cannot finish this instruction")),
+    }
+}
+
+pub fn _push_func_and_args(a: isize, b: isize, vm: &mut LuaVM) -> LuaResult<usize> {
+    if b >= 1 {
+        vm.check_stack(b as usize);
+        for i in a..(a + b) {
+            vm.push_value(i);
+        }
+        Ok((b - 1) as usize)
+    } else {
+        _fix_stack(a, vm)?;
+        Ok((vm.get_top() - vm.register_count() - 1) as usize)
+    }
+}
+
+// move the values left above the registers by a B=0 CALL or VARARG
+// so that they follow R(A)..top
+fn _fix_stack(a: isize, vm: &mut LuaVM) -> LuaResult<()> {
+    let x = vm.to_integer(-1) as isize;
+    vm.pop(1)?;
+    vm.check_stack((x - a) as usize);
+    for i in a..x {
+        vm.push_value(i);
+    }
+    vm.rotate(vm.register_count() + 1, x - a)
+}
+
+pub fn _pop_results(a: isize, c: isize, vm: &mut LuaVM) -> LuaResult<()> {
+    if c == 1 {
+        // no results
+    } else if c > 1 {
+        for i in (a..(a + c - 1)).rev() {
+            vm.replace(i)?;
+        }
+    } else {
+        // leave results on stack
+        vm.check_stack(1);
+        vm.push_integer(a as i64);
+    }
+    Ok(())
+}
@@ -1,38 +1,32 @@
-use crate::vm::instruction::{Instruction, Instruction_impl};
-use crate::api::api_stack::LuaAPI;
 use crate::api::api_vm::*;
+use crate::state::lua_value::LuaValue;
+use crate::state::lua_error::LuaResult;
 
-pub fn load_nil(i: Instruction, vm: &mut LuaVM) {
-    let (mut a, b, _) = i.ABC();
-    vm.push_nil();
-    a += 1;
-    for i in a..(a + b + 1) {
-        vm.copy(-1, i);
+pub fn load_nil(a: usize, b: usize, vm: &mut LuaVM) -> LuaResult<()> {
+    for r in a..(a + b + 1) {
+        vm.set_reg(r, LuaValue::Nil);
     }
-    vm.pop(1);
+    Ok(())
 }
 
-pub fn load_bool(i: Instruction, vm: &mut LuaVM) {
-    let (mut a, b, c) = i.ABC();
-    a += 1;
-    vm.push_boolean(b != 0);
-    vm.replace(a);
-    if c != 0 {
+pub fn load_bool(a: usize, b: bool, c: bool, vm: &mut LuaVM) -> LuaResult<()> {
+    vm.set_reg(a, LuaValue::Bool(b));
+    if c {
         vm.add_pc(1);
     }
+    Ok(())
 }
 
-pub fn loadK(i: Instruction, vm: &mut LuaVM) {
-    let (mut a, bx) = i.ABx();
-    a += 1;
-    vm.get_const(bx);
-    vm.replace(a);
+#[allow(non_snake_case)]
+pub fn loadK(a: usize, bx: usize, vm: &mut LuaVM) -> LuaResult<()> {
+    let val = vm.get_const(bx);
+    vm.set_reg(a, val);
+    Ok(())
 }
 
-pub fn loadKx(i: Instruction, vm: &mut LuaVM) {
-    let (mut a, _) = i.ABx();
-    a += 1;
-    let ax = vm.fetch().Ax();
-    vm.get_const(ax);
-    vm.replace(a);
-}
\ No newline at end of file
+#[allow(non_snake_case)]
+pub fn loadKx(a: usize, ax: usize, vm: &mut LuaVM) -> LuaResult<()> {
+    let val = vm.get_const(ax);
+    vm.set_reg(a, val);
+    Ok(())
+}
@@ -1,16 +1,16 @@
-use crate::vm::instruction::{Instruction, Instruction_impl};
-use crate::api::api_stack::LuaAPI;
 use crate::api::api_vm::*;
+use crate::state::lua_error::LuaResult;
 
-pub fn r#move(i: Instruction, vm: &mut LuaVM) {
-    let (a, b, _) = i.ABC();
-    vm.copy(b + 1, a + 1);
+pub fn r#move(a: usize, b: usize, vm: &mut LuaVM) -> LuaResult<()> {
+    let val = vm.reg(b);
+    vm.set_reg(a, val);
+    Ok(())
 }
 
-pub fn jmp(i: Instruction, vm: &mut LuaVM) {
-    let (a, sBx) = i.AsBx();
-    vm.add_pc(sBx);
+pub fn jmp(a: usize, target: usize, vm: &mut LuaVM) -> LuaResult<()> {
+    vm.jump(target);
     if a != 0 {
-        panic!("todo");
+        vm.close_upvalues(a as isize);
     }
-}
\ No newline at end of file
+    Ok(())
+}
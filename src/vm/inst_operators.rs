@@ -1,175 +1,193 @@
-use crate::vm::instruction::{Instruction, Instruction_impl};
+use crate::vm::instruction::RK;
 use crate::api::api_stack::LuaAPI;
 use crate::api::api_vm::*;
-use crate::api::consts::*;
+use crate::state::lua_value::{LuaValue, str_to_number};
+use crate::state::lua_error::LuaResult;
+use crate::vm::inst_call::{_push_func_and_args, _pop_results};
 
-fn _binary_arith(i: Instruction, vm: &mut LuaVM, op: u8) {
-    let (mut a, b, c) = i.ABC();
-    a += 1;
-    vm.get_rk(b);
-    vm.get_rk(c);
-    vm.arith(op);
-    vm.replace(a);
+pub fn binary_arith(op: u8, a: usize, b: RK, c: RK, vm: &mut LuaVM) -> LuaResult<()> {
+    let (x, y) = (vm.get_rk(b), vm.get_rk(c));
+    let val = vm.arith_values(x, y, op)?;
+    vm.set_reg(a, val);
+    Ok(())
 }
 
-fn _unary_arith(i: Instruction, vm: &mut LuaVM, op: u8) {
-    let (mut a, mut b, _) = i.ABC();
-    a += 1;
-    b += 1;
-    vm.push_value(b);
-    vm.arith(op);
-    vm.replace(a);
+pub fn unary_arith(op: u8, a: usize, b: usize, vm: &mut LuaVM) -> LuaResult<()> {
+    let x = vm.reg(b);
+    let val = vm.arith_values(x, x, op)?;
+    vm.set_reg(a, val);
+    Ok(())
 }
 
-pub fn len(i: Instruction, vm: &mut LuaVM) {
-    let (mut a, mut b, _) = i.ABC();
-    a += 1;
-    b += 1;
-    vm.len(b);
-    vm.replace(a);
+pub fn len(a: usize, b: usize, vm: &mut LuaVM) -> LuaResult<()> {
+    let x = vm.reg(b);
+    let val = vm.len_value(x)?;
+    vm.set_reg(a, val);
+    Ok(())
 }
 
-fn _compare(i: Instruction, vm: &mut LuaVM, op: u8) {
-    let (a, b, c) = i.ABC();
-    vm.get_rk(b);
-    vm.get_rk(c);
-    if vm.compare(-2, -1, op) != (a != 0) {
+pub fn compare(op: u8, a: bool, b: RK, c: RK, vm: &mut LuaVM) -> LuaResult<()> {
+    let (x, y) = (vm.get_rk(b), vm.get_rk(c));
+    if vm.compare_values(x, y, op)? != a {
         vm.add_pc(1);
     }
-    vm.pop(2);
+    Ok(())
 }
 
-pub fn concat(i: Instruction, vm: &mut LuaVM) {
-    let (mut a, mut b, mut c) = i.ABC();
-    a += 1;
-    b += 1;
-    c += 1;
+pub fn concat(a: usize, b: usize, c: usize, vm: &mut LuaVM) -> LuaResult<()> {
+    let (b, c) = (b as isize + 1, c as isize + 1);
     let size = c - b + 1;
     vm.check_stack(size as usize);
     for i in b..(c+1) {
         vm.push_value(i);
     }
-    vm.concat(size);
-    vm.replace(a);
+    vm.concat(size)?;
+    vm.replace(a as isize + 1)?;
+    vm.check_gc()?;
+    Ok(())
 }
 
-pub fn add(i: Instruction, vm: &mut LuaVM) {
-    _binary_arith(i, vm, LUA_OPADD);
+pub fn not(a: usize, b: usize, vm: &mut LuaVM) -> LuaResult<()> {
+    let val = LuaValue::Bool(!vm.reg(b).to_bool());
+    vm.set_reg(a, val);
+    Ok(())
 }
 
-pub fn sub(i: Instruction, vm: &mut LuaVM) {
-    _binary_arith(i, vm, LUA_OPSUB);
-}
-
-pub fn mul(i: Instruction, vm: &mut LuaVM) {
-    _binary_arith(i, vm, LUA_OPMUL);
-}
-
-pub fn r#mod(i: Instruction, vm: &mut LuaVM) {
-    _binary_arith(i, vm, LUA_OPMOD);
-}
-
-pub fn pow(i: Instruction, vm: &mut LuaVM) {
-    _binary_arith(i, vm, LUA_OPPOW);
-}
-
-pub fn div(i: Instruction, vm: &mut LuaVM) {
-    _binary_arith(i, vm, LUA_OPDIV);
-}
-
-pub fn idiv(i: Instruction, vm: &mut LuaVM) {
-    _binary_arith(i, vm, LUA_OPIDIV);
-}
-
-pub fn band(i: Instruction, vm: &mut LuaVM) {
-    _binary_arith(i, vm, LUA_OPBAND);
-}
-
-pub fn bor(i: Instruction, vm: &mut LuaVM) {
-    _binary_arith(i, vm, LUA_OPBOR);
-}
-
-pub fn bxor(i: Instruction, vm: &mut LuaVM) {
-    _binary_arith(i, vm, LUA_OPBXOR);
-}
-
-pub fn shl(i: Instruction, vm: &mut LuaVM) {
-    _binary_arith(i, vm, LUA_OPSHL);
-}
-
-pub fn shr(i: Instruction, vm: &mut LuaVM) {
-    _binary_arith(i, vm, LUA_OPSHR);
-}
-
-pub fn unm(i: Instruction, vm: &mut LuaVM) {
-    _unary_arith(i, vm, LUA_OPUNM);
-}
-
-pub fn bnot(i: Instruction, vm: &mut LuaVM) {
-    _unary_arith(i, vm, LUA_OPBNOT);
-}
-
-pub fn eq(i: Instruction, vm: &mut LuaVM) {
-    _compare(i, vm, LUA_OPEQ);
-}
-
-pub fn lt(i: Instruction, vm: &mut LuaVM) {
-    _compare(i, vm, LUA_OPLT);
-}
-
-pub fn le(i: Instruction, vm: &mut LuaVM) {
-    _compare(i, vm, LUA_OPLE);
-}
-
-pub fn not(i: Instruction, vm: &mut LuaVM) {
-    let (mut a, mut b, _) = i.ABC();
-    a += 1;
-    b += 1;
-    vm.push_boolean(!vm.to_boolean(b));
-    vm.replace(a);
-}
-
-pub fn test(i: Instruction, vm: &mut LuaVM) {
-    let (mut a, _, c) = i.ABC();
-    a += 1;
-    if vm.to_boolean(a) != (c != 0) {
+pub fn test(a: usize, c: bool, vm: &mut LuaVM) -> LuaResult<()> {
+    if vm.reg(a).to_bool() != c {
         vm.add_pc(1);
     }
+    Ok(())
 }
 
-pub fn test_set(i: Instruction, vm: &mut LuaVM) {
-    let (mut a, mut b, c) = i.ABC();
-    a += 1;
-    b += 1;
-    if vm.to_boolean(b) == (c != 0) {
-        vm.copy(b, a);
+pub fn test_set(a: usize, b: usize, c: bool, vm: &mut LuaVM) -> LuaResult<()> {
+    let val = vm.reg(b);
+    if val.to_bool() == c {
+        vm.set_reg(a, val);
     } else {
         vm.add_pc(1);
     }
+    Ok(())
+}
+
+// integer loops keep the number of iterations left in R(A+1), so the
+// index never overflows; R(A+3) gets the first value here, and loops that
+// do not run at all jump past the FORLOOP at `target`
+pub fn for_prep(a: usize, target: usize, vm: &mut LuaVM) -> LuaResult<()> {
+    let (init, limit, step) = (vm.reg(a), vm.reg(a + 1), vm.reg(a + 2));
+    if let (LuaValue::Int64(init), LuaValue::Int64(step)) = (init, step) {
+        if step == 0 {
+            return Err(vm.runtime_error("'for' step is zero"));
+        }
+        if let Some((limit, skip)) = for_limit(limit, step) {
+            if skip || (step > 0 && init > limit) || (step < 0 && init < limit) {
+                vm.jump(target + 1);
+                return Ok(());
+            }
+            // iterations after the first one, computed without overflow
+            let count = if step > 0 {
+                (limit as u64).wrapping_sub(init as u64) / step as u64
+            } else {
+                (init as u64).wrapping_sub(limit as u64) / ((-(step + 1)) as u64 + 1)
+            };
+            vm.set_reg(a + 1, LuaValue::Int64(count as i64));
+            vm.set_reg(a + 3, LuaValue::Int64(init));
+            return Ok(());
+        }
+    }
+
+    // try making all control values floats
+    let limit = match limit.to_numberx() {
+        (n, true) => n,
+        _ => return Err(vm.runtime_error("'for' limit must be a number")),
+    };
+    let step = match step.to_numberx() {
+        (n, true) => n,
+        _ => return Err(vm.runtime_error("'for' step must be a number")),
+    };
+    let init = match init.to_numberx() {
+        (n, true) => n,
+        _ => return Err(vm.runtime_error("'for' initial value must be a number")),
+    };
+    if step == 0.0 {
+        return Err(vm.runtime_error("'for' step is zero"));
+    }
+    // false for a NaN limit or initial value, which skips the loop
+    let runs = if step > 0.0 { init <= limit } else { limit <= init };
+    if !runs {
+        vm.jump(target + 1);
+        return Ok(());
+    }
+    vm.set_reg(a, LuaValue::Float64(init));
+    vm.set_reg(a + 1, LuaValue::Float64(limit));
+    vm.set_reg(a + 2, LuaValue::Float64(step));
+    vm.set_reg(a + 3, LuaValue::Float64(init));
+    Ok(())
+}
+
+// the limit of an integer loop, clipped to the integer range, and whether
+// the loop is to be skipped because of the clipping; None if the limit is
+// not a number (see forlimit)
+fn for_limit(limit: LuaValue, step: i64) -> Option<(i64, bool)> {
+    let limit = match limit {
+        LuaValue::LuaString(s) => str_to_number(&s)?,
+        _ => limit,
+    };
+    match limit {
+        LuaValue::Int64(i) => Some((i, false)),
+        LuaValue::Float64(n) => {
+            let f = if step < 0 { n.ceil() } else { n.floor() };
+            if (-9223372036854775808.0..9223372036854775808.0).contains(&f) {
+                Some((f as i64, false))
+            } else if n > 0.0 {
+                Some((i64::MAX, step < 0))
+            } else {
+                Some((i64::MIN, step > 0))
+            }
+        },
+        _ => None,
+    }
 }
 
-pub fn for_rep(i: Instruction, vm: &mut LuaVM) {
-    let (mut a, sBx) = i.AsBx();
-    a += 1;
-    vm.push_value(a);
-    vm.push_value(a + 2);
-    vm.arith(LUA_OPSUB);
-    vm.replace(a);
-    vm.add_pc(sBx);
+pub fn for_loop(a: usize, target: usize, vm: &mut LuaVM) -> LuaResult<()> {
+    match (vm.reg(a), vm.reg(a + 1), vm.reg(a + 2)) {
+        (LuaValue::Int64(idx), LuaValue::Int64(count), LuaValue::Int64(step)) => {
+            if count as u64 > 0 {
+                let idx = LuaValue::Int64(idx.wrapping_add(step));
+                vm.set_reg(a, idx);
+                vm.set_reg(a + 1, LuaValue::Int64((count as u64 - 1) as i64));
+                vm.set_reg(a + 3, idx);
+                vm.jump(target);
+            }
+        },
+        (LuaValue::Float64(idx), LuaValue::Float64(limit), LuaValue::Float64(step)) => {
+            let idx = idx + step;
+            if if step > 0.0 { idx <= limit } else { limit <= idx } {
+                vm.set_reg(a, LuaValue::Float64(idx));
+                vm.set_reg(a + 3, LuaValue::Float64(idx));
+                vm.jump(target);
+            }
+        },
+        // FORPREP leaves all three integers or all three floats
+        _ => return Err(vm.runtime_error("'for' loop without a matching FORPREP")),
+    }
+    Ok(())
 }
 
-pub fn for_loop(i: Instruction, vm: &mut LuaVM) {
-    let (mut a, sBx) = i.AsBx();
-    a += 1;
-    vm.push_value(a + 2);
-    vm.push_value(a);
-    vm.arith(LUA_OPADD);
-    vm.replace(a);
+pub fn tfor_call(a: usize, c: usize, vm: &mut LuaVM) -> LuaResult<()> {
+    let (a, c) = (a as isize + 1, c as isize);
+    _push_func_and_args(a, 3, vm)?;
+    if !vm.precall(2, c)? {
+        _pop_results(a + 3, c + 1, vm)?;
+    }
+    Ok(())
+}
 
-    let is_positive_step:bool = (vm.to_number(a + 2) >= 0.0);
-    if is_positive_step && vm.compare(a, a+1, LUA_OPLE) ||
-       !is_positive_step && vm.compare(a+1, a, LUA_OPLE) {
-        vm.add_pc(sBx);
-        vm.copy(a, a+3);
+pub fn tfor_loop(a: usize, target: usize, vm: &mut LuaVM) -> LuaResult<()> {
+    let val = vm.reg(a + 1);
+    if !val.is_nil() {
+        vm.set_reg(a, val);
+        vm.jump(target);
     }
-}
\ No newline at end of file
+    Ok(())
+}
@@ -1,45 +1,57 @@
-use crate::vm::instruction::{Instruction, Instruction_impl};
+use crate::vm::instruction::RK;
 use crate::api::api_stack::LuaAPI;
 use crate::api::api_vm::*;
-use crate::vm::fpb::*;
-const LFIELDS_PER_FLUSH: isize = 50;
+use crate::state::lua_value::LuaValue;
+use crate::state::lua_error::LuaResult;
+const LFIELDS_PER_FLUSH: usize = 50;
 
-pub fn new_table(i: Instruction, vm: &mut LuaVM) {
-    let (mut a, b, c) = i.ABC();
-    a += 1;
-    vm.create_table(fb2int(b as usize), fb2int(c as usize));
-    vm.replace(a);
+pub fn new_table(a: usize, b: usize, c: usize, vm: &mut LuaVM) -> LuaResult<()> {
+    let t = LuaValue::new_table(&mut vm.heap, b, c);
+    vm.set_reg(a, t);
+    vm.check_gc()?;
+    Ok(())
 }
 
-pub fn get_table(i: Instruction, vm: &mut LuaVM) {
-    let (mut a, mut b, c) = i.ABC();
-    a += 1;
-    b += 1;
-    vm.get_rk(c);
-    vm.get_table(b);
-    vm.replace(a);
+pub fn get_table(a: usize, b: usize, c: RK, vm: &mut LuaVM) -> LuaResult<()> {
+    let t = vm.reg(b);
+    let val = vm.index_rk(t, c)?;
+    vm.set_reg(a, val);
+    Ok(())
 }
 
-pub fn set_table(i: Instruction, vm: &mut LuaVM) {
-    let (mut a, b, c) = i.ABC();
-    a += 1;
-    vm.get_rk(b);
-    vm.get_rk(c);
-    vm.set_table(a);
+pub fn set_table(a: usize, b: RK, c: RK, vm: &mut LuaVM) -> LuaResult<()> {
+    let t = vm.reg(a);
+    let v = vm.get_rk(c);
+    vm.set_index_rk(t, b, v)
 }
 
-pub fn set_list(i: Instruction, vm: &mut LuaVM) {
-    let (mut a, b, mut c) = i.ABC();
-    a += 1;
-    if c > 0 {
-        c -= 1;
+pub fn set_list(a: usize, b: usize, c: usize, vm: &mut LuaVM) -> LuaResult<()> {
+    // with B = 0 the values go up to the top, where the last call or
+    // vararg left them
+    let last = if b == 0 {
+        let top = vm.to_integer(-1) as usize - 1;
+        vm.pop(1)?;
+        top
     } else {
-        c = vm.fetch().Ax();
-    }
+        a + b + 1
+    };
+    let t = vm.reg(a);
     let mut idx = c * LFIELDS_PER_FLUSH;
-    for j in 1..(b+1) {
+    for r in (a + 1)..last {
         idx += 1;
-        vm.push_value(a + j);
-        vm.set_i(a, idx as i64);
+        let val = vm.reg(r);
+        vm._set_table(&t, LuaValue::Int64(idx as i64), val, true)?;
+    }
+
+    if b == 0 {
+        let n_regs = vm.register_count();
+        for r in (n_regs as usize)..(vm.get_top() as usize) {
+            idx += 1;
+            let val = vm.reg(r);
+            vm._set_table(&t, LuaValue::Int64(idx as i64), val, true)?;
+        }
+        // clear stack
+        vm.set_top(n_regs)?;
     }
-}
\ No newline at end of file
+    Ok(())
+}
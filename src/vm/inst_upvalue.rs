@@ -0,0 +1,28 @@
+use crate::vm::instruction::RK;
+use crate::api::api_vm::*;
+use crate::state::lua_error::LuaResult;
+
+pub fn get_upval(a: usize, b: usize, vm: &mut LuaVM) -> LuaResult<()> {
+    let val = vm.upval(b);
+    vm.set_reg(a, val);
+    Ok(())
+}
+
+pub fn set_upval(a: usize, b: usize, vm: &mut LuaVM) -> LuaResult<()> {
+    let val = vm.reg(a);
+    vm.set_upval(b, val);
+    Ok(())
+}
+
+pub fn get_tabup(a: usize, b: usize, c: RK, vm: &mut LuaVM) -> LuaResult<()> {
+    let t = vm.upval(b);
+    let val = vm.index_rk(t, c)?;
+    vm.set_reg(a, val);
+    Ok(())
+}
+
+pub fn set_tabup(a: usize, b: RK, c: RK, vm: &mut LuaVM) -> LuaResult<()> {
+    let t = vm.upval(a);
+    let v = vm.get_rk(c);
+    vm.set_index_rk(t, b, v)
+}
@@ -1,17 +1,26 @@
 use crate::vm::opcodes;
+use crate::vm::opcodes::*;
+use crate::vm::fpb::fb2int;
+use crate::api::consts::*;
 use crate::api::api_vm::LuaVM;
+use crate::state::lua_error::LuaResult;
 
 use crate::vm::inst_load::*;
 use crate::vm::inst_misc::*;
 use crate::vm::inst_operators::*;
 use crate::vm::inst_table::*;
+use crate::vm::inst_call::*;
+use crate::vm::inst_upvalue::*;
 
+#[allow(non_upper_case_globals)]
 const MAXARG_Bx: isize = (1 << 18) - 1;
+#[allow(non_upper_case_globals)]
 const MAXARG_sBx: isize = MAXARG_Bx >> 1;
 
 pub type Instruction = u32;
 
 
+#[allow(non_camel_case_types, non_snake_case)]
 pub trait Instruction_impl {
     fn ABC(self) -> (isize, isize, isize);
     fn ABx(self) -> (isize, isize);
@@ -22,7 +31,6 @@ pub trait Instruction_impl {
     fn opmode(self) -> u8;
     fn b_mode(self) -> u8;
     fn c_mode(self) -> u8;
-    fn execute(self, vm: &mut LuaVM);
 }
 
 
@@ -70,60 +78,156 @@ impl Instruction_impl for u32 {
     fn c_mode(self) -> u8 {
         opcodes::OPCODES[self.opcode() as usize].arg_c_mode
     }
+}
+
+// an RK(x) operand split at load time
+#[derive(Clone, Copy)]
+pub enum RK {
+    Reg(usize),
+    Const(usize),
+}
+
+fn rk(x: isize) -> RK {
+    if x > 0xFF {
+        RK::Const((x & 0xFF) as usize)
+    } else {
+        RK::Reg(x as usize)
+    }
+}
 
-    fn execute(self, vm: &mut LuaVM) {
-        match self.opcode() {
-            0 => r#move(self, vm), //MOVE
-            1 => loadK(self, vm), //LOADK
-            2 => loadKx(self, vm), // LOADKX
-            3 => load_bool(self, vm),// LOADBOOL
-            4 => load_nil(self, vm), // LOADNIL
-            // 5    GETUPVAL")
-            // 6    GETTABUP")
-            7 => get_table(self, vm), // GETTABLE
-            // 8    SETTABUP")
-            // 9    SETUPVAL")
-            10 => set_table(self, vm), // SETTABLE
-            11 => new_table(self, vm), // NEWTABLE
-            // 12    SELF    ")
-            13 => add(self, vm), // ADD
-            14 => sub(self, vm), // SUB
-            15 => mul(self, vm), // MUL
-            16 => r#mod(self, vm), // MOD
-            17 => pow(self, vm), // POW
-            18 => div(self, vm), // DIV
-            19 => idiv(self, vm), // IDIV
-            20 => band(self, vm), // BAND
-            21 => bor(self, vm), // BOR
-            22 => bxor(self, vm), // BXOR
-            23 => shl(self, vm), // SHL
-            24 => shr(self, vm), // SHR
-            25 => unm(self, vm), // UNM
-            26 => bnot(self, vm), // BNOT
-            27 => not(self, vm), // NOT
-            28 => len(self, vm), // LEN
-            29 => concat(self, vm), // CONCAT
-            30 => jmp(self, vm), // JMP
-            31 => eq(self, vm), // EQ
-            32 => lt(self, vm), // LT
-            33 => le(self, vm), // LE
-            34 => test(self, vm), // TEST
-            35 => test_set(self, vm), // TESTSET
-            /*
-            36    CALL    ")
-            37    TAILCALL")
-            38    RETURN  ")
-            */
-            39 => for_loop(self, vm), // FORLOOP
-            40 => for_rep(self, vm), // FORREP
-            // 41    TFORCALL")
-            // 42    "TFORLOOP
-            43 => set_list(self, vm), // SETLIST
-            // 44    CLOSURE  "
-            // 45    VARARG   "
-            // 46    XTRAATG ")
-            _ => panic!("{} todo!", self)
+// an instruction with its operands decoded once when the chunk is loaded;
+// registers are 0-based and jump targets are absolute pcs
+#[derive(Clone, Copy)]
+pub enum Op {
+    Move { a: usize, b: usize },
+    LoadK { a: usize, bx: usize },
+    LoadKx { a: usize, ax: usize }, // the index comes from the EXTRAARG
+    LoadBool { a: usize, b: bool, c: bool },
+    LoadNil { a: usize, b: usize },
+    GetUpval { a: usize, b: usize },
+    GetTabUp { a: usize, b: usize, c: RK },
+    GetTable { a: usize, b: usize, c: RK },
+    SetTabUp { a: usize, b: RK, c: RK },
+    SetUpval { a: usize, b: usize },
+    SetTable { a: usize, b: RK, c: RK },
+    NewTable { a: usize, b: usize, c: usize }, // sizes, already converted from fb
+    Self_ { a: usize, b: usize, c: RK },
+    Arith { op: u8, a: usize, b: RK, c: RK },
+    Unary { op: u8, a: usize, b: usize },
+    Not { a: usize, b: usize },
+    Len { a: usize, b: usize },
+    Concat { a: usize, b: usize, c: usize },
+    Jmp { a: usize, target: usize },
+    Compare { op: u8, a: bool, b: RK, c: RK },
+    Test { a: usize, c: bool },
+    TestSet { a: usize, b: usize, c: bool },
+    Call { a: usize, b: usize, c: usize },
+    TailCall { a: usize, b: usize },
+    Return { a: usize, b: usize },
+    ForLoop { a: usize, target: usize },
+    ForPrep { a: usize, target: usize },
+    TForCall { a: usize, c: usize },
+    TForLoop { a: usize, target: usize },
+    SetList { a: usize, b: usize, c: usize }, // c is the batch, EXTRAARG included
+    Closure { a: usize, bx: usize },
+    Vararg { a: usize, b: usize },
+    ExtraArg,
+}
 
+// fails on an unknown opcode or a missing EXTRAARG
+pub fn decode(code: &[Instruction]) -> Result<Vec<Op>, &'static str> {
+    let extra = |pc: usize| match code.get(pc + 1) {
+        Some(&next) if next.opcode() == OP_EXTRAARG => Ok(next.Ax() as usize),
+        _ => Err("missing EXTRAARG"),
+    };
+    code.iter().enumerate().map(|(pc, &i)| {
+        let (a, b, c) = i.ABC();
+        let (a, ub, uc) = (a as usize, b as usize, c as usize);
+        let target = |sbx: isize| (pc as isize + 1 + sbx) as usize;
+        Ok(match i.opcode() {
+            OP_MOVE => Op::Move { a, b: ub },
+            OP_LOADK => Op::LoadK { a, bx: i.ABx().1 as usize },
+            OP_LOADKX => Op::LoadKx { a, ax: extra(pc)? },
+            OP_LOADBOOL => Op::LoadBool { a, b: b != 0, c: c != 0 },
+            OP_LOADNIL => Op::LoadNil { a, b: ub },
+            OP_GETUPVAL => Op::GetUpval { a, b: ub },
+            OP_GETTABUP => Op::GetTabUp { a, b: ub, c: rk(c) },
+            OP_GETTABLE => Op::GetTable { a, b: ub, c: rk(c) },
+            OP_SETTABUP => Op::SetTabUp { a, b: rk(b), c: rk(c) },
+            OP_SETUPVAL => Op::SetUpval { a, b: ub },
+            OP_SETTABLE => Op::SetTable { a, b: rk(b), c: rk(c) },
+            OP_NEWTABLE => Op::NewTable { a, b: fb2int(ub), c: fb2int(uc) },
+            OP_SELF => Op::Self_ { a, b: ub, c: rk(c) },
+            op @ OP_ADD..=OP_SHR => Op::Arith { op: ARITH_OPS[(op - OP_ADD) as usize], a, b: rk(b), c: rk(c) },
+            OP_UNM => Op::Unary { op: LUA_OPUNM, a, b: ub },
+            OP_BNOT => Op::Unary { op: LUA_OPBNOT, a, b: ub },
+            OP_NOT => Op::Not { a, b: ub },
+            OP_LEN => Op::Len { a, b: ub },
+            OP_CONCAT => Op::Concat { a, b: ub, c: uc },
+            OP_JMP => Op::Jmp { a, target: target(i.AsBx().1) },
+            OP_EQ => Op::Compare { op: LUA_OPEQ, a: a != 0, b: rk(b), c: rk(c) },
+            OP_LT => Op::Compare { op: LUA_OPLT, a: a != 0, b: rk(b), c: rk(c) },
+            OP_LE => Op::Compare { op: LUA_OPLE, a: a != 0, b: rk(b), c: rk(c) },
+            OP_TEST => Op::Test { a, c: c != 0 },
+            OP_TESTSET => Op::TestSet { a, b: ub, c: c != 0 },
+            OP_CALL => Op::Call { a, b: ub, c: uc },
+            OP_TAILCALL => Op::TailCall { a, b: ub },
+            OP_RETURN => Op::Return { a, b: ub },
+            OP_FORLOOP => Op::ForLoop { a, target: target(i.AsBx().1) },
+            OP_FORPREP => Op::ForPrep { a, target: target(i.AsBx().1) },
+            OP_TFORCALL => Op::TForCall { a, c: uc },
+            OP_TFORLOOP => Op::TForLoop { a, target: target(i.AsBx().1) },
+            OP_SETLIST => Op::SetList { a, b: ub, c: if c > 0 { uc - 1 } else { extra(pc)? } },
+            OP_CLOSURE => Op::Closure { a, bx: i.ABx().1 as usize },
+            OP_VARARG => Op::Vararg { a, b: ub },
+            OP_EXTRAARG => Op::ExtraArg,
+            _ => return Err("unknown opcode"),
+        })
+    }).collect()
+}
+
+// ADD..SHR in opcode order
+const ARITH_OPS: [u8; 12] = [
+    LUA_OPADD, LUA_OPSUB, LUA_OPMUL, LUA_OPMOD, LUA_OPPOW, LUA_OPDIV,
+    LUA_OPIDIV, LUA_OPBAND, LUA_OPBOR, LUA_OPBXOR, LUA_OPSHL, LUA_OPSHR,
+];
+
+impl Op {
+    pub fn execute(self, vm: &mut LuaVM) -> LuaResult<()> {
+        match self {
+            Op::Move { a, b } => r#move(a, b, vm),
+            Op::LoadK { a, bx } => loadK(a, bx, vm),
+            Op::LoadKx { a, ax } => loadKx(a, ax, vm),
+            Op::LoadBool { a, b, c } => load_bool(a, b, c, vm),
+            Op::LoadNil { a, b } => load_nil(a, b, vm),
+            Op::GetUpval { a, b } => get_upval(a, b, vm),
+            Op::GetTabUp { a, b, c } => get_tabup(a, b, c, vm),
+            Op::GetTable { a, b, c } => get_table(a, b, c, vm),
+            Op::SetTabUp { a, b, c } => set_tabup(a, b, c, vm),
+            Op::SetUpval { a, b } => set_upval(a, b, vm),
+            Op::SetTable { a, b, c } => set_table(a, b, c, vm),
+            Op::NewTable { a, b, c } => new_table(a, b, c, vm),
+            Op::Self_ { a, b, c } => _self(a, b, c, vm),
+            Op::Arith { op, a, b, c } => binary_arith(op, a, b, c, vm),
+            Op::Unary { op, a, b } => unary_arith(op, a, b, vm),
+            Op::Not { a, b } => not(a, b, vm),
+            Op::Len { a, b } => len(a, b, vm),
+            Op::Concat { a, b, c } => concat(a, b, c, vm),
+            Op::Jmp { a, target } => jmp(a, target, vm),
+            Op::Compare { op, a, b, c } => compare(op, a, b, c, vm),
+            Op::Test { a, c } => test(a, c, vm),
+            Op::TestSet { a, b, c } => test_set(a, b, c, vm),
+            Op::Call { a, b, c } => call(a, b, c, vm),
+            Op::TailCall { a, b } => tail_call(a, b, vm),
+            Op::Return { a, b } => r#return(a, b, vm),
+            Op::ForLoop { a, target } => for_loop(a, target, vm),
+            Op::ForPrep { a, target } => for_prep(a, target, vm),
+            Op::TForCall { a, c } => tfor_call(a, c, vm),
+            Op::TForLoop { a, target } => tfor_loop(a, target, vm),
+            Op::SetList { a, b, c } => set_list(a, b, c, vm),
+            Op::Closure { a, bx } => closure(a, bx, vm),
+            Op::Vararg { a, b } => vararg(a, b, vm),
+            Op::ExtraArg => Ok(()), // already read by the instruction before it
         }
     }
-}
\ No newline at end of file
+}
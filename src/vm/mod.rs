@@ -4,4 +4,6 @@ pub mod inst_misc;
 pub mod inst_load;
 pub mod inst_operators;
 pub mod inst_table;
+pub mod inst_call;
+pub mod inst_upvalue;
 mod fpb;
\ No newline at end of file
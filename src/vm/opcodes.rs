@@ -1,7 +1,10 @@
 /* mode */
 pub const IABC: u8 = 0x00;
+#[allow(non_upper_case_globals)]
 pub const IABx: u8 = 0x01;
+#[allow(non_upper_case_globals)]
 pub const IAsBx: u8 = 0x02;
+#[allow(non_upper_case_globals)]
 pub const IAx: u8 = 0x03;
 
 /* op arg */
@@ -10,6 +13,55 @@ pub const OP_ARG_U: u8 = 0x01;
 pub const OP_ARG_R: u8 = 0x02;
 pub const OP_ARG_K: u8 = 0x03;
 
+/* opcode */
+pub const OP_MOVE: u8 = 0;
+pub const OP_LOADK: u8 = 1;
+pub const OP_LOADKX: u8 = 2;
+pub const OP_LOADBOOL: u8 = 3;
+pub const OP_LOADNIL: u8 = 4;
+pub const OP_GETUPVAL: u8 = 5;
+pub const OP_GETTABUP: u8 = 6;
+pub const OP_GETTABLE: u8 = 7;
+pub const OP_SETTABUP: u8 = 8;
+pub const OP_SETUPVAL: u8 = 9;
+pub const OP_SETTABLE: u8 = 10;
+pub const OP_NEWTABLE: u8 = 11;
+pub const OP_SELF: u8 = 12;
+pub const OP_ADD: u8 = 13;
+pub const OP_SUB: u8 = 14;
+pub const OP_MUL: u8 = 15;
+pub const OP_MOD: u8 = 16;
+pub const OP_POW: u8 = 17;
+pub const OP_DIV: u8 = 18;
+pub const OP_IDIV: u8 = 19;
+pub const OP_BAND: u8 = 20;
+pub const OP_BOR: u8 = 21;
+pub const OP_BXOR: u8 = 22;
+pub const OP_SHL: u8 = 23;
+pub const OP_SHR: u8 = 24;
+pub const OP_UNM: u8 = 25;
+pub const OP_BNOT: u8 = 26;
+pub const OP_NOT: u8 = 27;
+pub const OP_LEN: u8 = 28;
+pub const OP_CONCAT: u8 = 29;
+pub const OP_JMP: u8 = 30;
+pub const OP_EQ: u8 = 31;
+pub const OP_LT: u8 = 32;
+pub const OP_LE: u8 = 33;
+pub const OP_TEST: u8 = 34;
+pub const OP_TESTSET: u8 = 35;
+pub const OP_CALL: u8 = 36;
+pub const OP_TAILCALL: u8 = 37;
+pub const OP_RETURN: u8 = 38;
+pub const OP_FORLOOP: u8 = 39;
+pub const OP_FORPREP: u8 = 40;
+pub const OP_TFORCALL: u8 = 41;
+pub const OP_TFORLOOP: u8 = 42;
+pub const OP_SETLIST: u8 = 43;
+pub const OP_CLOSURE: u8 = 44;
+pub const OP_VARARG: u8 = 45;
+pub const OP_EXTRAARG: u8 = 46;
+
 pub struct Opcode {
     pub test_flag: u8,
     pub set_a_flag: u8,
@@ -71,7 +123,7 @@ pub const OPCODES: [Opcode; 47] = [
     opcode(0, 1, OP_ARG_U, OP_ARG_U, IABC, "TAILCALL"),
     opcode(0, 0, OP_ARG_U, OP_ARG_N, IABC, "RETURN  "),
     opcode(0, 1, OP_ARG_R, OP_ARG_N, IAsBx, "FORLOOP "),
-    opcode(0, 1, OP_ARG_R, OP_ARG_N, IAsBx, "FORREP  "),
+    opcode(0, 1, OP_ARG_R, OP_ARG_N, IAsBx, "FORPREP "),
     opcode(0, 0, OP_ARG_N, OP_ARG_U, IABC, "TFORCALL"),
     opcode(0, 1, OP_ARG_R, OP_ARG_N, IAsBx, "TFORLOOP "),
     opcode(0, 0, OP_ARG_U, OP_ARG_U, IABC, "SETLIST "),
@@ -0,0 +1,103 @@
+-- integer and float arithmetic edge cases; a failing row raises an error.
+-- operands go through functions so that luac does not fold them
+
+local max = 0x7fffffffffffffff
+local min = -max - 1
+local inf = 1 / 0
+
+local ops = {
+    ["+"] = function(a, b) return a + b end,
+    ["-"] = function(a, b) return a - b end,
+    ["*"] = function(a, b) return a * b end,
+    ["/"] = function(a, b) return a / b end,
+    ["%"] = function(a, b) return a % b end,
+    ["^"] = function(a, b) return a ^ b end,
+    ["//"] = function(a, b) return a // b end,
+    ["&"] = function(a, b) return a & b end,
+    ["|"] = function(a, b) return a | b end,
+    ["~"] = function(a, b) return a ~ b end,
+    ["<<"] = function(a, b) return a << b end,
+    [">>"] = function(a, b) return a >> b end,
+    ["neg"] = function(a) return -a end,
+    ["bnot"] = function(a) return ~a end,
+}
+
+local cases = {
+    -- wrap around
+    {max, "+", 1, min},
+    {min, "-", 1, max},
+    {max, "*", 2, -2},
+    {min, "*", -1, min},
+    {min, "neg", nil, min},
+    -- floor division and modulo
+    {7, "//", 2, 3},
+    {-7, "//", 2, -4},
+    {7, "//", -2, -4},
+    {-7, "//", -2, 3},
+    {min, "//", -1, min},
+    {min, "//", 1, min},
+    {7, "%", 3, 1},
+    {-7, "%", 3, 2},
+    {7, "%", -3, -2},
+    {-7, "%", -3, -1},
+    {min, "%", -1, 0},
+    {min, "%", max, max - 1},
+    {7.5, "//", 2, 3.0},
+    {-7.5, "//", 2, -4.0},
+    {1, "//", 0.0, inf},
+    {-1, "//", 0.0, -inf},
+    {7.5, "%", 2, 1.5},
+    {-7.5, "%", 2, 0.5},
+    {7.5, "%", -2, -0.5},
+    {-7.5, "%", -2, -1.5},
+    {5, "%", inf, 5.0},
+    {-5, "%", inf, inf},
+    {5, "%", -inf, -inf},
+    -- float operators
+    {7, "/", 2, 3.5},
+    {2, "^", 10, 1024.0},
+    {2, "^", -1, 0.5},
+    {"10", "+", 1, 11},
+    -- shifts
+    {1, "<<", 63, min},
+    {1, "<<", 64, 0},
+    {1, "<<", -1, 0},
+    {2, ">>", -1, 4},
+    {-1, ">>", 1, max},
+    {-1, ">>", 63, 1},
+    {-1, ">>", 64, 0},
+    {3, "<<", min, 0},
+    {3, ">>", min, 0},
+    -- bitwise with floats and strings
+    {3.0, "|", 0, 3},
+    {"3", "|", 0, 3},
+    {0, "bnot", nil, -1},
+    {5, "&", 3, 1},
+    {5, "~", 3, 6},
+}
+
+for i, case in ipairs(cases) do
+    local a, op, b, expected = case[1], case[2], case[3], case[4]
+    if ops[op](a, b) ~= expected then
+        error("case " .. i .. " failed: " .. a .. " " .. op .. " " .. tostring(b))
+    end
+end
+local nan = ops["/"](0, 0)
+if nan == nan then
+    error("nan == nan")
+end
+print(#cases .. " cases passed")
+
+local function fails(op, a, b)
+    local ok, err = pcall(ops[op], a, b)
+    if ok then
+        error("no error for " .. op)
+    end
+    print(err)
+end
+fails("//", max, 0)
+fails("%", max, 0)
+fails("|", 1.5, 0)
+fails("|", 2^63, 0)
+fails("+", "a", 1)
+fails("|", {}, 1)
@@ -0,0 +1,11 @@
+-- array part writes, reads and lengths
+local s = 0
+for r = 1, 20 do
+  local t = {}
+  for i = 1, 100000 do t[i] = i end
+  for i = 100000, 1, -1 do s = s + t[i] end
+  local u = {}
+  for i = 100000, 1, -1 do u[i] = i end
+  s = s + #u + #t
+end
+print(s)
@@ -0,0 +1,11 @@
+-- calls and returns of a small Lua function
+local function add(a, b) return a + b end
+local function fib(n)
+  if n < 2 then return n end
+  return add(fib(n - 1), fib(n - 2))
+end
+local x = 0
+for i = 1, 1000000 do
+  x = add(x, i) % 1000
+end
+print(x, fib(25))
@@ -0,0 +1,9 @@
+-- reads and writes of table fields with constant keys
+local p = {x = 1, y = 2, z = 3, name = "p"}
+local s = 0
+for i = 1, 3000000 do
+  p.x = p.y + p.z
+  p.y = p.x - p.z
+  s = s + p.x
+end
+print(s)
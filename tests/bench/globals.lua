@@ -0,0 +1,7 @@
+-- global function lookups in a hot loop, like math.floor
+mathx = {floor = function(n) return n // 1 end}
+local s = 0
+for i = 1, 3000000 do
+  s = s + mathx.floor(i / 2)
+end
+print(s)
@@ -0,0 +1,12 @@
+-- short instructions of many kinds, so dispatch is most of the cost
+local a, b, c, n = 1, 2.5, true, 0
+for i = 1, 3000000 do
+  local x = a
+  local y = not c
+  if y then n = n - 1 elseif x < i and b <= 3 then n = n + 1 end
+  local z = -x
+  a, x = x, z
+  a = -a
+  c = i % 3 ~= 0
+end
+print(n, a)
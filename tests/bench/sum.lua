@@ -0,0 +1,8 @@
+-- integer arithmetic and comparisons in a numeric for loop
+local sum = 0
+for i = 1, 10000000 do
+  if i % 2 == 0 then
+    sum = sum + i
+  end
+end
+print(sum)
@@ -0,0 +1,32 @@
+-- numbers are coerced, integers and floats keep their look
+local i, f = 10, 2.5
+print("a" .. i .. "b" .. f .. "c", 1 .. 2, 1.0 .. "", -0.0 .. "", 2^63 .. "")
+
+-- a long chain is one result
+local parts = {}
+for n = 1, 10 do parts[n] = n end
+local s = parts[1] .. parts[2] .. parts[3] .. parts[4] .. parts[5] ..
+  parts[6] .. parts[7] .. parts[8] .. parts[9] .. parts[10]
+print(s, #s)
+
+-- __concat is tried on either side, right to left
+local mt = {}
+mt.__concat = function(a, b)
+  local l = getmetatable(a) == mt and "<" .. a.v .. ">" or a
+  local r = getmetatable(b) == mt and "<" .. b.v .. ">" or b
+  return l .. r
+end
+local o = setmetatable({v = "o"}, mt)
+print("x" .. o, o .. "y", 1 .. o, o .. 2.5)
+print("a" .. "b" .. o .. "c" .. "d")
+print(o .. o)
+-- the metamethod may return anything
+local n = setmetatable({}, {__concat = function() return 7 end})
+print(n .. "s", "s" .. n .. "t")
+
+-- the error names the operand that is not a string or number
+print(pcall(function() return "a" .. {} end))
+print(pcall(function() local t = {}; return t .. "a" end))
+print(pcall(function() return "a" .. nil .. "b" end))
+print(pcall(function() return "a" .. true end))
+print(pcall(function() local x; return 1 .. x .. 2 end))
@@ -0,0 +1,131 @@
+-- a coroutine that resumes another one is "normal" and cannot be
+-- resumed, also after it has yielded before
+local function check(got, expected)
+    if got ~= expected then
+        error("expected " .. tostring(expected) .. ", got " .. tostring(got), 2)
+    end
+end
+
+local a, b
+b = coroutine.create(function()
+    for i = 1, 2 do
+        check(coroutine.status(a), "normal")
+        check(coroutine.status(b), "running")
+        local ok, err = coroutine.resume(a)
+        check(ok, false)
+        check(err, "cannot resume non-suspended coroutine")
+        if i == 1 then
+            coroutine.yield("b1")
+        end
+    end
+    return "b2"
+end)
+a = coroutine.create(function()
+    for i = 1, 2 do
+        -- the second round runs after a yield
+        check(coroutine.status(a), "running")
+        local ok, v = coroutine.resume(b)
+        check(ok, true)
+        check(v, "b" .. i)
+        check(coroutine.status(b), i == 1 and "suspended" or "dead")
+        coroutine.yield(i)
+    end
+    return "done"
+end)
+
+check(coroutine.status(a), "suspended")
+for i = 1, 2 do
+    local ok, v = coroutine.resume(a)
+    check(ok, true)
+    check(v, i)
+    check(coroutine.status(a), "suspended")
+end
+local ok, v = coroutine.resume(a)
+check(ok, true)
+check(v, "done")
+check(coroutine.status(a), "dead")
+ok, v = coroutine.resume(a)
+check(ok, false)
+check(v, "cannot resume dead coroutine")
+
+-- a wrapped generator keeps working across yields
+local gen = coroutine.wrap(function()
+    for i = 1, 3 do
+        coroutine.yield(i)
+    end
+end)
+check(gen() + gen() + gen(), 6)
+
+-- yields across pcall and xpcall; an error after the resume is still
+-- caught by them
+local co = coroutine.wrap(function()
+    local ok, v = pcall(function()
+        check(coroutine.isyieldable(), true)
+        return coroutine.yield("p1") + 1
+    end)
+    check(ok, true)
+    check(v, 11)
+    local ok, err = pcall(function()
+        local x = coroutine.yield("p2")
+        error("after " .. x, 0)
+    end)
+    check(ok, false)
+    check(err, "after resume")
+    ok, err = xpcall(function()
+        coroutine.yield("x1")
+        error("boom", 0)
+    end, function(m) return "handled " .. m end)
+    check(ok, false)
+    check(err, "handled boom")
+    ok, v = pcall(pcall, coroutine.yield, "nested")
+    check(ok, true)
+    check(v, true)
+    return "pcalls"
+end)
+check(co(), "p1")
+check(co(10), "p2")
+check(co("resume"), "x1")
+check(co(), "nested")
+check(co(), "pcalls")
+
+-- yields inside metamethods run for an instruction
+local mt = {
+    __index = function(t, k) return coroutine.yield("index " .. k) end,
+    __newindex = function(t, k, v) rawset(t, k, coroutine.yield("newindex " .. k)) end,
+    __call = function(self, x) return coroutine.yield("call " .. x) end,
+    __add = function(a, b) return coroutine.yield("add") end,
+    __lt = function(a, b) return coroutine.yield("lt") end,
+    __len = function(a) return coroutine.yield("len") end,
+    __concat = function(a, b) return coroutine.yield("concat") end,
+}
+co = coroutine.wrap(function()
+    local t = setmetatable({}, mt)
+    local sum = t.x + (t + 1) + #t
+    t.y = sum
+    local cmp = (t < t) and "lt" or "nlt"
+    -- a missing __le runs not (b < a)
+    cmp = cmp .. ((t <= t) and " le" or " nle")
+    return rawget(t, "y"), t(5) .. cmp, "a" .. t .. "b"
+end)
+check(co(), "index x")
+check(co(1), "add")
+check(co(2), "len")
+check(co(3), "newindex y")
+check(co(6), "lt")
+check(co(false), "lt")
+check(co(true), "call 5")
+check(co("c"), "concat")
+local y, s, cat = co("m")
+check(y, 6)
+check(s, "cnlt nle")
+check(cat, "am")
+
+-- a Rust function in between still cannot be suspended
+co = coroutine.wrap(function()
+    local t = setmetatable({}, {__tostring = function() return coroutine.yield() end})
+    return pcall(tostring, t)
+end)
+local ok, err = co()
+check(ok, false)
+check(err, "attempt to yield across a C-call boundary")
+print("ok")
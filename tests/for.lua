@@ -0,0 +1,35 @@
+-- numeric for loops
+local function check(got, expected)
+    if got ~= expected then
+        error("expected " .. tostring(expected) .. ", got " .. tostring(got), 2)
+    end
+end
+
+local function count(init, limit, step)
+    local n = 0
+    for _ = init, limit, step do
+        n = n + 1
+    end
+    return n
+end
+
+local nan = 0 / 0
+check(count(1.0, nan, 1), 0)
+check(count(1.0, nan, -1), 0)
+check(count(nan, 10, 1), 0)
+check(count(nan, 10.0, -1), 0)
+check(count(1, 3.5, 1), 3)
+check(count(1.0, 3, 0.5), 5)
+check(count(3, 1, -1), 3)
+check(count(1, 0, 1), 0)
+check(count(0x7ffffffffffffffe, 0x7fffffffffffffff, 1), 2)
+check(count(-0x7fffffffffffffff - 1, -0x7fffffffffffffff, 1), 2)
+check(count(1, 1e100, 0x4000000000000000), 2)
+check(count(1, -1e100, 1), 0)
+
+print("ok")
+print(pcall(count, 1, 10, 0))
+print(pcall(count, 1.0, 10, 0))
+print(pcall(count, 1, {}, 1))
+print(pcall(count, 1, 2, "x"))
+print(pcall(count, nil, 2, 1))
@@ -0,0 +1,57 @@
+-- field access through the per-instruction caches stays right when the
+-- table is rehashed, swapped or given a metatable between two runs
+local function get(t) return t.name end
+local function set(t, v) t.name = v end
+
+local t = {name = 1}
+print(get(t), get(t))
+for i = 1, 100 do t["k" .. i] = i end
+print(get(t))
+set(t, 2)
+for i = 1, 100 do t["k" .. i] = nil end
+for i = 1, 100 do t["j" .. i] = i end
+print(get(t), rawget(t, "name"))
+
+-- different tables at the same instruction
+local a, b = {x = 0, name = "a"}, {name = "b"}
+print(get(a), get(b), get(a), get({}))
+set(b, "bb")
+set({}, "lost")
+print(get(b), get(a))
+
+-- a removed field is not found in its old slot
+t.name = nil
+print(get(t))
+set(t, 3)
+print(get(t))
+
+-- a global read in a loop while the globals grow
+local sum = 0
+for i = 1, 200 do
+  counter = i
+  _ENV["g" .. i] = i
+  sum = sum + counter
+end
+print(sum)
+
+-- once the field is gone __index and __newindex take over
+local log = {}
+local p = setmetatable({name = "raw"}, {
+  __index = function(_, k) return "mm " .. k end,
+  __newindex = function(tt, k, v) log[#log + 1] = v; rawset(tt, k, v) end,
+})
+print(get(p))
+p.name = nil
+print(get(p))
+set(p, "new")
+set(p, "newer")
+print(get(p), #log, log[1])
+
+-- a table that is its own metatable
+local s = {}
+s.__newindex = function(tt, k, v) rawset(tt, k, v .. "!") end
+setmetatable(s, s)
+set(s, "self")
+print(get(s))
+set(s, "again")
+print(get(s))
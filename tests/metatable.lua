@@ -0,0 +1,75 @@
+-- __index and __newindex, as tables and as functions, chained
+local base = {greet = "hi"}
+local mid = setmetatable({}, {__index = base})
+local obj = setmetatable({}, {__index = mid})
+print(obj.greet, rawget(obj, "greet"))
+
+local log = {}
+local proxy = setmetatable({}, {
+  __index = function(t, k) return k .. "!" end,
+  __newindex = function(t, k, v) log[#log + 1] = k; rawset(t, k, v * 2) end,
+})
+print(proxy.foo)
+proxy.x = 21
+proxy.x = 5
+print(proxy.x, #log, log[1])
+
+local store = {}
+local fwd = setmetatable({}, {__newindex = store})
+fwd.a = 1
+print(rawget(fwd, "a"), store.a)
+
+-- arithmetic and bitwise metamethods
+local V = {}
+V.__index = V
+local function vec(x) return setmetatable({x = x}, V) end
+V.__add = function(a, b) return vec(a.x + b.x) end
+V.__sub = function(a, b) return vec(a.x - b.x) end
+V.__mul = function(a, b)
+  if getmetatable(a) ~= V then return vec(a * b.x) end
+  return vec(a.x * b)
+end
+V.__unm = function(a) return vec(-a.x) end
+V.__band = function(a, b) return "band" end
+V.__shl = function(a, b) return "shl" end
+V.__bnot = function(a) return "bnot" end
+V.__idiv = function(a, b) return "idiv" end
+print((vec(1) + vec(2)).x, (vec(5) - vec(3)).x, (vec(3) * 4).x, (2 * vec(3)).x, (-vec(7)).x)
+print(vec(1) & 1, 1 << vec(1), ~vec(1), vec(1) // 2)
+
+-- comparisons
+local C = {}
+C.__eq = function(a, b) return a.k == b.k end
+C.__lt = function(a, b) return a.k < b.k end
+C.__le = function(a, b) return a.k <= b.k end
+local function cmp(k) return setmetatable({k = k}, C) end
+local a, b, c = cmp(1), cmp(2), cmp(1)
+print(a == c, a ~= b, a < b, b < a, a <= c, b <= a, b > a, a >= b)
+-- __le falls back to not __lt with the operands swapped
+local L = {__lt = function(x, y) return x.k < y.k end}
+print(setmetatable({k = 1}, L) <= setmetatable({k = 2}, L))
+
+-- __len, __concat, __call, __tostring
+local m
+m = setmetatable({}, {
+  __len = function() return 42 end,
+  __concat = function(x, y)
+    return (rawequal(x, m) and "M" or x) .. (rawequal(y, m) and "M" or y)
+  end,
+  __call = function(self, p, q) return p + q, rawequal(self, m) end,
+  __tostring = function() return "<m>" end,
+})
+print(#m, m .. "x", "x" .. m, 1 .. m)
+print(m(3, 4))
+print(tostring(m), m)
+
+-- __metatable protection
+local prot = setmetatable({}, {__metatable = "locked"})
+print(getmetatable(prot))
+print(pcall(setmetatable, prot, {}))
+print(pcall(setmetatable, prot, nil))
+
+-- missing metamethods are still type errors
+print(pcall(function() return {} + 1 end))
+print(pcall(function() return {} < {} end))
+print(pcall(function() return #nil end))
@@ -0,0 +1,55 @@
+// compiles each tests/*.lua with luac 5.3 and runs it; a script passes if
+// its stdout and stderr match tests/<name>.expected, or, without that file,
+// if it exits without error. It needs luac 5.3, so it only runs on request:
+// `cargo test -- --ignored`, with LUAC set when luac 5.3 is not on the path.
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+// a luac whose chunks carry the 5.3 version byte
+fn find_luac(root: &Path, out_dir: &Path) -> Option<String> {
+    let luac = env::var("LUAC").unwrap_or_else(|_| String::from("luac"));
+    let probe = out_dir.join("probe.out");
+    let status = Command::new(&luac).current_dir(root).arg("-o").arg(&probe).arg("tests/hello_world.lua").status().ok()?;
+    let chunk = fs::read(&probe).ok()?;
+    if status.success() && chunk.get(4) == Some(&0x53) {
+        Some(luac)
+    } else {
+        None
+    }
+}
+
+#[test]
+#[ignore = "needs luac 5.3; run with --ignored"]
+fn scripts() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let out_dir = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let luac = find_luac(root, out_dir).expect("luac 5.3 not found, set LUAC to point at it");
+    let mut names: Vec<String> = fs::read_dir(root.join("tests"))
+        .unwrap()
+        .filter_map(|e| e.unwrap().file_name().into_string().ok())
+        .filter_map(|f| f.strip_suffix(".lua").map(String::from))
+        .collect();
+    names.sort();
+
+    let mut failed = Vec::new();
+    for name in names.iter() {
+        let src = format!("tests/{}.lua", name);
+        let chunk = out_dir.join(format!("{}.out", name));
+        let status = Command::new(&luac).current_dir(root).arg("-o").arg(&chunk).arg(&src).status().unwrap();
+        assert!(status.success(), "luac failed on {}", src);
+
+        let out = Command::new(env!("CARGO_BIN_EXE_lua-compiler")).current_dir(root).arg(&chunk).output().unwrap();
+        let output = String::from_utf8_lossy(&out.stdout).into_owned() + &String::from_utf8_lossy(&out.stderr);
+        let ok = match fs::read_to_string(root.join(format!("tests/{}.expected", name))) {
+            Ok(expected) => output == expected,
+            Err(_) => out.status.success(),
+        };
+        if !ok {
+            eprintln!("---- {} ----\n{}", name, output);
+            failed.push(name.as_str());
+        }
+    }
+    assert!(failed.is_empty(), "failed scripts: {:?}", failed);
+}
@@ -0,0 +1,71 @@
+-- tail calls reuse the frame of the caller
+local function check(got, expected)
+    if got ~= expected then
+        error("expected " .. tostring(expected) .. ", got " .. tostring(got), 2)
+    end
+end
+
+local function f(n)
+    if n > 0 then
+        return f(n - 1)
+    end
+    return "done"
+end
+check(f(1e6), "done")
+
+-- mutual recursion, with upvalues of the replaced frames still live
+local even, odd
+function even(n, acc)
+    if n == 0 then return true, acc() end
+    return odd(n - 1, function() return acc() + 1 end)
+end
+function odd(n, acc)
+    if n == 0 then return false, acc() end
+    return even(n - 1, acc)
+end
+local e, count = even(300001, function() return 0 end)
+check(e, false)
+check(count, 150001)
+
+-- varargs and multiple results pass through
+local function last(...)
+    return ...
+end
+local function pass(...)
+    return last(...)
+end
+local a, b, c = pass(1, 2, 3)
+check(a + b + c, 6)
+
+-- callable tables and Rust functions
+local callable = setmetatable({}, { __call = function(self, n) return n * 2 end })
+local function via_call(n) return callable(n) end
+check(via_call(21), 42)
+local function via_rust(t) return rawlen(t) end
+check(via_rust({1, 2, 3}), 3)
+
+-- in a coroutine, across yields
+local co = coroutine.wrap(function(n)
+    local function loop(i)
+        if i == 0 then return "end" end
+        coroutine.yield(i)
+        return loop(i - 1)
+    end
+    return loop(n)
+end)
+check(co(3), 3)
+check(co(), 2)
+check(co(), 1)
+check(co(), "end")
+
+-- errors raised after a tail call
+local function fail() error("boom") end
+local function g() return fail() end
+print(pcall(g))
+local function h() return nil_value() end
+print(pcall(h))
+
+-- the traceback marks where frames were replaced
+local function deep() error("at the bottom") end
+local function tail() return deep() end
+tail()
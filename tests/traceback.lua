@@ -0,0 +1,36 @@
+-- runtime errors carry the source:line: of the failing instruction
+print(pcall(function() local t = nil; return t.x end))
+print(pcall(error, "plain"))
+print(pcall(function() error("here") end))
+print(pcall(function() error("no position", 0) end))
+
+-- level 2 blames the caller
+local function check(v)
+  if not v then error("check failed", 2) end
+end
+print(pcall(function()
+  check(false)
+end))
+
+-- the traceback walks every active frame
+local obj = {}
+function obj.method()
+  return debug.traceback("tb")
+end
+local function outer()
+  local s = obj.method()
+  return s
+end
+print(outer())
+print(debug.traceback("level 2", 2))
+
+-- numbers are messages too; other values pass through untouched
+print(debug.traceback(42))
+local msg = {}
+print(rawequal(debug.traceback(msg), msg))
+
+-- an uncaught error ends the script with a traceback on stderr
+local function fail()
+  local x = {} .. "s"
+end
+fail()
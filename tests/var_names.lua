@@ -0,0 +1,36 @@
+-- type errors name the variable that held the bad value
+local t = nil
+local up = nil
+local obj = {}
+
+-- locals
+print(pcall(function() local a; return a.x end))
+print(pcall(function() local a; return a + 1 end))
+print(pcall(function() local a; a() end))
+print(pcall(function() local a = {}; return a .. "s" end))
+
+-- globals
+print(pcall(function() return cfg.name end))
+print(pcall(function() return missing_fn() end))
+print(pcall(function() return -undefined_num end))
+
+-- fields
+print(pcall(function() return obj.inner.x end))
+print(pcall(function() return obj.count + 1 end))
+print(pcall(function() obj.go() end))
+
+-- upvalues
+print(pcall(function() return t.x end))
+print(pcall(function() up() end))
+print(pcall(function() return up .. "s" end))
+
+-- constants
+print(pcall(function() return ("s")() end))
+print(pcall(function() local s = "abc" + 1 end))
+
+-- methods
+print(pcall(function() obj:run() end))
+
+-- the bitwise and length errors name the variable too
+print(pcall(function() local n = 1.5; return n | 1 end))
+print(pcall(function() local a; return #a end))
@@ -0,0 +1,43 @@
+-- weak tables and finalizers
+local function check(got, expected)
+    if got ~= expected then
+        error("expected " .. tostring(expected) .. ", got " .. tostring(got), 2)
+    end
+end
+
+-- an object being finalized stays in weak-key tables until the next cycle
+local cache = setmetatable({}, {__mode = "k"})
+local seen
+do
+    local obj = setmetatable({}, {__gc = function(o) seen = cache[o] end})
+    cache[obj] = {"cached"}
+end
+collectgarbage()
+check(seen and seen[1], "cached")
+check(next(cache) ~= nil, true)
+collectgarbage()
+check(next(cache), nil)
+
+-- but weak values referring to it are cleared before its finalizer runs
+local values = setmetatable({}, {__mode = "v"})
+local found
+do
+    local obj = setmetatable({}, {__gc = function() found = values[1] end})
+    values[1] = obj
+end
+collectgarbage()
+check(found, nil)
+
+-- ephemerons: a value is only kept through its key
+local eph = setmetatable({}, {__mode = "k"})
+local key = {}
+eph[key] = {}
+eph[{}] = key
+collectgarbage()
+local n = 0
+for k, v in pairs(eph) do
+    n = n + 1
+    check(k, key)
+end
+check(n, 1)
+print("ok")
@@ -0,0 +1,32 @@
+#!/usr/bin/env bash
+# times the scripts in tests/bench; with several interpreters, their runs
+# are interleaved so that drift on the machine hits all of them alike
+#   tool/bench.sh                          the release build of this tree
+#   tool/bench.sh old/lua-compiler target/release/lua-compiler
+# RUNS sets the runs per script (default 10), LUAC the luac 5.3 to use
+set -e
+cd "$(dirname "$0")/.."
+luac=${LUAC:-luac}
+runs=${RUNS:-10}
+if [ $# -eq 0 ]; then
+  cargo build -q --release
+  set -- target/release/lua-compiler
+fi
+out=$(mktemp -d)
+trap 'rm -rf "$out"' EXIT
+
+for src in tests/bench/*.lua; do
+  name=$(basename "$src" .lua)
+  "$luac" -o "$out/$name.out" "$src"
+  for ((r = 0; r < runs; r++)); do
+    for ((b = 1; b <= $#; b++)); do
+      start=$(date +%s%N)
+      "${!b}" "$out/$name.out" > /dev/null
+      echo $(( ($(date +%s%N) - start) / 1000000 )) >> "$out/$name.$b"
+    done
+  done
+  for ((b = 1; b <= $#; b++)); do
+    sort -n "$out/$name.$b" | awk -v name="$name" -v bin="${!b}" \
+      '{ t[NR] = $1 } END { printf "%-8s %6d ms best %6d ms median  %s\n", name, t[1], t[int((NR + 1) / 2)], bin }'
+  done
+done